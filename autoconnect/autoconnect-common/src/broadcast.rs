@@ -10,9 +10,13 @@
 ///
 /// see api discussion: https://docs.google.com/document/d/1Wxqf1a4HDkKgHDIswPmhmdvk8KPoMEh2q6SPhaz4LNE/edit#
 ///
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
 use serde_derive::{Deserialize, Serialize};
+use tokio::sync::watch;
 
 use autopush_common::errors::{ApcErrorKind, Result};
 
@@ -29,18 +33,31 @@ pub struct BroadcastSubs {
     change_count: u32,                 // the last known change
 }
 
+impl BroadcastSubs {
+    /// Release any excess capacity held by the subscription list
+    pub fn shrink_to_fit(&mut self) {
+        self.broadcast_list.shrink_to_fit();
+    }
+}
+
 /// The server maintained list of Broadcasts
+///
+/// Keys are never reused, so removing a broadcast doesn't invalidate the
+/// keys held by existing `BroadcastSubs` (a removed key simply never matches
+/// again)
 #[derive(Debug)]
 struct BroadcastRegistry {
     lookup: HashMap<String, BroadcastKey>, // mapping of broadcast string identifiers to internal BroadcastKeys
-    table: Vec<String>,                    // List of known broadcast string identifiers
+    table: HashMap<BroadcastKey, String>,  // known broadcast string identifiers
+    next_key: BroadcastKey,
 }
 
 impl BroadcastRegistry {
     fn new() -> BroadcastRegistry {
         BroadcastRegistry {
             lookup: HashMap::new(),
-            table: Vec::new(),
+            table: HashMap::new(),
+            next_key: 0,
         }
     }
 
@@ -50,14 +67,22 @@ impl BroadcastRegistry {
         if let Some(v) = self.lookup.get(&broadcast_id) {
             return *v;
         }
-        let i = self.table.len() as u32;
-        self.table.push(broadcast_id.clone());
+        let i = self.next_key;
+        self.next_key += 1;
+        self.table.insert(i, broadcast_id.clone());
         self.lookup.insert(broadcast_id, i);
         i
     }
 
+    /// Remove a broadcast from the lookup table, freeing its identifier
+    fn remove_broadcast(&mut self, key: BroadcastKey) {
+        if let Some(broadcast_id) = self.table.remove(&key) {
+            self.lookup.remove(&broadcast_id);
+        }
+    }
+
     fn lookup_id(&self, key: BroadcastKey) -> Option<String> {
-        self.table.get(key as usize).cloned()
+        self.table.get(&key).cloned()
     }
 
     fn lookup_key(&self, broadcast_id: &str) -> Option<BroadcastKey> {
@@ -80,12 +105,16 @@ pub struct Broadcast {
     version: String,
 }
 
+/// The version sent for unknown Broadcasts: both those never known and
+/// tombstones of those removed
+pub const BROADCAST_NOT_FOUND: &str = "Broadcast not found";
+
 impl Broadcast {
     /// Errors out a broadcast for broadcasts that weren't found
     pub fn error(self) -> Broadcast {
         Broadcast {
             broadcast_id: self.broadcast_id,
-            version: "Broadcast not found".to_string(),
+            version: BROADCAST_NOT_FOUND.to_string(),
         }
     }
 }
@@ -128,12 +157,21 @@ pub struct BroadcastSubsInit(
 
 /// BroadcastChangeTracker tracks the broadcasts, their change_count, and the
 /// broadcast lookup registry
+///
+/// Removed broadcasts leave a tombstone (a revision without a version) so
+/// that subscribed Clients are told of the removal. Tombstones are compacted
+/// from the registry once older than `tombstone_ttl`
 #[derive(Debug)]
 pub struct BroadcastChangeTracker {
     broadcast_list: Vec<BroadcastRevision>,
     broadcast_registry: BroadcastRegistry,
     broadcast_versions: HashMap<BroadcastKey, String>,
     change_count: u32,
+    /// Publishes the latest change_count
+    changes: watch::Sender<u32>,
+    /// When each tombstoned broadcast was removed
+    tombstones: HashMap<BroadcastKey, Instant>,
+    tombstone_ttl: Duration,
 }
 
 impl BroadcastChangeTracker {
@@ -144,6 +182,9 @@ impl BroadcastChangeTracker {
             broadcast_registry: BroadcastRegistry::new(),
             broadcast_versions: HashMap::new(),
             change_count: 0,
+            changes: watch::Sender::new(0),
+            tombstones: HashMap::new(),
+            tombstone_ttl: Duration::ZERO,
         };
         for srv in broadcasts {
             let key = tracker.broadcast_registry.add_broadcast(srv.broadcast_id);
@@ -152,6 +193,13 @@ impl BroadcastChangeTracker {
         tracker
     }
 
+    /// Set how long tombstones of removed broadcasts are retained: this should
+    /// allow every connected Client the chance to be sent them
+    pub fn with_tombstone_ttl(mut self, tombstone_ttl: Duration) -> Self {
+        self.tombstone_ttl = tombstone_ttl;
+        self
+    }
+
     /// Add a `Vec` of `Broadcast`s via `self.add_broadcast`
     ///
     /// Returning the latest change_count (or `None` for an empty `Vec`)
@@ -171,10 +219,14 @@ impl BroadcastChangeTracker {
             return change_count;
         }
         self.change_count += 1;
+        self.changes.send_replace(self.change_count);
+        // Revives a tombstoned broadcast's key
         let key = self
             .broadcast_registry
             .add_broadcast(broadcast.broadcast_id);
         self.broadcast_versions.insert(key, broadcast.version);
+        self.tombstones.remove(&key);
+        self.broadcast_list.retain(|bcast| bcast.broadcast != key);
         self.broadcast_list.push(BroadcastRevision {
             change_count: self.change_count,
             broadcast: key,
@@ -212,6 +264,7 @@ impl BroadcastChangeTracker {
             .filter_map(|(i, bcast)| (bcast.broadcast == key).then_some(i))
             .next();
         self.change_count += 1;
+        self.changes.send_replace(self.change_count);
         if let Some(bcast_index) = bcast_index {
             trace!("📢  {} index: {}", &b_id, &bcast_index);
             let mut bcast = self.broadcast_list.remove(bcast_index);
@@ -230,6 +283,86 @@ impl BroadcastChangeTracker {
         Ok(self.change_count)
     }
 
+    /// Remove a broadcast, leaving a tombstone to notify subscribed Clients,
+    /// triggering a change_count increase.
+    ///
+    /// Returns `None` if the broadcast wasn't found.
+    pub fn remove_broadcast(&mut self, broadcast_id: &str) -> Option<u32> {
+        let key = self.live_key(broadcast_id)?;
+        trace!("📢 Removing {}", broadcast_id);
+        self.broadcast_versions.remove(&key);
+        self.tombstones.insert(key, Instant::now());
+        self.change_count += 1;
+        self.changes.send_replace(self.change_count);
+        self.broadcast_list.retain(|bcast| bcast.broadcast != key);
+        self.broadcast_list.push(BroadcastRevision {
+            change_count: self.change_count,
+            broadcast: key,
+        });
+        Some(self.change_count)
+    }
+
+    /// Synchronize with the complete set of current `broadcasts`: adding or
+    /// updating them, removing any others and compacting expired tombstones.
+    ///
+    /// Returning the latest change_count (or `None` if nothing changed)
+    pub fn sync_broadcasts(&mut self, broadcasts: Vec<Broadcast>) -> Option<u32> {
+        let removed: Vec<_> = self
+            .broadcast_versions
+            .keys()
+            .filter_map(|key| self.broadcast_registry.lookup_id(*key))
+            .filter(|broadcast_id| !broadcasts.iter().any(|b| &b.broadcast_id == broadcast_id))
+            .collect();
+        let old_count = self.change_count;
+        for broadcast_id in removed {
+            self.remove_broadcast(&broadcast_id);
+        }
+        self.add_broadcasts(broadcasts);
+        self.expire_tombstones();
+        (old_count != self.change_count).then_some(self.change_count)
+    }
+
+    /// Compact tombstones older than `tombstone_ttl` from the registry
+    fn expire_tombstones(&mut self) {
+        let expired: Vec<_> = self
+            .tombstones
+            .iter()
+            .filter_map(|(key, removed)| (removed.elapsed() >= self.tombstone_ttl).then_some(*key))
+            .collect();
+        for key in expired {
+            trace!("📢 Compacting tombstone {}", key);
+            self.tombstones.remove(&key);
+            self.broadcast_registry.remove_broadcast(key);
+            self.broadcast_list.retain(|bcast| bcast.broadcast != key);
+        }
+    }
+
+    /// The current (not removed) Broadcasts and their versions
+    pub fn broadcasts(&self) -> HashMap<String, String> {
+        self.broadcast_versions
+            .iter()
+            .filter_map(|(key, ver)| Some((self.broadcast_registry.lookup_id(*key)?, ver.clone())))
+            .collect()
+    }
+
+    /// Lookup the key of a broadcast that hasn't been removed
+    fn live_key(&self, broadcast_id: &str) -> Option<BroadcastKey> {
+        self.broadcast_registry
+            .lookup_key(broadcast_id)
+            .filter(|key| self.broadcast_versions.contains_key(key))
+    }
+
+    /// The latest change_count
+    pub fn change_count(&self) -> u32 {
+        self.change_count
+    }
+
+    /// Subscribe to notifications of Broadcast changes (the latest
+    /// change_count)
+    pub fn subscribe_changes(&self) -> watch::Receiver<u32> {
+        self.changes.subscribe()
+    }
+
     /// Returns the new broadcast versions since the provided `client_set`.
     pub fn change_count_delta(&self, client_set: &mut BroadcastSubs) -> Option<Vec<Broadcast>> {
         if self.change_count <= client_set.change_count {
@@ -243,13 +376,16 @@ impl BroadcastChangeTracker {
             if !client_set.broadcast_list.contains(&bcast.broadcast) {
                 continue;
             }
-            if let Some(ver) = self.broadcast_versions.get(&bcast.broadcast) {
-                if let Some(bcast_id) = self.broadcast_registry.lookup_id(bcast.broadcast) {
-                    bcast_delta.push(Broadcast {
-                        broadcast_id: bcast_id,
-                        version: (*ver).clone(),
-                    });
-                }
+            if let Some(bcast_id) = self.broadcast_registry.lookup_id(bcast.broadcast) {
+                let version = match self.broadcast_versions.get(&bcast.broadcast) {
+                    Some(ver) => (*ver).clone(),
+                    // Tombstone
+                    None => BROADCAST_NOT_FOUND.to_owned(),
+                };
+                bcast_delta.push(Broadcast {
+                    broadcast_id: bcast_id,
+                    version,
+                });
             }
         }
         client_set.change_count = self.change_count;
@@ -262,7 +398,7 @@ impl BroadcastChangeTracker {
         let mut bcast_list = Vec::new();
         let mut bcast_delta = Vec::new();
         for bcast in broadcasts.iter() {
-            if let Some(bcast_key) = self.broadcast_registry.lookup_key(&bcast.broadcast_id) {
+            if let Some(bcast_key) = self.live_key(&bcast.broadcast_id) {
                 if let Some(ver) = self.broadcast_versions.get(&bcast_key) {
                     if *ver != bcast.version {
                         bcast_delta.push(Broadcast {
@@ -293,7 +429,7 @@ impl BroadcastChangeTracker {
     ) -> Option<Vec<Broadcast>> {
         let mut bcast_delta = self.change_count_delta(broadcast_subs).unwrap_or_default();
         for bcast in broadcasts.iter() {
-            if let Some(bcast_key) = self.broadcast_registry.lookup_key(&bcast.broadcast_id) {
+            if let Some(bcast_key) = self.live_key(&bcast.broadcast_id) {
                 if let Some(ver) = self.broadcast_versions.get(&bcast_key) {
                     if *ver != bcast.version {
                         bcast_delta.push(Broadcast {
@@ -312,11 +448,7 @@ impl BroadcastChangeTracker {
     pub fn missing_broadcasts(&self, broadcasts: &[Broadcast]) -> Vec<Broadcast> {
         broadcasts
             .iter()
-            .filter(|&b| {
-                self.broadcast_registry
-                    .lookup_key(&b.broadcast_id)
-                    .is_none()
-            })
+            .filter(|&b| self.live_key(&b.broadcast_id).is_none())
             .map(|b| b.clone().error())
             .collect()
     }
@@ -390,4 +522,76 @@ mod tests {
         assert_eq!(broadcast_subs.change_count, 1);
         assert_eq!(tracker.broadcast_list.len(), 1);
     }
+
+    #[test]
+    fn test_broadcast_removal() {
+        let broadcasts = make_broadcast_base();
+        let desired_broadcasts = broadcasts.clone();
+        let mut tracker =
+            BroadcastChangeTracker::new(broadcasts).with_tombstone_ttl(Duration::from_secs(60));
+        let BroadcastSubsInit(mut broadcast_subs, _) = tracker.broadcast_delta(&desired_broadcasts);
+
+        // bcastb's no longer included
+        let change_count = tracker.sync_broadcasts(vec![desired_broadcasts[0].clone()]);
+        assert_eq!(change_count, Some(1));
+        assert_eq!(
+            tracker.sync_broadcasts(vec![desired_broadcasts[0].clone()]),
+            None
+        );
+
+        // Subscribers are sent a tombstone
+        let delta = tracker.change_count_delta(&mut broadcast_subs).unwrap();
+        assert_eq!(delta, vec![desired_broadcasts[1].clone().error()]);
+        // New subscribers are sent an error
+        let BroadcastSubsInit(new_subs, delta) = tracker.broadcast_delta(&desired_broadcasts);
+        assert_eq!(new_subs.broadcast_list.len(), 1);
+        assert!(delta.is_empty());
+        assert_eq!(
+            tracker.missing_broadcasts(&desired_broadcasts),
+            vec![desired_broadcasts[1].clone().error()]
+        );
+
+        // Re-adding revives it
+        tracker.add_broadcast(Broadcast {
+            broadcast_id: String::from("bcastb"),
+            version: String::from("revbeta"),
+        });
+        let delta = tracker.change_count_delta(&mut broadcast_subs).unwrap();
+        assert_eq!(delta.len(), 1);
+        assert_eq!(delta[0].version, String::from("revbeta"));
+        assert_eq!(tracker.broadcast_list.len(), 1);
+    }
+
+    #[test]
+    fn test_broadcast_tombstone_compaction() {
+        let broadcasts = make_broadcast_base();
+        let desired_broadcasts = broadcasts.clone();
+        let mut tracker = BroadcastChangeTracker::new(broadcasts);
+        let BroadcastSubsInit(mut broadcast_subs, _) = tracker.broadcast_delta(&desired_broadcasts);
+
+        // No tombstone_ttl: compacted immediately
+        tracker.sync_broadcasts(vec![desired_broadcasts[0].clone()]);
+        assert!(tracker.tombstones.is_empty());
+        assert!(tracker.broadcast_list.is_empty());
+        assert_eq!(tracker.broadcast_registry.table.len(), 1);
+        assert!(tracker.change_count_delta(&mut broadcast_subs).is_none());
+
+        // Existing subscriptions remain valid
+        tracker.add_broadcast(Broadcast {
+            broadcast_id: String::from("bcasta"),
+            version: String::from("rev2"),
+        });
+        let delta = tracker.change_count_delta(&mut broadcast_subs).unwrap();
+        assert_eq!(delta.len(), 1);
+        assert_eq!(delta[0].broadcast_id, String::from("bcasta"));
+
+        // A new key's allocated when re-added, so the stale subscription
+        // doesn't match it
+        tracker.add_broadcast(Broadcast {
+            broadcast_id: String::from("bcastb"),
+            version: String::from("revbeta"),
+        });
+        assert_eq!(tracker.broadcast_registry.lookup_key("bcastb"), Some(2));
+        assert!(tracker.change_count_delta(&mut broadcast_subs).is_none());
+    }
 }
@@ -1,10 +1,23 @@
-use std::{collections::HashMap, error::Error, io, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    error::Error,
+    future::Future,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use actix_web::rt;
-use cadence::{CountedExt, StatsdClient};
-use serde_derive::Deserialize;
+use cadence::{CountedExt, Gauged, StatsdClient};
+use serde_derive::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
+use autopush_common::util::sec_since_epoch;
+
 use crate::broadcast::{Broadcast, BroadcastChangeTracker};
 
 /// The payload provided by the Megaphone service
@@ -13,11 +26,30 @@ pub struct MegaphoneResponse {
     pub broadcasts: HashMap<String, String>,
 }
 
+/// The last known Broadcasts, persisted for use should the Megaphone service
+/// be unavailable at startup
+#[derive(Debug, Deserialize, Serialize)]
+struct Snapshot {
+    broadcasts: HashMap<String, String>,
+    /// When the Broadcasts were last synced with the Megaphone service
+    /// (seconds since epoch)
+    synced_at: u64,
+}
+
 /// Initialize the `BroadcastChangeTracker`
 ///
 /// Immediately populates it with the current Broadcasts polled from the
-/// Megaphone service, then spawns a background task to periodically refresh
-/// it
+/// Megaphone service, then spawns a background task to keep it up to date.
+///
+/// When a `stream_url` is provided the task subscribes to the Megaphone
+/// service's Server-Sent Events stream, applying updates as they arrive and
+/// falling back to polling `url` whenever the stream is disconnected.
+/// Otherwise it periodically polls `url`.
+///
+/// When a `snapshot_path` is provided the Broadcasts are persisted there
+/// after each update, and loaded from there should the Megaphone service be
+/// unavailable now (instead of failing).
+#[allow(clippy::too_many_arguments)]
 pub async fn init_and_spawn_megaphone_updater(
     broadcaster: &Arc<RwLock<BroadcastChangeTracker>>,
     http: &reqwest::Client,
@@ -25,30 +57,349 @@ pub async fn init_and_spawn_megaphone_updater(
     url: &str,
     token: &str,
     poll_interval: Duration,
+    stream_url: Option<&str>,
+    snapshot_path: Option<&str>,
 ) -> reqwest::Result<()> {
-    updater(broadcaster, http, url, token).await?;
+    let updater = Updater {
+        broadcaster: Arc::clone(broadcaster),
+        http: http.clone(),
+        metrics: Arc::clone(metrics),
+        url: url.to_owned(),
+        token: token.to_owned(),
+        snapshot_path: snapshot_path.map(PathBuf::from),
+        synced_at: AtomicU64::new(0),
+    };
+    if let Err(e) = updater.update().await {
+        if !updater.load_snapshot().await {
+            return Err(e);
+        }
+        warn!(
+            "📢megaphone unavailable, using snapshot (staleness: {}s): {}",
+            updater.staleness().unwrap_or_default(),
+            e
+        );
+    }
 
-    let broadcaster = Arc::clone(broadcaster);
-    let http = http.clone();
-    let metrics = Arc::clone(metrics);
-    let url = url.to_owned();
-    let token = token.to_owned();
+    let stream = match stream_url {
+        // The stream is long lived, so it can't share `http`'s request
+        // timeout
+        Some(stream_url) => Some((
+            reqwest::Client::builder()
+                .connect_timeout(STREAM_CONNECT_TIMEOUT)
+                .build()?,
+            stream_url.to_owned(),
+        )),
+        None => None,
+    };
     rt::spawn(async move {
+        let Some((stream_http, stream_url)) = stream else {
+            loop {
+                rt::time::sleep(poll_interval).await;
+                updater.poll().await;
+            }
+        };
         loop {
-            rt::time::sleep(poll_interval).await;
-            if let Err(e) = updater(&broadcaster, &http, &url, &token).await {
-                report_updater_error(&metrics, e);
-            } else {
-                metrics.incr_with_tags("megaphone.updater.ok").send();
+            let result = updater
+                .subscribe(
+                    &stream_http,
+                    &stream_url,
+                    // Expect at least a keepalive within a couple of poll
+                    // intervals before considering the stream dead
+                    poll_interval * 2,
+                    updater.poll(),
+                )
+                .await;
+            match result {
+                Ok(()) => {
+                    debug!("📢megaphone::subscribe stream closed");
+                    updater
+                        .metrics
+                        .incr_with_tags("megaphone.stream.closed")
+                        .send();
+                }
+                Err(e) => report_updater_error(&updater.metrics, "megaphone.stream.error", e),
             }
+            // Fall back to polling while disconnected
+            updater.poll().await;
+            rt::time::sleep(poll_interval).await;
         }
     });
 
     Ok(())
 }
 
+/// How long to wait when (re)connecting to the Megaphone stream
+const STREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Keeps the `BroadcastChangeTracker` up to date with the Megaphone service
+struct Updater {
+    broadcaster: Arc<RwLock<BroadcastChangeTracker>>,
+    http: reqwest::Client,
+    metrics: Arc<StatsdClient>,
+    url: String,
+    token: String,
+    snapshot_path: Option<PathBuf>,
+    /// When the Broadcasts were last synced with the Megaphone service
+    /// (seconds since epoch, 0 if never)
+    synced_at: AtomicU64,
+}
+
+impl Updater {
+    /// Poll the Megaphone service once, recording the outcome
+    async fn poll(&self) {
+        if let Err(e) = self.update().await {
+            report_updater_error(&self.metrics, "megaphone.updater.error", e);
+        } else {
+            self.metrics.incr_with_tags("megaphone.updater.ok").send();
+        }
+        if let Some(staleness) = self.staleness() {
+            let _ = self
+                .metrics
+                .gauge("megaphone.broadcasts.staleness", staleness);
+        }
+    }
+
+    /// Refresh the `BroadcastChangeTracker`'s Broadcasts from the Megaphone
+    /// service
+    async fn update(&self) -> reqwest::Result<()> {
+        trace!("📢megaphone::updater");
+        let MegaphoneResponse { broadcasts } = self
+            .http
+            .get(&self.url)
+            .header("Authorization", &self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let broadcasts = Broadcast::from_hashmap(broadcasts);
+        // The response is the complete set of Broadcasts, so any others have
+        // been removed (though an empty response is distrusted and isn't
+        // considered a sync)
+        if broadcasts.is_empty() {
+            debug!("📢megaphone::updater empty response, ignoring");
+            return Ok(());
+        }
+        let change_count = self.broadcaster.write().await.sync_broadcasts(broadcasts);
+        trace!("📢 sync_broadcasts change_count: {:?}", change_count);
+        self.synced();
+        if change_count.is_some() {
+            self.save_snapshot().await;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to the Megaphone service's Server-Sent Events stream,
+    /// applying each event's Broadcasts to the `BroadcastChangeTracker`
+    /// immediately.
+    ///
+    /// Events may include only the changed Broadcasts, so unlike polling, they
+    /// never remove any. `catch_up` is run once connected to pick up any
+    /// changes (including removals) made while disconnected. Returns when the
+    /// stream ends or has been idle for longer than `idle_timeout`.
+    async fn subscribe(
+        &self,
+        http: &reqwest::Client,
+        url: &str,
+        idle_timeout: Duration,
+        catch_up: impl Future<Output = ()>,
+    ) -> reqwest::Result<()> {
+        trace!("📢megaphone::subscribe");
+        let mut response = http
+            .get(url)
+            .header("Authorization", &self.token)
+            .header("Accept", "text/event-stream")
+            .send()
+            .await?
+            .error_for_status()?;
+        self.metrics
+            .incr_with_tags("megaphone.stream.connected")
+            .send();
+        catch_up.await;
+
+        let mut events = EventStream::default();
+        loop {
+            let Ok(chunk) = rt::time::timeout(idle_timeout, response.chunk()).await else {
+                debug!("📢megaphone::subscribe stream idle, reconnecting");
+                self.metrics
+                    .incr_with_tags("megaphone.stream.error")
+                    .with_tag("reason", "idle")
+                    .send();
+                return Ok(());
+            };
+            let Some(chunk) = chunk? else {
+                return Ok(());
+            };
+            // Any data (even a keepalive) confirms the stream's current
+            self.synced();
+            for data in events.feed(&chunk) {
+                let MegaphoneResponse { broadcasts } = match serde_json::from_str(&data) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!("📢megaphone::subscribe invalid event: {}", e);
+                        self.metrics
+                            .incr_with_tags("megaphone.stream.error")
+                            .with_tag("reason", "invalid")
+                            .send();
+                        continue;
+                    }
+                };
+                let broadcasts = Broadcast::from_hashmap(broadcasts);
+                if !broadcasts.is_empty() {
+                    let changed = {
+                        let mut broadcaster = self.broadcaster.write().await;
+                        let old_count = broadcaster.change_count();
+                        let change_count = broadcaster.add_broadcasts(broadcasts);
+                        trace!("📢 stream add_broadcast change_count: {:?}", change_count);
+                        change_count != Some(old_count)
+                    };
+                    if changed {
+                        self.save_snapshot().await;
+                    }
+                }
+                self.metrics
+                    .incr_with_tags("megaphone.stream.update")
+                    .send();
+            }
+        }
+    }
+
+    /// Record the Broadcasts as currently in sync with the Megaphone service
+    fn synced(&self) {
+        self.synced_at.store(sec_since_epoch(), Ordering::Relaxed);
+    }
+
+    /// How long (in seconds) since the Broadcasts were last synced with the
+    /// Megaphone service
+    fn staleness(&self) -> Option<u64> {
+        let synced_at = self.synced_at.load(Ordering::Relaxed);
+        (synced_at > 0).then(|| sec_since_epoch().saturating_sub(synced_at))
+    }
+
+    /// Persist the current Broadcasts to the snapshot file (if configured)
+    async fn save_snapshot(&self) {
+        let Some(ref path) = self.snapshot_path else {
+            return;
+        };
+        let snapshot = Snapshot {
+            broadcasts: self.broadcaster.read().await.broadcasts(),
+            synced_at: self.synced_at.load(Ordering::Relaxed),
+        };
+        // Write then rename so a partially written snapshot's never loaded
+        let tmp_path = path.with_extension("tmp");
+        let result = match serde_json::to_vec(&snapshot) {
+            Ok(json) => match tokio::fs::write(&tmp_path, json).await {
+                Ok(()) => tokio::fs::rename(&tmp_path, path).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!("📢megaphone snapshot save failed: {}", e);
+            self.metrics
+                .incr_with_tags("megaphone.snapshot.error")
+                .with_tag("reason", "save")
+                .send();
+        }
+    }
+
+    /// Populate the `BroadcastChangeTracker` from the snapshot file (if
+    /// configured)
+    ///
+    /// Returns whether it was loaded
+    async fn load_snapshot(&self) -> bool {
+        let Some(ref path) = self.snapshot_path else {
+            return false;
+        };
+        let snapshot: Snapshot = match tokio::fs::read(path).await {
+            Ok(json) => match serde_json::from_slice(&json) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    warn!("📢megaphone snapshot invalid: {}", e);
+                    self.metrics
+                        .incr_with_tags("megaphone.snapshot.error")
+                        .with_tag("reason", "invalid")
+                        .send();
+                    return false;
+                }
+            },
+            Err(e) => {
+                warn!("📢megaphone snapshot load failed: {}", e);
+                self.metrics
+                    .incr_with_tags("megaphone.snapshot.error")
+                    .with_tag("reason", "load")
+                    .send();
+                return false;
+            }
+        };
+        self.broadcaster
+            .write()
+            .await
+            .add_broadcasts(Broadcast::from_hashmap(snapshot.broadcasts));
+        self.synced_at.store(snapshot.synced_at, Ordering::Relaxed);
+        self.metrics
+            .incr_with_tags("megaphone.snapshot.loaded")
+            .send();
+        if let Some(staleness) = self.staleness() {
+            let _ = self
+                .metrics
+                .gauge("megaphone.broadcasts.staleness", staleness);
+        }
+        true
+    }
+}
+
+/// A minimal Server-Sent Events parser, yielding the `data` of each
+/// complete event
+///
+/// Other fields (`event`, `id`, `retry`) and comments (commonly used as
+/// keepalives) are ignored.
+#[derive(Debug, Default)]
+struct EventStream {
+    /// A partial line carried over from the previous chunk
+    line: Vec<u8>,
+    /// The `data` lines of the event being accumulated
+    data: Option<String>,
+}
+
+impl EventStream {
+    /// Feed the next chunk of the stream, returning any completed events
+    fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        let mut events = vec![];
+        for &byte in chunk {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let mut line = std::mem::take(&mut self.line);
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if line.is_empty() {
+                // A blank line dispatches the event
+                events.extend(self.data.take());
+                continue;
+            }
+            let line = String::from_utf8_lossy(&line);
+            let (field, value) = line.split_once(':').unwrap_or((&line, ""));
+            if field != "data" {
+                continue;
+            }
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match self.data {
+                Some(ref mut data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => self.data = Some(value.to_owned()),
+            }
+        }
+        events
+    }
+}
+
 /// Emits a log, metric and Sentry event depending on the type of Error
-fn report_updater_error(metrics: &Arc<StatsdClient>, err: reqwest::Error) {
+fn report_updater_error(metrics: &Arc<StatsdClient>, metric: &str, err: reqwest::Error) {
     let reason = if err.is_timeout() {
         "timeout"
     } else if err.is_connect() {
@@ -59,39 +410,15 @@ fn report_updater_error(metrics: &Arc<StatsdClient>, err: reqwest::Error) {
         "unknown"
     };
     metrics
-        .incr_with_tags("megaphone.updater.error")
+        .incr_with_tags(metric)
         .with_tag("reason", reason)
         .send();
     if reason == "unknown" {
-        error!("📢megaphone::updater failed: {}", err);
+        error!("📢{} failed: {}", metric, err);
         sentry::capture_event(sentry::event_from_error(&err));
     } else {
-        trace!("📢megaphone::updater failed (reason: {}): {}", reason, err);
-    }
-}
-
-/// Refresh the `BroadcastChangeTracker`'s Broadcasts from the Megaphone service
-async fn updater(
-    broadcaster: &Arc<RwLock<BroadcastChangeTracker>>,
-    http: &reqwest::Client,
-    url: &str,
-    token: &str,
-) -> reqwest::Result<()> {
-    trace!("📢megaphone::updater");
-    let MegaphoneResponse { broadcasts } = http
-        .get(url)
-        .header("Authorization", token)
-        .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await?;
-    let broadcasts = Broadcast::from_hashmap(broadcasts);
-    if !broadcasts.is_empty() {
-        let change_count = broadcaster.write().await.add_broadcasts(broadcasts);
-        trace!("📢 add_broadcast change_count: {:?}", change_count);
+        trace!("📢{} failed (reason: {}): {}", metric, reason, err);
     }
-    Ok(())
 }
 
 /// Determine if a source of [reqwest::Error] was a [hyper::Error] Io Error
@@ -119,3 +446,191 @@ fn is_hyper_io(err: &hyper::Error) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use cadence::{NopMetricSink, StatsdClient};
+    use tokio::sync::RwLock;
+
+    use super::{init_and_spawn_megaphone_updater, EventStream, Updater};
+    use crate::broadcast::{Broadcast, BroadcastChangeTracker};
+
+    fn updater(url: String, broadcasts: Vec<Broadcast>, snapshot_path: Option<PathBuf>) -> Updater {
+        Updater {
+            broadcaster: Arc::new(RwLock::new(BroadcastChangeTracker::new(broadcasts))),
+            http: reqwest::Client::new(),
+            metrics: Arc::new(StatsdClient::builder("", NopMetricSink).build()),
+            url,
+            token: "token".to_owned(),
+            snapshot_path,
+            synced_at: AtomicU64::new(0),
+        }
+    }
+
+    fn broadcasts(broadcasts: &[(&str, &str)]) -> Vec<Broadcast> {
+        broadcasts
+            .iter()
+            .map(|(id, ver)| Broadcast::from((id.to_string(), ver.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn event_stream() {
+        let mut events = EventStream::default();
+        assert!(events.feed(b": keepalive\n\n").is_empty());
+        assert!(events
+            .feed(b"event: update\nid: 1\ndata: {\"a\"")
+            .is_empty());
+        assert!(events.feed(b":1}\r\n").is_empty());
+        assert_eq!(
+            events.feed(b"\r\ndata:x\ndata: y\n\n"),
+            vec![r#"{"a":1}"#.to_owned(), "x\ny".to_owned()]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn subscribe_applies_updates() {
+        let mut server = mockito::Server::new_async().await;
+        let stream = server
+            .mock("GET", "/stream")
+            .match_header("Authorization", "token")
+            .with_header("Content-Type", "text/event-stream")
+            .with_body(concat!(
+                ": keepalive\n\n",
+                "data: {\"broadcasts\": {\"foo/bar\": \"v2\"}}\n\n",
+                "data: garbage\n\n",
+                "data: {\"broadcasts\": {\"baz/quux\": \"v5\"}}\n\n",
+            ))
+            .create_async()
+            .await;
+        let updater = updater(server.url(), broadcasts(&[("foo/bar", "v1")]), None);
+        let mut caught_up = false;
+
+        updater
+            .subscribe(
+                &reqwest::Client::new(),
+                &format!("{}/stream", server.url()),
+                Duration::from_secs(5),
+                async { caught_up = true },
+            )
+            .await
+            .unwrap();
+        stream.assert_async().await;
+        assert!(caught_up);
+        assert!(updater.staleness().is_some());
+
+        let client = broadcasts(&[("foo/bar", "v1"), ("baz/quux", "v0")]);
+        let mut delta = updater.broadcaster.read().await.broadcast_delta(&client).1;
+        delta.sort_by_key(|b| serde_json::to_string(b).unwrap());
+        assert_eq!(delta, broadcasts(&[("baz/quux", "v5"), ("foo/bar", "v2")]));
+    }
+
+    #[actix_rt::test]
+    async fn snapshot_only_on_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("broadcasts.json");
+        let mut server = mockito::Server::new_async().await;
+        let ok = server
+            .mock("GET", "/broadcasts")
+            .with_body(r#"{"broadcasts": {"foo/bar": "v1"}}"#)
+            .expect(2)
+            .create_async()
+            .await;
+        let updater = updater(
+            format!("{}/broadcasts", server.url()),
+            vec![],
+            Some(snapshot_path.clone()),
+        );
+        updater.update().await.unwrap();
+        assert!(snapshot_path.exists());
+
+        // Not rewritten when nothing changed
+        std::fs::remove_file(&snapshot_path).unwrap();
+        updater.update().await.unwrap();
+        assert!(!snapshot_path.exists());
+        ok.assert_async().await;
+        ok.remove_async().await;
+
+        // Nor synced by an (untrusted) empty response
+        server
+            .mock("GET", "/broadcasts")
+            .with_body(r#"{"broadcasts": {}}"#)
+            .create_async()
+            .await;
+        updater.synced_at.store(0, Ordering::Relaxed);
+        updater.update().await.unwrap();
+        assert!(updater.staleness().is_none());
+        assert!(!snapshot_path.exists());
+    }
+
+    #[actix_rt::test]
+    async fn snapshot_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("broadcasts.json");
+        let mut server = mockito::Server::new_async().await;
+        let ok = server
+            .mock("GET", "/broadcasts")
+            .with_body(r#"{"broadcasts": {"foo/bar": "v1", "baz/quux": "v2"}}"#)
+            .create_async()
+            .await;
+        let url = format!("{}/broadcasts", server.url());
+        updater(url.clone(), vec![], Some(snapshot_path.clone()))
+            .update()
+            .await
+            .unwrap();
+        ok.assert_async().await;
+        ok.remove_async().await;
+
+        // Megaphone's now unavailable
+        server
+            .mock("GET", "/broadcasts")
+            .with_status(503)
+            .create_async()
+            .await;
+        let unavailable = updater(url.clone(), vec![], None);
+        assert!(init_and_spawn_megaphone_updater(
+            &unavailable.broadcaster,
+            &unavailable.http,
+            &unavailable.metrics,
+            &url,
+            "token",
+            Duration::from_secs(60),
+            None,
+            None,
+        )
+        .await
+        .is_err());
+
+        let unavailable = updater(url.clone(), vec![], None);
+        init_and_spawn_megaphone_updater(
+            &unavailable.broadcaster,
+            &unavailable.http,
+            &unavailable.metrics,
+            &url,
+            "token",
+            Duration::from_secs(60),
+            None,
+            snapshot_path.to_str(),
+        )
+        .await
+        .unwrap();
+        let client = broadcasts(&[("foo/bar", "v0"), ("baz/quux", "v0")]);
+        let mut delta = unavailable
+            .broadcaster
+            .read()
+            .await
+            .broadcast_delta(&client)
+            .1;
+        delta.sort_by_key(|b| serde_json::to_string(b).unwrap());
+        assert_eq!(delta, broadcasts(&[("baz/quux", "v2"), ("foo/bar", "v1")]));
+    }
+}
@@ -0,0 +1,284 @@
+//! Encodings of the WebPush protocol messages over WebSocket frames
+//!
+//! JSON text frames ([JsonCodec]) are the default. Clients may instead
+//! request a compact binary encoding via the WebSocket subprotocol
+//! (`Sec-WebSocket-Protocol`) header:
+//!
+//! - [MSGPACK_SUBPROTOCOL]: MessagePack binary frames ([MessagePackCodec]).
+//!   Messages have the same shape as their JSON counterparts (maps keyed by
+//!   the same field names) except for Notifications' `data`, which is sent as
+//!   raw bytes rather than base64 encoded.
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use serde_derive::Serialize;
+use uuid::Uuid;
+
+use autopush_common::util::b64_decode_url;
+
+use super::{ClientMessage, ServerMessage};
+
+/// The WebSocket subprotocol selecting the [MessagePackCodec]
+pub const MSGPACK_SUBPROTOCOL: &str = "webpush-msgpack";
+
+/// A WebSocket data frame
+#[derive(Debug, Eq, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    #[error("Couldn't parse JSON message: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Couldn't decode MessagePack message: {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+
+    #[error("Couldn't encode MessagePack message: {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+
+    #[error("Invalid Notification data: {0}")]
+    InvalidData(#[from] base64::DecodeError),
+
+    #[error("Expected {0} frame")]
+    UnexpectedFrame(&'static str),
+}
+
+/// Encodes `ServerMessage`s and decodes `ClientMessage`s to/from WebSocket
+/// frames
+pub trait Codec: fmt::Debug + Send + Sync {
+    /// The WebSocket subprotocol selecting this codec (`None` for the default
+    /// codec, which requires no negotiation)
+    fn subprotocol(&self) -> Option<&'static str>;
+
+    /// Decode a frame sent by the Client
+    fn decode(&self, frame: Frame) -> Result<ClientMessage, CodecError>;
+
+    /// Encode a message to send to the Client
+    fn encode(&self, msg: &ServerMessage) -> Result<Frame, CodecError>;
+}
+
+/// Select a codec from the Client's requested (comma separated) WebSocket
+/// subprotocols, in order of preference, falling back to [JsonCodec]
+pub fn negotiate(requested: Option<&str>) -> Arc<dyn Codec> {
+    let requested = requested.unwrap_or_default();
+    for subprotocol in requested.split(',').map(str::trim) {
+        if subprotocol == MSGPACK_SUBPROTOCOL {
+            return Arc::new(MessagePackCodec);
+        }
+    }
+    Arc::new(JsonCodec)
+}
+
+/// The default codec: JSON text frames
+#[derive(Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn subprotocol(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn decode(&self, frame: Frame) -> Result<ClientMessage, CodecError> {
+        match frame {
+            Frame::Text(text) => Ok(text.parse()?),
+            Frame::Binary(_) => Err(CodecError::UnexpectedFrame("Text")),
+        }
+    }
+
+    fn encode(&self, msg: &ServerMessage) -> Result<Frame, CodecError> {
+        Ok(Frame::Text(msg.to_json()?))
+    }
+}
+
+/// MessagePack binary frames
+#[derive(Debug, Default)]
+pub struct MessagePackCodec;
+
+/// The MessagePack representation of a `ServerMessage::Notification`,
+/// carrying its `data` as raw bytes
+#[derive(Serialize)]
+struct MessagePackNotification<'a> {
+    #[serde(rename = "messageType")]
+    message_type: &'static str,
+    #[serde(rename = "channelID")]
+    channel_id: &'a Uuid,
+    version: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    data: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<&'a HashMap<String, String>>,
+}
+
+impl Codec for MessagePackCodec {
+    fn subprotocol(&self) -> Option<&'static str> {
+        Some(MSGPACK_SUBPROTOCOL)
+    }
+
+    fn decode(&self, frame: Frame) -> Result<ClientMessage, CodecError> {
+        let Frame::Binary(bytes) = frame else {
+            return Err(CodecError::UnexpectedFrame("Binary"));
+        };
+        // As with JSON, an empty map is a Ping
+        if rmp_serde::from_slice::<HashMap<(), ()>>(&bytes).is_ok_and(|map| map.is_empty()) {
+            return Ok(ClientMessage::Ping);
+        }
+        let mut de = rmp_serde::Deserializer::from_read_ref(&bytes).with_human_readable();
+        Ok(serde::Deserialize::deserialize(&mut de)?)
+    }
+
+    fn encode(&self, msg: &ServerMessage) -> Result<Frame, CodecError> {
+        let mut buf = Vec::new();
+        let mut ser = rmp_serde::Serializer::new(&mut buf)
+            .with_struct_map()
+            .with_human_readable();
+        match msg {
+            ServerMessage::Ping { resume_token: None } => {
+                serde::Serialize::serialize(&HashMap::<(), ()>::new(), &mut ser)?;
+            }
+            ServerMessage::Notification(notif) => {
+                let data = notif.data.as_deref().map(b64_decode_url).transpose()?;
+                let notif = MessagePackNotification {
+                    message_type: "notification",
+                    channel_id: &notif.channel_id,
+                    version: &notif.version,
+                    data,
+                    headers: notif.headers.as_ref(),
+                };
+                serde::Serialize::serialize(&notif, &mut ser)?;
+            }
+            _ => serde::Serialize::serialize(msg, &mut ser)?,
+        }
+        Ok(Frame::Binary(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_derive::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use autopush_common::notification::Notification;
+
+    use super::*;
+
+    const CHID: Uuid = Uuid::from_u128(0xdeadbeef_0000_0000_abad_1dea00000000);
+
+    /// What a MessagePack Client would decode a Notification as
+    #[derive(Debug, Deserialize)]
+    struct ClientNotification {
+        #[serde(rename = "messageType")]
+        message_type: String,
+        #[serde(rename = "channelID")]
+        channel_id: String,
+        version: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    #[test]
+    fn negotiate_subprotocol() {
+        assert_eq!(negotiate(None).subprotocol(), None);
+        assert_eq!(negotiate(Some("foo, bar")).subprotocol(), None);
+        assert_eq!(
+            negotiate(Some("foo, webpush-msgpack")).subprotocol(),
+            Some(MSGPACK_SUBPROTOCOL)
+        );
+    }
+
+    #[test]
+    fn json_default() {
+        let codec = JsonCodec;
+        assert!(matches!(
+            codec.decode(Frame::Text("{}".to_owned())).unwrap(),
+            ClientMessage::Ping
+        ));
+        assert!(matches!(
+            codec.decode(Frame::Binary(vec![])),
+            Err(CodecError::UnexpectedFrame("Text"))
+        ));
+        assert_eq!(
+            codec
+                .encode(&ServerMessage::Ping { resume_token: None })
+                .unwrap(),
+            Frame::Text("{}".to_owned())
+        );
+        assert_eq!(
+            codec
+                .encode(&ServerMessage::Ping {
+                    resume_token: Some("foo".to_owned())
+                })
+                .unwrap(),
+            Frame::Text(r#"{"messageType":"ping","resume_token":"foo"}"#.to_owned())
+        );
+    }
+
+    #[test]
+    fn msgpack_client_messages() {
+        #[derive(Serialize)]
+        struct Ack {
+            #[serde(rename = "messageType")]
+            message_type: &'static str,
+            updates: Vec<HashMap<&'static str, String>>,
+        }
+        let codec = MessagePackCodec;
+        let ack = Ack {
+            message_type: "ack",
+            updates: vec![HashMap::from([
+                ("channelID", CHID.as_hyphenated().to_string()),
+                ("version", "foo".to_owned()),
+            ])],
+        };
+        let bytes = rmp_serde::to_vec_named(&ack).unwrap();
+        let ClientMessage::Ack { updates } = codec.decode(Frame::Binary(bytes)).unwrap() else {
+            panic!("Expected an Ack");
+        };
+        assert_eq!(updates[0].channel_id, CHID);
+        assert_eq!(updates[0].version, "foo");
+
+        let ping = rmp_serde::to_vec(&HashMap::<(), ()>::new()).unwrap();
+        assert!(matches!(
+            codec.decode(Frame::Binary(ping)).unwrap(),
+            ClientMessage::Ping
+        ));
+        assert!(matches!(
+            codec.decode(Frame::Text("{}".to_owned())),
+            Err(CodecError::UnexpectedFrame("Binary"))
+        ));
+    }
+
+    #[test]
+    fn msgpack_notification_raw_data() {
+        let codec = MessagePackCodec;
+        let notif = Notification {
+            channel_id: CHID,
+            version: "foo".to_owned(),
+            // b64 url encoded [0xff; 30]
+            data: Some("_".repeat(40)),
+            ..Default::default()
+        };
+        let Frame::Binary(bytes) = codec
+            .encode(&ServerMessage::Notification(notif.clone()))
+            .unwrap()
+        else {
+            panic!("Expected a Binary frame");
+        };
+        let decoded: ClientNotification = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded.message_type, "notification");
+        assert_eq!(decoded.channel_id, CHID.as_hyphenated().to_string());
+        assert_eq!(decoded.version, "foo");
+        assert_eq!(decoded.data, vec![0xff; 30]);
+
+        // Smaller than its JSON equivalent
+        let Frame::Text(json) = JsonCodec
+            .encode(&ServerMessage::Notification(notif))
+            .unwrap()
+        else {
+            panic!("Expected a Text frame");
+        };
+        assert!(bytes.len() < json.len());
+    }
+}
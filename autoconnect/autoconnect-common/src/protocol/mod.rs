@@ -6,6 +6,9 @@
 //! are used to generate the ability to serialize these structures to JSON,
 //! using the `serde` crate. More docs for serde can be found at
 //! <https://serde.rs>
+//!
+//! How these messages are framed over the WebSocket connection is determined
+//! by the negotiated [codec::Codec] (JSON text frames by default).
 use std::collections::HashMap;
 use std::str::FromStr;
 
@@ -14,6 +17,8 @@ use uuid::Uuid;
 
 use autopush_common::notification::Notification;
 
+pub mod codec;
+
 #[derive(Debug, Eq, PartialEq, Serialize)]
 #[serde(untagged)]
 pub enum BroadcastValue {
@@ -26,6 +31,9 @@ pub enum BroadcastValue {
 pub enum ServerNotification {
     CheckStorage,
     Notification(Notification),
+    /// An Ack from a client that acknowledges messages out of band (via the
+    /// RFC 8030 `DELETE /message` endpoint) rather than over its connection
+    Ack(ClientAck),
     #[default]
     Disconnect,
 }
@@ -39,6 +47,14 @@ pub enum ClientMessage {
         _channel_ids: Option<Vec<Uuid>>,
         #[serde(skip_serializing_if = "Option::is_none")]
         broadcasts: Option<HashMap<String, String>>,
+        /// A token from a previous session's Hello response, resuming that
+        /// session
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+        /// The resumed session's delivery cursor: Notifications the Client
+        /// received (and processed) that the server may not have seen Ack'd
+        #[serde(skip_serializing_if = "Option::is_none")]
+        acked: Option<Vec<ClientAck>>,
     },
 
     Register {
@@ -80,7 +96,7 @@ impl FromStr for ClientMessage {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ClientAck {
     #[serde(rename = "channelID")]
     pub channel_id: Uuid,
@@ -96,6 +112,9 @@ pub enum ServerMessage {
         // This is required for output, but will always be "true"
         use_webpush: bool,
         broadcasts: HashMap<String, BroadcastValue>,
+        /// Allows a reconnecting Client to resume this session
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
     },
 
     Register {
@@ -118,7 +137,11 @@ pub enum ServerMessage {
 
     Notification(Notification),
 
-    Ping,
+    Ping {
+        /// A refreshed session resume token (see `Hello`)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+    },
 }
 
 impl ServerMessage {
@@ -126,7 +149,7 @@ impl ServerMessage {
         match self {
             // clients recognize {"messageType": "ping"} but traditionally both
             // client/server send the empty object version
-            ServerMessage::Ping => Ok("{}".to_owned()),
+            ServerMessage::Ping { resume_token: None } => Ok("{}".to_owned()),
             _ => serde_json::to_string(self),
         }
     }
@@ -7,7 +7,7 @@ use uuid::Uuid;
 use autopush_common::errors::{ApcErrorKind, Result};
 use autopush_common::notification::Notification;
 
-use crate::protocol::ServerNotification;
+use crate::protocol::{ClientAck, ServerNotification};
 
 /// A connected Websocket client.
 #[derive(Debug)]
@@ -81,6 +81,24 @@ impl ClientRegistry {
         Err(ApcErrorKind::GeneralError("User not connected".into()).into())
     }
 
+    /// An Ack for a notification delivered to the uaid has come (from an
+    /// RFC 8030 client)
+    ///
+    /// Only forwarded to the connection `uid` the notification was delivered
+    /// over.
+    pub async fn ack(&self, uaid: Uuid, uid: Uuid, ack: ClientAck) -> Result<()> {
+        trace!("ClientRegistry::ack");
+        let clients = self.clients.read().await;
+        if let Some(client) = clients.get(&uaid).filter(|client| client.uid == uid) {
+            let result = client.tx.unbounded_send(ServerNotification::Ack(ack));
+            if result.is_ok() {
+                debug!("ClientRegistry::ack Forwarded ack to client");
+                return Ok(());
+            }
+        }
+        Err(ApcErrorKind::GeneralError("User not connected".into()).into())
+    }
+
     /// The client specified by `uaid` has disconnected.
     pub async fn disconnect(&self, uaid: &Uuid, uid: &Uuid) -> Result<()> {
         trace!("ClientRegistry::disconnect");
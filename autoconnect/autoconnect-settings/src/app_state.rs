@@ -24,12 +24,16 @@ pub struct AppState {
 
     /// Encryption object for the endpoint URL
     pub fernet: MultiFernet,
+    /// The individual `fernet` keys, for decrypting tokens with a TTL
+    pub fernet_keys: Arc<Vec<Fernet>>,
     /// The connected WebSocket clients
     pub clients: Arc<ClientRegistry>,
     /// The Megaphone Broadcast change tracker
     pub broadcaster: Arc<RwLock<BroadcastChangeTracker>>,
 
     pub settings: Settings,
+    /// Keys accepted for signed internal routing requests
+    pub router_auth_keys: Arc<Vec<String>>,
     pub router_url: String,
     pub endpoint_url: String,
 }
@@ -51,7 +55,18 @@ impl AppState {
                 Fernet::new(&key).unwrap_or_else(|| panic!("Invalid {ENV_PREFIX}_CRYPTO_KEY"))
             })
             .collect();
-        let fernet = MultiFernet::new(fernets);
+        let fernet = MultiFernet::new(fernets.clone());
+        let router_auth_keys = &settings.router_auth_keys.replace(['"', ' '], "");
+        if !(router_auth_keys.starts_with('[') && router_auth_keys.ends_with(']')) {
+            return Err(ConfigError::Message(format!(
+                "Invalid {ENV_PREFIX}_ROUTER_AUTH_KEYS"
+            )));
+        }
+        let router_auth_keys: Vec<String> = router_auth_keys[1..router_auth_keys.len() - 1]
+            .split(',')
+            .filter(|key| !key.is_empty())
+            .map(str::to_owned)
+            .collect();
         let metrics = autopush_common::metrics::builder(
             &settings.statsd_label,
             &settings.statsd_host,
@@ -87,7 +102,12 @@ impl AppState {
             .timeout(Duration::from_secs(1))
             .build()
             .unwrap_or_else(|e| panic!("Error while building reqwest::Client: {}", e));
-        let broadcaster = Arc::new(RwLock::new(BroadcastChangeTracker::new(Vec::new())));
+        // Retain tombstones long enough for every connected Client to be
+        // sent them with their next Ping
+        let broadcaster = Arc::new(RwLock::new(
+            BroadcastChangeTracker::new(Vec::new())
+                .with_tombstone_ttl(settings.auto_ping_interval + settings.auto_ping_timeout),
+        ));
 
         let router_url = settings.router_url();
         let endpoint_url = settings.endpoint_url();
@@ -97,9 +117,11 @@ impl AppState {
             metrics,
             http,
             fernet,
+            fernet_keys: Arc::new(fernets),
             clients: Arc::new(ClientRegistry::default()),
             broadcaster,
             settings,
+            router_auth_keys: Arc::new(router_auth_keys),
             router_url,
             endpoint_url,
         })
@@ -110,6 +132,11 @@ impl AppState {
     /// Via `autoconnect_common::megaphone::init_and_spawn_megaphone_updater`
     pub async fn init_and_spawn_megaphone_updater(&self) -> Result<(), ConfigError> {
         let Some(ref url) = self.settings.megaphone_api_url else {
+            if self.settings.megaphone_api_stream_url.is_some() {
+                return Err(ConfigError::Message(format!(
+                    "{ENV_PREFIX}__MEGAPHONE_API_STREAM_URL requires {ENV_PREFIX}__MEGAPHONE_API_URL"
+                )));
+            }
             return Ok(());
         };
         let Some(ref token) = self.settings.megaphone_api_token else {
@@ -124,6 +151,8 @@ impl AppState {
             url,
             token,
             self.settings.megaphone_poll_interval,
+            self.settings.megaphone_api_stream_url.as_deref(),
+            self.settings.megaphone_snapshot_path.as_deref(),
         )
         .await
         .map_err(|e| ConfigError::Message(e.to_string()))?;
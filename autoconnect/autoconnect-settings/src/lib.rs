@@ -57,6 +57,14 @@ pub struct Settings {
     pub router_port: u16,
     /// The DNS name to use for internal routing
     pub router_hostname: Option<String>,
+    /// A stringified list of keys accepted for signed internal routing
+    /// requests (allows for key rotation). An empty list accepts unsigned
+    /// requests.
+    pub router_auth_keys: String,
+    /// How far (in seconds) a signed internal routing request's timestamp may
+    /// drift from the current time
+    #[serde(deserialize_with = "deserialize_u32_to_duration")]
+    pub router_auth_max_skew: Duration,
     /// The server based ping interval (also used for Broadcast sends)
     #[serde(deserialize_with = "deserialize_f64_to_duration")]
     pub auto_ping_interval: Duration,
@@ -94,11 +102,69 @@ pub struct Settings {
     /// How often to poll the server for new data
     #[serde(deserialize_with = "deserialize_u32_to_duration")]
     pub megaphone_poll_interval: Duration,
+    /// Server-Sent Events endpoint streaming Broadcast changes as they occur.
+    /// When set, polling `megaphone_api_url` only occurs while the stream is
+    /// disconnected
+    pub megaphone_api_stream_url: Option<String>,
+    /// File persisting the last known Broadcasts, loaded at startup should
+    /// the Megaphone service be unavailable
+    pub megaphone_snapshot_path: Option<String>,
+    /// The maximum (randomized, to spread load) delay in seconds before
+    /// Broadcast changes are pushed to subscribed Clients. 0 disables this:
+    /// changes are delivered with each Client's next auto Ping instead
+    #[serde(deserialize_with = "deserialize_u32_to_duration")]
+    pub broadcast_fanout_jitter: Duration,
     /// Use human readable (simplified, non-JSON)
     pub human_logs: bool,
     /// Maximum allowed number of backlogged messages. Exceeding this number will
     /// trigger a user reset because the user may have been offline way too long.
     pub msg_limit: u32,
+    /// Maximum number of Notifications sent to a Client awaiting its Ack.
+    /// Further Direct Notifications are queued (up to this many again, then
+    /// stored) and further stored Notifications left in storage until the
+    /// Client catches up
+    pub inflight_window: usize,
+    /// Maximum number of Notifications read from storage at a time (Topic
+    /// reads fetch one more)
+    pub storage_page_size: usize,
+    /// Maximum number of stored Notifications sent to a Client per session (0
+    /// for no limit). Any remaining are sent during its next session. Direct
+    /// Notifications spilled to storage during the session don't count
+    /// towards it
+    pub storage_session_limit: u32,
+    /// Send stored Topic and timestamp Notifications interleaved by age
+    /// (oldest first) rather than all Topic Notifications first
+    pub storage_interleave: bool,
+    /// How long (in seconds) a session resume token remains valid for a
+    /// reconnecting Client. Tokens are issued in the Hello response and
+    /// refreshed with each Ping reply, so Clients should Ping more often than
+    /// this. 0 disables session resumption
+    #[serde(deserialize_with = "deserialize_u32_to_duration")]
+    pub resume_token_ttl: Duration,
+    /// How long (in seconds) to wait before redelivering a Notification the
+    /// Client Nack'd with a transient error code. Doubles with each
+    /// subsequent redelivery of the same Notification (up to an hour).
+    /// Redeliveries are scheduled in memory and lost if the node restarts
+    #[serde(deserialize_with = "deserialize_u32_to_duration")]
+    pub nack_redelivery_delay: Duration,
+    /// Maximum number of times a Nack'd Notification is redelivered before
+    /// it's dropped
+    pub nack_max_redeliveries: u32,
+    /// How long (in seconds) a Client with no outstanding Notifications may
+    /// be idle before it's hibernated: its session state is reduced to what's
+    /// needed to rebuild it on its next message or Notification. 0 disables
+    /// hibernation
+    #[serde(deserialize_with = "deserialize_u32_to_duration")]
+    pub hibernate_idle_timeout: Duration,
+    /// Whether to accept WebSocket permessage-deflate (RFC 7692) compression
+    /// when offered by the Client
+    pub ws_deflate: bool,
+    /// The minimum size (in bytes) of an outgoing WebSocket message to be
+    /// compressed: smaller messages are sent uncompressed
+    pub ws_deflate_threshold: usize,
+    /// The maximum size (in bytes) a compressed incoming WebSocket message may
+    /// inflate to
+    pub ws_deflate_max_message_size: usize,
     /// Sets the maximum number of concurrent connections per actix-web worker.
     ///
     /// All socket listeners will stop accepting connections when this limit is
@@ -118,6 +184,8 @@ impl Default for Settings {
             resolve_hostname: false,
             router_port: 8081,
             router_hostname: None,
+            router_auth_keys: "[]".to_owned(),
+            router_auth_max_skew: Duration::from_secs(60),
             auto_ping_interval: Duration::from_secs(300),
             auto_ping_timeout: Duration::from_secs(4),
             open_handshake_timeout: Duration::from_secs(5),
@@ -135,8 +203,22 @@ impl Default for Settings {
             megaphone_api_url: None,
             megaphone_api_token: None,
             megaphone_poll_interval: Duration::from_secs(30),
+            megaphone_api_stream_url: None,
+            megaphone_snapshot_path: None,
+            broadcast_fanout_jitter: Duration::from_secs(0),
             human_logs: false,
             msg_limit: 150,
+            inflight_window: 100,
+            storage_page_size: 10,
+            storage_session_limit: 0,
+            storage_interleave: false,
+            resume_token_ttl: Duration::from_secs(60),
+            nack_redelivery_delay: Duration::from_secs(10),
+            nack_max_redeliveries: 3,
+            hibernate_idle_timeout: Duration::from_secs(0),
+            ws_deflate: false,
+            ws_deflate_threshold: 256,
+            ws_deflate_max_message_size: 65_536,
             actix_max_connections: None,
             actix_workers: None,
         }
@@ -215,6 +297,17 @@ impl Settings {
         non_zero(self.megaphone_poll_interval, "MEGAPHONE_POLL_INTERVAL")?;
         non_zero(self.auto_ping_interval, "AUTO_PING_INTERVAL")?;
         non_zero(self.auto_ping_timeout, "AUTO_PING_TIMEOUT")?;
+        let non_zero_usize = |val: usize, name| {
+            if val == 0 {
+                return Err(ConfigError::Message(format!(
+                    "Invalid {}_{}: cannot be 0",
+                    ENV_PREFIX, name
+                )));
+            }
+            Ok(())
+        };
+        non_zero_usize(self.inflight_window, "INFLIGHT_WINDOW")?;
+        non_zero_usize(self.storage_page_size, "STORAGE_PAGE_SIZE")?;
         Ok(())
     }
 
@@ -2,7 +2,11 @@ use actix_http::ws::HandshakeError;
 use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
 use serde_json::json;
 
-use autopush_common::errors::ReportableError;
+use autopush_common::{
+    db::error::DbError,
+    errors::{ApcError, ReportableError},
+    router_auth::RouterAuthError,
+};
 
 /// The main error type
 #[derive(thiserror::Error, Debug)]
@@ -12,6 +16,21 @@ pub enum ApiError {
 
     #[error("LogCheck")]
     LogCheck,
+
+    #[error("Router auth error: {0}")]
+    RouterAuth(#[from] RouterAuthError),
+
+    #[error("Invalid notification: {0}")]
+    InvalidNotification(#[from] serde_json::Error),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Database error: {0}")]
+    Database(#[from] DbError),
+
+    #[error("Error while creating endpoint URL: {0}")]
+    EndpointUrl(#[source] ApcError),
 }
 
 impl ResponseError for ApiError {
@@ -19,6 +38,12 @@ impl ResponseError for ApiError {
         match self {
             ApiError::Actix(e) => e.as_response_error().status_code(),
             ApiError::LogCheck => StatusCode::IM_A_TEAPOT,
+            ApiError::RouterAuth(_) => StatusCode::UNAUTHORIZED,
+            ApiError::InvalidNotification(_) | ApiError::InvalidRequest(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::Database(e) => e.status(),
+            ApiError::EndpointUrl(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
@@ -33,10 +58,23 @@ impl ResponseError for ApiError {
 }
 
 impl ReportableError for ApiError {
+    fn reportable_source(&self) -> Option<&(dyn ReportableError + 'static)> {
+        match self {
+            ApiError::Database(e) => Some(e),
+            ApiError::EndpointUrl(e) => Some(e),
+            _ => None,
+        }
+    }
+
     fn is_sentry_event(&self) -> bool {
         match self {
             // Ignore failing upgrade to WebSocket
             ApiError::Actix(e) => e.as_error::<HandshakeError>().is_none(),
+            // Rejected routing requests are tracked via metrics
+            ApiError::RouterAuth(_)
+            | ApiError::InvalidNotification(_)
+            | ApiError::InvalidRequest(_) => false,
+            ApiError::Database(e) => e.is_sentry_event(),
             _ => true,
         }
     }
@@ -46,8 +84,10 @@ impl ApiError {
     /// Return a unique errno code per variant
     pub fn errno(&self) -> i32 {
         match self {
-            ApiError::Actix(_) => 500,
+            ApiError::Actix(_) | ApiError::Database(_) | ApiError::EndpointUrl(_) => 500,
             ApiError::LogCheck => 999,
+            ApiError::RouterAuth(_) => 401,
+            ApiError::InvalidNotification(_) | ApiError::InvalidRequest(_) => 400,
         }
     }
 }
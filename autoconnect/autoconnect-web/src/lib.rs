@@ -36,6 +36,17 @@ pub fn config(cfg: &mut web::ServiceConfig) {
     cfg
         // Websocket Handler
         .route("/", web::get().to(routes::ws_route))
+        // RFC 8030 Push Service for user agents
+        .service(web::resource("/subscribe").route(web::post().to(routes::subscribe_route)))
+        .service(
+            web::resource("/subscription/{uaid}/{chid}")
+                .route(web::delete().to(routes::unsubscribe_route)),
+        )
+        .service(
+            web::resource("/subscription-set/{uaid}")
+                .route(web::get().to(routes::push_stream_route)),
+        )
+        .service(web::resource("/message/{message_id}").route(web::delete().to(routes::ack_route)))
         .service(web::scope("").configure(dockerflow::config));
 }
 
@@ -43,5 +54,9 @@ pub fn config(cfg: &mut web::ServiceConfig) {
 pub fn config_router(cfg: &mut web::ServiceConfig) {
     cfg.service(web::resource("/push/{uaid}").route(web::put().to(routes::push_route)))
         .service(web::resource("/notif/{uaid}").route(web::put().to(routes::check_storage_route)))
+        .service(
+            web::resource("/ack/{uaid}/{uid}/{chid}/{version}")
+                .route(web::put().to(routes::router_ack_route)),
+        )
         .service(web::scope("").configure(dockerflow::config));
 }
@@ -1,8 +1,18 @@
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::{
+    http::header::{LINK, LOCATION},
+    web, HttpRequest, HttpResponse,
+};
+use cadence::CountedExt;
+use serde_derive::Deserialize;
 use uuid::Uuid;
 
+use autoconnect_common::protocol::ClientAck;
 use autoconnect_settings::AppState;
-use autopush_common::notification::Notification;
+use autoconnect_ws::MessageId;
+use autopush_common::{
+    db::User, endpoint::make_endpoint, notification::Notification, router_auth,
+    util::sec_since_epoch,
+};
 
 use crate::error::ApiError;
 
@@ -15,38 +25,318 @@ pub async fn ws_route(
     Ok(autoconnect_ws::ws_handler(req, body, app_state).await?)
 }
 
+/// The Link relation of an RFC 8030 push resource
+const REL_PUSH: &str = "urn:ietf:params:push";
+/// The Link relation of an RFC 8030 subscription set
+const REL_PUSH_SET: &str = "urn:ietf:params:push:set";
+
+/// Optional body of an RFC 8030 subscription request
+#[derive(Debug, Default, Deserialize)]
+struct SubscribeRequest {
+    /// The application server's VAPID public key, restricting the subscription
+    /// to that application server (as with the WebSocket Register message)
+    key: Option<String>,
+}
+
+/// Extract the uaid of an existing subscription set from the request's `Link`
+/// header (RFC 8030 section 4.1)
+fn subscription_set(req: &HttpRequest) -> Result<Option<Uuid>, ApiError> {
+    for value in req.headers().get_all(LINK) {
+        let value = value
+            .to_str()
+            .map_err(|_| ApiError::InvalidRequest("Invalid Link header".to_owned()))?;
+        for link in value.split(',') {
+            let Some((target, params)) = link.trim().split_once(';') else {
+                continue;
+            };
+            let is_set = params
+                .split(';')
+                .any(|param| param.trim() == format!(r#"rel="{REL_PUSH_SET}""#));
+            if !is_set {
+                continue;
+            }
+            let uaid = target
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .rsplit('/')
+                .next()
+                .and_then(|uaid| Uuid::try_parse(uaid).ok())
+                .ok_or_else(|| ApiError::InvalidRequest("Invalid subscription set".to_owned()))?;
+            return Ok(Some(uaid));
+        }
+    }
+    Ok(None)
+}
+
+/// Create an RFC 8030 push message subscription
+///
+/// The new subscription is added to the subscription set specified by the
+/// request's `Link` header, otherwise a new set (User) is created.
+pub async fn subscribe_route(
+    req: HttpRequest,
+    body: web::Bytes,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let request: SubscribeRequest = if body.is_empty() {
+        Default::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| ApiError::InvalidRequest(e.to_string()))?
+    };
+    let uaid = match subscription_set(&req)? {
+        Some(uaid) => {
+            if app_state.db.get_user(&uaid).await?.is_none() {
+                return Ok(HttpResponse::NotFound().body("Subscription set not found"));
+            }
+            uaid
+        }
+        None => {
+            let user = User::builder()
+                .build()
+                .map_err(|e| ApiError::InvalidRequest(format!("User::builder error: {e}")))?;
+            app_state.db.add_user(&user).await?;
+            user.uaid
+        }
+    };
+    let channel_id = Uuid::new_v4();
+    trace!(
+        "⏩ subscribe_route, uaid: {} channel_id: {}",
+        uaid,
+        channel_id
+    );
+    let endpoint = make_endpoint(
+        &uaid,
+        &channel_id,
+        request.key.as_deref(),
+        &app_state.endpoint_url,
+        &app_state.fernet,
+    )
+    .map_err(ApiError::EndpointUrl)?;
+    app_state.db.add_channel(&uaid, &channel_id).await?;
+    app_state
+        .metrics
+        .incr_with_tags("ua.command.register")
+        .with_tag("protocol", "rfc8030")
+        .send();
+
+    Ok(HttpResponse::Created()
+        .insert_header((
+            LOCATION,
+            format!(
+                "/subscription/{}/{}",
+                uaid.as_simple(),
+                channel_id.as_hyphenated()
+            ),
+        ))
+        .append_header((LINK, format!(r#"<{endpoint}>; rel="{REL_PUSH}""#)))
+        .append_header((
+            LINK,
+            format!(
+                r#"</subscription-set/{}>; rel="{REL_PUSH_SET}""#,
+                uaid.as_simple()
+            ),
+        ))
+        .finish())
+}
+
+/// Remove an RFC 8030 push message subscription
+pub async fn unsubscribe_route(
+    path: web::Path<(Uuid, Uuid)>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (uaid, channel_id) = path.into_inner();
+    trace!(
+        "⏩ unsubscribe_route, uaid: {} channel_id: {}",
+        uaid,
+        channel_id
+    );
+    if !app_state.db.remove_channel(&uaid, &channel_id).await? {
+        return Ok(HttpResponse::NotFound().body("Subscription not found"));
+    }
+    app_state
+        .metrics
+        .incr_with_tags("ua.command.unregister")
+        .with_tag("protocol", "rfc8030")
+        .send();
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Receive push messages for an RFC 8030 subscription set
+pub async fn push_stream_route(
+    req: HttpRequest,
+    uaid: web::Path<Uuid>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(autoconnect_ws::push_stream_handler(req, uaid.into_inner(), app_state).await?)
+}
+
+/// Acknowledge an RFC 8030 push message delivered over a push stream
+///
+/// The message resource is an encrypted `MessageId`, only applying to the push
+/// stream it was delivered over. The push stream may be held by another node,
+/// in which case the Ack is routed to the node recorded for the user.
+pub async fn ack_route(
+    message_id: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let Some(MessageId {
+        uaid,
+        uid,
+        channel_id,
+        version,
+    }) = MessageId::decrypt(&app_state.fernet, &message_id)
+    else {
+        return Ok(HttpResponse::NotFound().body("Message not found"));
+    };
+    trace!("⏩ ack_route, uaid: {} channel_id: {}", uaid, channel_id);
+    let ack = ClientAck {
+        channel_id,
+        version,
+    };
+    if app_state.clients.ack(uaid, uid, ack.clone()).await.is_ok() {
+        return Ok(HttpResponse::NoContent().finish());
+    }
+    let node_id = app_state
+        .db
+        .get_user(&uaid)
+        .await?
+        .and_then(|user| user.node_id)
+        .filter(|node_id| *node_id != app_state.router_url);
+    if let Some(node_id) = node_id {
+        if route_ack(&app_state, &node_id, uaid, uid, &ack).await {
+            return Ok(HttpResponse::NoContent().finish());
+        }
+    }
+    Ok(HttpResponse::NotFound().body("Client not available"))
+}
+
+/// Forward an Ack to the node holding the user's push stream, returning
+/// whether the node accepted it
+async fn route_ack(
+    app_state: &AppState,
+    node_id: &str,
+    uaid: Uuid,
+    uid: Uuid,
+    ack: &ClientAck,
+) -> bool {
+    let path = format!(
+        "/ack/{}/{}/{}/{}",
+        uaid.as_simple(),
+        uid.as_simple(),
+        ack.channel_id.as_hyphenated(),
+        ack.version
+    );
+    let mut request = app_state.http.put(format!("{node_id}{path}"));
+    if let Some(key) = app_state.router_auth_keys.first() {
+        match router_auth::make_header(key, sec_since_epoch(), &path, &[]) {
+            Ok(header) => request = request.header(router_auth::ROUTER_AUTH_HEADER, header),
+            Err(e) => warn!("🔐 Unable to sign router request: {:?}", e),
+        }
+    }
+    match request.send().await {
+        Ok(response) => response.status().is_success(),
+        Err(e) => {
+            debug!("⏩ ack_route, failed to route to node: {}", e; "node_id" => node_id);
+            false
+        }
+    }
+}
+
+/// Verify the signature of an internal routing request (when router auth
+/// keys are configured)
+fn check_router_auth(req: &HttpRequest, body: &[u8], app_state: &AppState) -> Result<(), ApiError> {
+    if app_state.router_auth_keys.is_empty() {
+        return Ok(());
+    }
+    let header = req
+        .headers()
+        .get(router_auth::ROUTER_AUTH_HEADER)
+        .and_then(|v| v.to_str().ok());
+    router_auth::verify_header(
+        header,
+        &app_state.router_auth_keys,
+        req.path(),
+        body,
+        sec_since_epoch(),
+        app_state.settings.router_auth_max_skew.as_secs(),
+    )
+    .map_err(|e| {
+        warn!("🔐 Rejecting router request: {}", e; "path" => req.path());
+        app_state
+            .metrics
+            .incr_with_tags("ua.router.auth.rejected")
+            .with_tag("reason", e.metric_label())
+            .send();
+        e.into()
+    })
+}
+
 /// Deliver a Push notification directly to a connected client
 pub async fn push_route(
+    req: HttpRequest,
     uaid: web::Path<Uuid>,
-    notif: web::Json<Notification>,
+    body: web::Bytes,
     app_state: web::Data<AppState>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ApiError> {
+    check_router_auth(&req, &body, &app_state)?;
+    let notif: Notification = serde_json::from_slice(&body)?;
     trace!(
         "⏩ push_route, uaid: {} channel_id: {}",
         uaid,
         notif.channel_id
     );
+    let result = app_state.clients.notify(uaid.into_inner(), notif).await;
+    if result.is_ok() {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Ok(HttpResponse::NotFound().body("Client not available"))
+    }
+}
+
+/// Apply an Ack routed from another node to a connected push stream client
+pub async fn router_ack_route(
+    req: HttpRequest,
+    path: web::Path<(Uuid, Uuid, Uuid, String)>,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    check_router_auth(&req, &[], &app_state)?;
+    let (uaid, uid, channel_id, version) = path.into_inner();
+    trace!(
+        "⏩ router_ack_route, uaid: {} channel_id: {}",
+        uaid,
+        channel_id
+    );
     let result = app_state
         .clients
-        .notify(uaid.into_inner(), notif.into_inner())
+        .ack(
+            uaid,
+            uid,
+            ClientAck {
+                channel_id,
+                version,
+            },
+        )
         .await;
     if result.is_ok() {
-        HttpResponse::Ok().finish()
+        Ok(HttpResponse::NoContent().finish())
     } else {
-        HttpResponse::NotFound().body("Client not available")
+        Ok(HttpResponse::NotFound().body("Client not available"))
     }
 }
 
 /// Notify a connected client to check storage for new notifications
 pub async fn check_storage_route(
+    req: HttpRequest,
     uaid: web::Path<Uuid>,
+    body: web::Bytes,
     app_state: web::Data<AppState>,
-) -> HttpResponse {
+) -> Result<HttpResponse, ApiError> {
+    check_router_auth(&req, &body, &app_state)?;
     trace!("⏩ check_storage_route, uaid: {}", uaid);
     let result = app_state.clients.check_storage(uaid.into_inner()).await;
     if result.is_ok() {
-        HttpResponse::Ok().finish()
+        Ok(HttpResponse::Ok().finish())
     } else {
-        HttpResponse::NotFound().body("Client not available")
+        Ok(HttpResponse::NotFound().body("Client not available"))
     }
 }
@@ -6,11 +6,20 @@ use futures_util::{SinkExt, StreamExt};
 use serde_json::json;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use autoconnect_common::test_support::{hello_again_db, hello_db, DUMMY_UAID, HELLO, HELLO_AGAIN};
+use autoconnect_common::{
+    protocol::ServerNotification,
+    test_support::{hello_again_db, hello_db, DUMMY_CHID, DUMMY_UAID, HELLO, HELLO_AGAIN},
+};
 use autoconnect_settings::{AppState, Settings};
-use autopush_common::notification::Notification;
+use autoconnect_ws::MessageId;
+use autopush_common::{
+    db::{mock::MockDbClient, User},
+    notification::Notification,
+    router_auth,
+    util::sec_since_epoch,
+};
 
-use crate::{build_app, config};
+use crate::{build_app, config, config_router};
 
 #[ctor::ctor]
 fn init_test_logging() {
@@ -51,7 +60,8 @@ pub async fn hello_new_user() {
     assert_eq!(msg["use_webpush"], true);
     assert!(msg["uaid"].is_string());
     assert!(msg["broadcasts"].is_object());
-    assert_eq!(msg.as_object().map_or(0, |o| o.len()), 5);
+    assert!(msg["resume_token"].is_string());
+    assert_eq!(msg.as_object().map_or(0, |o| o.len()), 6);
 }
 
 #[actix_rt::test]
@@ -229,3 +239,248 @@ pub async fn broadcast_after_ping() {
         .expect("!broadcasts.is_object()");
     assert_eq!(broadcasts["foo/bar"].as_str(), Some("v2"));
 }
+
+#[actix_rt::test]
+pub async fn router_auth() {
+    let settings = Settings {
+        router_auth_keys: r#"["new_key", "old_key"]"#.to_owned(),
+        ..Settings::test_settings()
+    };
+    let app_state = AppState::from_settings(settings).unwrap();
+    let srv = actix_test::start(move || build_app!(app_state, config_router));
+    let path = format!("/notif/{}", DUMMY_UAID.as_simple());
+
+    // Unsigned requests are rejected
+    let response = srv.put(&path).send().await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::UNAUTHORIZED);
+
+    // As are requests signed with an unknown key
+    let header = router_auth::make_header("bad_key", sec_since_epoch(), &path, &[]).unwrap();
+    let response = srv
+        .put(&path)
+        .insert_header((router_auth::ROUTER_AUTH_HEADER, header))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::UNAUTHORIZED);
+
+    // Requests signed with a rotated key are accepted (the client isn't
+    // connected)
+    let header = router_auth::make_header("old_key", sec_since_epoch(), &path, &[]).unwrap();
+    let response = srv
+        .put(&path)
+        .insert_header((router_auth::ROUTER_AUTH_HEADER, header))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+pub async fn rfc8030_subscribe() {
+    let mut db = MockDbClient::new();
+    db.expect_add_user().times(1).return_once(|_| Ok(()));
+    db.expect_add_channel().times(1).return_once(|_, _| Ok(()));
+    db.expect_get_user().times(1).return_once(|_| Ok(None));
+    let srv = test_server(AppState {
+        db: db.into_boxed_arc(),
+        ..Default::default()
+    });
+
+    let response = srv.post("/subscribe").send().await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::CREATED);
+    let location = response
+        .headers()
+        .get("Location")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(location.starts_with("/subscription/"));
+    let links: Vec<_> = response
+        .headers()
+        .get_all("Link")
+        .map(|link| link.to_str().unwrap())
+        .collect();
+    assert_eq!(links.len(), 2);
+    assert!(links[0].contains("/wpush/") && links[0].ends_with(r#"rel="urn:ietf:params:push""#));
+    assert!(links[1].ends_with(r#"rel="urn:ietf:params:push:set""#));
+
+    // Adding to an unknown subscription set
+    let response = srv
+        .post("/subscribe")
+        .insert_header((
+            "Link",
+            format!(
+                r#"</subscription-set/{}>; rel="urn:ietf:params:push:set""#,
+                DUMMY_UAID.as_simple()
+            ),
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+pub async fn rfc8030_push_stream() {
+    let app_state = AppState {
+        db: hello_again_db(DUMMY_UAID).into_boxed_arc(),
+        ..Default::default()
+    };
+    let srv = test_server(app_state.clone());
+
+    let mut response = srv
+        .get(format!("/subscription-set/{}", DUMMY_UAID.as_simple()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::OK);
+    assert_eq!(
+        response.headers().get("Content-Type").unwrap(),
+        "text/event-stream"
+    );
+
+    // The push stream registers its client in the background
+    let notif = Notification {
+        channel_id: DUMMY_CHID,
+        version: "foo".to_owned(),
+        ttl: 60,
+        data: Some("bar".to_owned()),
+        ..Notification::default()
+    };
+    let mut delivered = false;
+    for _ in 0..10 {
+        if app_state
+            .clients
+            .notify(DUMMY_UAID, notif.clone())
+            .await
+            .is_ok()
+        {
+            delivered = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(delivered);
+
+    let chunk = response.next().await.unwrap().unwrap();
+    let event = std::str::from_utf8(&chunk).unwrap();
+    let (message, rest) = event
+        .strip_prefix("id: ")
+        .and_then(|event| event.split_once('\n'))
+        .unwrap();
+    assert!(rest.starts_with("event: push\ndata: "));
+    assert!(rest.contains(r#""data":"bar""#));
+
+    let response = srv.delete(message).send().await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::NO_CONTENT);
+}
+
+#[actix_rt::test]
+pub async fn rfc8030_ack_requires_message_id() {
+    let mut db = MockDbClient::new();
+    db.expect_get_user().times(2).returning(|_| Ok(None));
+    let app_state = AppState {
+        db: db.into_boxed_arc(),
+        ..Default::default()
+    };
+    // A client connected over the WebSocket
+    let uid = uuid::Uuid::new_v4();
+    let mut rx = app_state.clients.connect(DUMMY_UAID, uid).await;
+    let srv = test_server(app_state.clone());
+
+    // The message resource can't be derived from the message details
+    let response = srv
+        .delete(format!(
+            "/message/{}/{}/foo",
+            DUMMY_UAID.as_simple(),
+            DUMMY_CHID.as_hyphenated()
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::NOT_FOUND);
+    let response = srv.delete("/message/invalid").send().await.unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::NOT_FOUND);
+
+    // Nor does it apply to another connection of the subscription set
+    let message_id = |uid| {
+        MessageId {
+            uaid: DUMMY_UAID,
+            uid,
+            channel_id: DUMMY_CHID,
+            version: "foo".to_owned(),
+        }
+        .encrypt(&app_state.fernet)
+    };
+    for other_uid in [uuid::Uuid::new_v4(), uuid::Uuid::new_v4()] {
+        let response = srv
+            .delete(format!("/message/{}", message_id(other_uid)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), actix_http::StatusCode::NOT_FOUND);
+    }
+    assert!(rx.try_next().is_err());
+
+    let response = srv
+        .delete(format!("/message/{}", message_id(uid)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::NO_CONTENT);
+    let Some(ServerNotification::Ack(ack)) = rx.next().await else {
+        panic!("Expected an Ack");
+    };
+    assert_eq!(ack.version, "foo");
+}
+
+#[actix_rt::test]
+pub async fn rfc8030_ack_routed_to_node() {
+    let settings = Settings {
+        router_auth_keys: r#"["key"]"#.to_owned(),
+        ..Settings::test_settings()
+    };
+    // The node holding the push stream
+    let node_state = AppState::from_settings(settings.clone()).unwrap();
+    let uid = uuid::Uuid::new_v4();
+    let mut rx = node_state.clients.connect(DUMMY_UAID, uid).await;
+    let message_id = MessageId {
+        uaid: DUMMY_UAID,
+        uid,
+        channel_id: DUMMY_CHID,
+        version: "foo".to_owned(),
+    }
+    .encrypt(&node_state.fernet);
+    let node_app_state = node_state.clone();
+    let node = actix_test::start(move || build_app!(node_app_state, config_router));
+
+    // The node receiving the Ack
+    let node_id = node.url("").trim_end_matches('/').to_owned();
+    let mut db = MockDbClient::new();
+    db.expect_get_user().times(1).return_once(move |_| {
+        Ok(Some(
+            User::builder()
+                .uaid(DUMMY_UAID)
+                .node_id(node_id)
+                .build()
+                .unwrap(),
+        ))
+    });
+    let srv = test_server(AppState {
+        db: db.into_boxed_arc(),
+        ..AppState::from_settings(settings).unwrap()
+    });
+
+    let response = srv
+        .delete(format!("/message/{message_id}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), actix_http::StatusCode::NO_CONTENT);
+    let Some(ServerNotification::Ack(ack)) = rx.next().await else {
+        panic!("Expected an Ack");
+    };
+    assert_eq!(ack.channel_id, DUMMY_CHID);
+    assert_eq!(ack.version, "foo");
+}
@@ -0,0 +1,230 @@
+use std::{collections::HashMap, fmt, mem, sync::Arc};
+
+use cadence::{CountedExt, Timed};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use autoconnect_common::broadcast::{Broadcast, BroadcastSubs};
+use autoconnect_settings::{AppState, Settings};
+use autopush_common::{
+    db::User,
+    util::{ms_since_epoch, user_agent::UserAgentInfo},
+};
+
+use super::{AckState, ClientFlags, SessionStatistics, WebPushClient};
+
+/// An identified Client, which is either active or hibernating
+///
+/// Clients that are idle with no outstanding Notifications may be hibernated
+/// (via `hibernate`): the `WebPushClient` is dropped in favor of the minimal
+/// `HibernatedClient` needed to rebuild it. It's rebuilt (via `wake`) when
+/// it's next sent a message or Notification. WebSocket Pings and Broadcasts
+/// don't require waking it.
+#[derive(Debug)]
+pub enum IdentifiedClient {
+    Active(Box<WebPushClient>),
+    Hibernated(Box<HibernatedClient>),
+}
+
+/// The state retained by a hibernating Client to rebuild its
+/// `WebPushClient`
+pub struct HibernatedClient {
+    uaid: Uuid,
+    uid: Uuid,
+    /// The User-Agent header the Client's `UserAgentInfo` is derived from
+    ua: Box<str>,
+    broadcast_subs: BroadcastSubs,
+    flags: ClientFlags,
+    /// See `AckState::unacked_stored_highest`
+    unacked_stored_highest: Option<u64>,
+    /// Redelivery counts of Nack'd Notifications, as their redeliveries may
+    /// still be pending
+    nack_redeliveries: HashMap<String, u32>,
+    sent_from_storage: u32,
+    spilled_direct: u32,
+    deferred_add_user: Option<Box<User>>,
+    stats: SessionStatistics,
+    connected_at: u64,
+    last_ping: u64,
+    current_timestamp: Option<u64>,
+    /// When hibernation began
+    since: u64,
+
+    app_state: Arc<AppState>,
+}
+
+impl fmt::Debug for HibernatedClient {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("HibernatedClient")
+            .field("uaid", &self.uaid)
+            .field("uid", &self.uid)
+            .field("ua", &self.ua)
+            .field("broadcast_subs", &self.broadcast_subs)
+            .field("flags", &self.flags)
+            .field("unacked_stored_highest", &self.unacked_stored_highest)
+            .field("nack_redeliveries", &self.nack_redeliveries)
+            .field("sent_from_storage", &self.sent_from_storage)
+            .field("deferred_add_user", &self.deferred_add_user)
+            .field("stats", &self.stats)
+            .field("connected_at", &self.connected_at)
+            .field("last_ping", &self.last_ping)
+            .field("since", &self.since)
+            .finish()
+    }
+}
+
+impl From<WebPushClient> for IdentifiedClient {
+    fn from(client: WebPushClient) -> Self {
+        Self::Active(Box::new(client))
+    }
+}
+
+impl IdentifiedClient {
+    /// Hibernate an idle Client with no outstanding Notifications
+    ///
+    /// Returns whether the Client's now hibernating.
+    pub fn hibernate(&mut self) -> bool {
+        let Self::Active(client) = self else {
+            return true;
+        };
+        let ack_state = &client.ack_state;
+        if ack_state.unacked_notifs()
+            || !ack_state.pending_direct_notifs.is_empty()
+            || client.flags.check_storage
+            || client.flags.increment_storage
+        {
+            return false;
+        }
+        trace!("💤 IdentifiedClient::hibernate");
+        let hibernated = Box::new(HibernatedClient::take(client));
+        let _ = hibernated.app_state.metrics.incr("ua.connection.hibernate");
+        *self = Self::Hibernated(hibernated);
+        true
+    }
+
+    /// Return the `WebPushClient`, rebuilding it if the Client's hibernating
+    pub fn wake(&mut self) -> &mut WebPushClient {
+        if let Self::Hibernated(hibernated) = self {
+            trace!("💤 IdentifiedClient::wake");
+            let metrics = &hibernated.app_state.metrics;
+            let _ = metrics.incr("ua.connection.wake");
+            let _ = metrics.time(
+                "ua.connection.hibernated",
+                ms_since_epoch().saturating_sub(hibernated.since),
+            );
+            *self = Self::from(hibernated.rebuild());
+        }
+        match self {
+            Self::Active(client) => client,
+            Self::Hibernated(_) => unreachable!(),
+        }
+    }
+
+    /// Consume this, returning the `WebPushClient` (rebuilt if the Client's
+    /// hibernating)
+    pub fn into_client(self) -> Box<WebPushClient> {
+        match self {
+            Self::Active(client) => client,
+            Self::Hibernated(mut hibernated) => Box::new(hibernated.rebuild()),
+        }
+    }
+
+    /// Whether this Client's currently hibernating
+    pub fn is_hibernating(&self) -> bool {
+        matches!(self, Self::Hibernated(_))
+    }
+
+    /// Return a reference to `AppState`'s `Settings`
+    pub fn app_settings(&self) -> &Settings {
+        &self.app_state().settings
+    }
+
+    /// See `WebPushClient::broadcast_delta`
+    pub async fn broadcast_delta(&mut self) -> Option<Vec<Broadcast>> {
+        let (app_state, broadcast_subs) = match self {
+            Self::Active(client) => (&client.app_state, &mut client.broadcast_subs),
+            Self::Hibernated(hibernated) => (&hibernated.app_state, &mut hibernated.broadcast_subs),
+        };
+        app_state
+            .broadcaster
+            .read()
+            .await
+            .change_count_delta(broadcast_subs)
+    }
+
+    /// See `WebPushClient::broadcast_changes`
+    pub async fn broadcast_changes(&self) -> watch::Receiver<u32> {
+        self.app_state()
+            .broadcaster
+            .read()
+            .await
+            .subscribe_changes()
+    }
+
+    fn app_state(&self) -> &Arc<AppState> {
+        match self {
+            Self::Active(client) => &client.app_state,
+            Self::Hibernated(hibernated) => &hibernated.app_state,
+        }
+    }
+}
+
+impl HibernatedClient {
+    /// Take the state needed to rebuild an idle `WebPushClient` from it
+    ///
+    /// The remainder of the `WebPushClient` is left to be dropped.
+    fn take(client: &mut WebPushClient) -> Self {
+        let ack_state = &mut client.ack_state;
+        let mut nack_redeliveries = mem::take(&mut ack_state.nack_redeliveries);
+        nack_redeliveries.shrink_to_fit();
+        let mut broadcast_subs = mem::take(&mut client.broadcast_subs);
+        broadcast_subs.shrink_to_fit();
+        Self {
+            uaid: client.uaid,
+            uid: client.uid,
+            ua: mem::take(&mut client.ua_info)
+                .into_user_agent_string()
+                .into_boxed_str(),
+            broadcast_subs,
+            flags: mem::take(&mut client.flags),
+            unacked_stored_highest: ack_state.unacked_stored_highest,
+            nack_redeliveries,
+            sent_from_storage: client.sent_from_storage,
+            spilled_direct: client.spilled_direct,
+            deferred_add_user: client.deferred_add_user.take().map(Box::new),
+            stats: mem::take(&mut client.stats),
+            connected_at: client.connected_at,
+            last_ping: client.last_ping,
+            current_timestamp: client.current_timestamp,
+            since: ms_since_epoch(),
+            app_state: Arc::clone(&client.app_state),
+        }
+    }
+
+    /// Rebuild the `WebPushClient`
+    ///
+    /// Its resumed session cursor's not retained: it only applies to
+    /// Notifications read from storage, none of which were outstanding.
+    fn rebuild(&mut self) -> WebPushClient {
+        WebPushClient {
+            uaid: self.uaid,
+            uid: self.uid,
+            ua_info: UserAgentInfo::from(self.ua.as_ref()),
+            broadcast_subs: mem::take(&mut self.broadcast_subs),
+            flags: mem::take(&mut self.flags),
+            ack_state: AckState {
+                unacked_stored_highest: self.unacked_stored_highest,
+                nack_redeliveries: mem::take(&mut self.nack_redeliveries),
+                ..Default::default()
+            },
+            sent_from_storage: self.sent_from_storage,
+            spilled_direct: self.spilled_direct,
+            deferred_add_user: self.deferred_add_user.take().map(|user| *user),
+            stats: mem::take(&mut self.stats),
+            connected_at: self.connected_at,
+            last_ping: self.last_ping,
+            current_timestamp: self.current_timestamp,
+            app_state: Arc::clone(&self.app_state),
+        }
+    }
+}
@@ -1,13 +1,18 @@
-use std::{fmt, mem, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, mem,
+    sync::Arc,
+};
 
 use actix_web::rt;
 use cadence::Timed;
 use futures::channel::mpsc;
+use tokio::sync::watch;
 use uuid::Uuid;
 
 use autoconnect_common::{
     broadcast::{Broadcast, BroadcastSubs},
-    protocol::{ServerMessage, ServerNotification},
+    protocol::{ClientAck, ServerMessage, ServerNotification},
 };
 
 use autoconnect_settings::{AppState, Settings};
@@ -19,9 +24,12 @@ use autopush_common::{
 
 use crate::error::{SMError, SMErrorKind};
 
+mod hibernate;
 mod on_client_msg;
 mod on_server_notif;
 
+pub use hibernate::{HibernatedClient, IdentifiedClient};
+
 /// A WebPush Client that's successfully identified itself to the server via a
 /// Hello message.
 ///
@@ -50,6 +58,9 @@ pub struct WebPushClient {
     /// Count of messages sent from storage (for enforcing
     /// `settings.msg_limit`). Resets to 0 when storage is emptied
     sent_from_storage: u32,
+    /// Count of Direct messages spilled to storage this session: reading
+    /// them back is exempt from `settings.storage_session_limit`
+    spilled_direct: u32,
     /// Exists for new User records: these are not written to the db during
     /// Hello, instead they're lazily added to the db on their first Register
     /// message
@@ -97,6 +108,7 @@ impl WebPushClient {
         connected_at: u64,
         current_timestamp: Option<u64>,
         deferred_add_user: Option<User>,
+        resumed: Vec<ClientAck>,
         app_state: Arc<AppState>,
     ) -> Result<(Self, Vec<ServerMessage>), SMError> {
         trace!("👁‍🗨WebPushClient::new");
@@ -110,8 +122,12 @@ impl WebPushClient {
             ua_info: UserAgentInfo::from(ua.as_str()),
             broadcast_subs,
             flags,
-            ack_state: Default::default(),
+            ack_state: AckState {
+                resumed,
+                ..Default::default()
+            },
             sent_from_storage: Default::default(),
+            spilled_direct: Default::default(),
             connected_at,
             current_timestamp,
             deferred_add_user,
@@ -165,6 +181,11 @@ impl WebPushClient {
             .change_count_delta(&mut self.broadcast_subs)
     }
 
+    /// Subscribe to notifications of this server's Broadcasts changing
+    pub async fn broadcast_changes(&self) -> watch::Receiver<u32> {
+        self.app_state.broadcaster.read().await.subscribe_changes()
+    }
+
     /// Cleanup after the session has ended
     pub fn shutdown(&mut self, reason: Option<String>) {
         trace!("👁‍🗨WebPushClient::shutdown");
@@ -197,6 +218,7 @@ impl WebPushClient {
             "direct_storage" => stats.direct_storage,
             "stored_retrieved" => stats.stored_retrieved,
             "stored_acked" => stats.stored_acked,
+            "resumed_acked" => stats.resumed_acked,
             "nacks" => stats.nacks,
             "registers" => stats.registers,
             "unregisters" => stats.unregisters,
@@ -210,6 +232,7 @@ impl WebPushClient {
     /// so on shutdown, any not Ack'd are stored in the db to not be lost
     fn save_and_notify_unacked_direct_notifs(&mut self) {
         let mut notifs = mem::take(&mut self.ack_state.unacked_direct_notifs);
+        notifs.extend(mem::take(&mut self.ack_state.pending_direct_notifs));
         trace!(
             "👁‍🗨WebPushClient::save_and_notify_unacked_direct_notifs len: {}",
             notifs.len()
@@ -319,6 +342,9 @@ pub struct SessionStatistics {
     stored_retrieved: i32,
     /// Number of message pulled from storage and acknowledged
     stored_acked: i32,
+    /// Number of messages pulled from storage that were skipped as already
+    /// received in a resumed session
+    resumed_acked: i32,
     /// Number of messages total that are not acknowledged.
     nacks: i32,
     /// Number of unregister requests
@@ -336,6 +362,9 @@ struct AckState {
     unacked_direct_notifs: Vec<Notification>,
     /// List of unAck'd sent notifications from storage
     unacked_stored_notifs: Vec<Notification>,
+    /// Direct notifications awaiting room in the in-flight window
+    /// (`Settings::inflight_window`) to be sent
+    pending_direct_notifs: VecDeque<Notification>,
     /// Either the `current_timestamp` value in storage (returned from
     /// `fetch_messages`) or the last unAck'd timestamp Message's
     /// `sortkey_timestamp` (returned from `fetch_timestamp_messages`).
@@ -355,6 +384,14 @@ struct AckState {
     /// c) written back to `current_timestamp` in storage via
     /// `increment_storage`
     unacked_stored_highest: Option<u64>,
+    /// Delivery cursor of a resumed session: Notifications the Client
+    /// received during its previous session (which may not have been Ack'd
+    /// before it disconnected). These are treated as Ack'd when read from
+    /// storage instead of being resent
+    resumed: Vec<ClientAck>,
+    /// Number of times Notifications (by version) Nack'd by the Client have
+    /// been redelivered
+    nack_redeliveries: HashMap<String, u32>,
 }
 
 impl AckState {
@@ -363,26 +400,37 @@ impl AckState {
     fn unacked_notifs(&self) -> bool {
         !self.unacked_stored_notifs.is_empty() || !self.unacked_direct_notifs.is_empty()
     }
+
+    /// The number of notifications sent to the Client awaiting its Ack
+    fn inflight(&self) -> usize {
+        self.unacked_stored_notifs.len() + self.unacked_direct_notifs.len()
+    }
+
+    /// The remaining room in the in-flight window
+    fn inflight_available(&self, window: usize) -> usize {
+        window.saturating_sub(self.inflight())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{mem, sync::Arc, time::Duration};
 
+    use futures::StreamExt;
     use uuid::Uuid;
 
     use autoconnect_common::{
-        protocol::{ClientMessage, ServerMessage, ServerNotification},
+        protocol::{ClientAck, ClientMessage, ServerMessage, ServerNotification},
         test_support::{DUMMY_CHID, DUMMY_UAID, UA},
     };
-    use autoconnect_settings::AppState;
+    use autoconnect_settings::{AppState, Settings};
     use autopush_common::{
-        db::{client::FetchMessageResponse, mock::MockDbClient},
+        db::{client::FetchMessageResponse, error::DbError, mock::MockDbClient},
         notification::Notification,
         util::{ms_since_epoch, sec_since_epoch},
     };
 
-    use super::WebPushClient;
+    use super::{ClientFlags, HibernatedClient, IdentifiedClient, WebPushClient};
 
     async fn wpclient(uaid: Uuid, app_state: AppState) -> (WebPushClient, Vec<ServerMessage>) {
         WebPushClient::new(
@@ -393,6 +441,7 @@ mod tests {
             ms_since_epoch(),
             None,
             None,
+            vec![],
             Arc::new(app_state),
         )
         .await
@@ -414,7 +463,12 @@ mod tests {
     async fn webpush_ping() {
         let (mut client, _) = wpclient(DUMMY_UAID, Default::default()).await;
         let pong = client.on_client_msg(ClientMessage::Ping).await.unwrap();
-        assert!(matches!(pong.as_slice(), [ServerMessage::Ping]));
+        assert!(matches!(
+            pong.as_slice(),
+            [ServerMessage::Ping {
+                resume_token: Some(_)
+            }]
+        ));
     }
 
     #[actix_rt::test]
@@ -482,4 +536,590 @@ mod tests {
             .expect("CheckStorage failed");
         assert!(smsgs.is_empty())
     }
+
+    #[actix_rt::test]
+    async fn server_notif_ack() {
+        let (mut client, _) = wpclient(DUMMY_UAID, Default::default()).await;
+        let notif = Notification {
+            channel_id: DUMMY_CHID,
+            version: "foo".to_owned(),
+            ttl: 60,
+            ..Default::default()
+        };
+        let smsgs = client
+            .on_server_notif(ServerNotification::Notification(notif))
+            .await
+            .unwrap();
+        assert!(matches!(smsgs.as_slice(), [ServerMessage::Notification(_)]));
+        assert!(client.ack_state.unacked_notifs());
+
+        // An Ack forwarded via the ClientRegistry clears the unacked notif
+        let smsgs = client
+            .on_server_notif(ServerNotification::Ack(ClientAck {
+                channel_id: DUMMY_CHID,
+                version: "foo".to_owned(),
+            }))
+            .await
+            .unwrap();
+        assert!(smsgs.is_empty());
+        assert!(!client.ack_state.unacked_notifs());
+    }
+
+    #[actix_rt::test]
+    async fn resume_skips_received() {
+        let mut db = MockDbClient::new();
+        let mut seq = mockall::Sequence::new();
+        let timestamp = ms_since_epoch();
+        let received = Notification {
+            version: "received".to_owned(),
+            ..new_timestamp_notif(&DUMMY_CHID, 60)
+        };
+        let unreceived = Notification {
+            version: "unreceived".to_owned(),
+            ..new_timestamp_notif(&DUMMY_CHID, 60)
+        };
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: Some(timestamp),
+                    messages: vec![received, unreceived],
+                })
+            });
+
+        let (client, smsgs) = WebPushClient::new(
+            DUMMY_UAID,
+            UA.to_owned(),
+            Default::default(),
+            ClientFlags {
+                check_storage: true,
+                ..Default::default()
+            },
+            ms_since_epoch(),
+            None,
+            None,
+            vec![ClientAck {
+                channel_id: DUMMY_CHID,
+                version: "received".to_owned(),
+            }],
+            Arc::new(AppState {
+                db: db.into_boxed_arc(),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+        // Only the Notification not received in the previous session is resent
+        let [ServerMessage::Notification(notif)] = smsgs.as_slice() else {
+            panic!("Expected a single Notification: {smsgs:?}");
+        };
+        assert_eq!(notif.version, "unreceived");
+        assert_eq!(client.ack_state.unacked_stored_notifs.len(), 1);
+        assert!(client.ack_state.resumed.is_empty());
+        assert_eq!(client.stats.resumed_acked, 1);
+    }
+
+    /// Send a Direct `Notification` to the client, returning it
+    async fn send_direct(client: &mut WebPushClient, version: &str) -> Notification {
+        let notif = Notification {
+            channel_id: DUMMY_CHID,
+            version: version.to_owned(),
+            ttl: 60,
+            timestamp: sec_since_epoch(),
+            vapid_sub: Some("mailto:admin@example.com".to_owned()),
+            ..Default::default()
+        };
+        client
+            .on_server_notif(ServerNotification::Notification(notif.clone()))
+            .await
+            .unwrap();
+        notif
+    }
+
+    fn nack(code: i32, version: &str) -> ClientMessage {
+        ClientMessage::Nack {
+            code: Some(code),
+            version: version.to_owned(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn nack_drop() {
+        let (mut client, _) = wpclient(DUMMY_UAID, Default::default()).await;
+        send_direct(&mut client, "foo").await;
+        send_direct(&mut client, "bar").await;
+
+        // Unknown codes/versions leave the Notification unacknowledged
+        client.on_client_msg(nack(404, "foo")).await.unwrap();
+        client.on_client_msg(nack(301, "baz")).await.unwrap();
+        assert_eq!(client.ack_state.unacked_direct_notifs.len(), 2);
+
+        // Decryption failures and declined Notifications are dropped
+        client.on_client_msg(nack(301, "foo")).await.unwrap();
+        client.on_client_msg(nack(302, "bar")).await.unwrap();
+        assert!(!client.ack_state.unacked_notifs());
+        assert_eq!(client.stats.nacks, 4);
+    }
+
+    #[actix_rt::test]
+    async fn nack_redeliver() {
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                settings: Settings {
+                    nack_redelivery_delay: Duration::from_millis(1),
+                    nack_max_redeliveries: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+        let mut snotif_stream = client.registry_connect().await;
+        send_direct(&mut client, "foo").await;
+
+        let smsgs = client.on_client_msg(nack(303, "foo")).await.unwrap();
+        assert!(smsgs.is_empty());
+        assert!(!client.ack_state.unacked_notifs());
+        assert_eq!(client.ack_state.nack_redeliveries.get("foo"), Some(&1));
+
+        // Redelivered via the ClientRegistry
+        let snotif = snotif_stream.next().await.unwrap();
+        let ServerNotification::Notification(notif) = &snotif else {
+            panic!("Expected a Notification: {snotif:?}");
+        };
+        assert_eq!(notif.version, "foo");
+        let smsgs = client.on_server_notif(snotif).await.unwrap();
+        assert!(matches!(smsgs.as_slice(), [ServerMessage::Notification(_)]));
+
+        // Dropped after exhausting its redeliveries
+        client.on_client_msg(nack(303, "foo")).await.unwrap();
+        assert!(!client.ack_state.unacked_notifs());
+        assert!(client.ack_state.nack_redeliveries.is_empty());
+        client.registry_disconnect().await;
+        assert!(snotif_stream.next().await.is_none());
+    }
+
+    /// A redelivery which can't be stored is reported
+    #[actix_rt::test]
+    async fn nack_redeliver_failed() {
+        let mut db = MockDbClient::new();
+        db.expect_save_message()
+            .times(1)
+            .return_once(|_, _| Err(DbError::General("test-error".to_owned())));
+        let (metrics, sink) = cadence::SpyMetricSink::new();
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                metrics: Arc::new(cadence::StatsdClient::from_sink("autopush", sink)),
+                settings: Settings {
+                    nack_redelivery_delay: Duration::from_millis(1),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+        // Not connected to the ClientRegistry, so the redelivery's stored
+        send_direct(&mut client, "foo").await;
+        client.on_client_msg(nack(303, "foo")).await.unwrap();
+
+        for _ in 0..100 {
+            let failed = metrics.try_iter().any(|metric| {
+                let metric = String::from_utf8(metric).unwrap();
+                metric.starts_with("autopush.ua.command.nack:")
+                    && metric.contains("action:redeliver_failed")
+            });
+            if failed {
+                return;
+            }
+            actix_rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("The failed redelivery wasn't reported");
+    }
+
+    fn ack(versions: &[&str]) -> ClientMessage {
+        ClientMessage::Ack {
+            updates: versions
+                .iter()
+                .map(|version| ClientAck {
+                    channel_id: DUMMY_CHID,
+                    version: version.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn inflight_window() {
+        let mut db = MockDbClient::new();
+        let timestamp = ms_since_epoch();
+        db.expect_save_message()
+            .times(1)
+            .withf(|_, notif| notif.version == "5" && notif.sortkey_timestamp == Some(0))
+            .return_once(|_, _| Ok(()));
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .withf(|_, limit| limit == &2)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .withf(|_, _, limit| limit == &2)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: Some(timestamp),
+                    messages: vec![Notification {
+                        version: "5".to_owned(),
+                        ..new_timestamp_notif(&DUMMY_CHID, 60)
+                    }],
+                })
+            });
+        let (mut client, _) = wpclient(
+            DUMMY_UAID,
+            AppState {
+                db: db.into_boxed_arc(),
+                settings: Settings {
+                    inflight_window: 2,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let mut sent = vec![];
+        for version in ["1", "2", "3", "4", "5"] {
+            let notif = Notification {
+                channel_id: DUMMY_CHID,
+                version: version.to_owned(),
+                ttl: 60,
+                timestamp: sec_since_epoch(),
+                ..Default::default()
+            };
+            sent.extend(
+                client
+                    .on_server_notif(ServerNotification::Notification(notif))
+                    .await
+                    .unwrap(),
+            );
+        }
+        // Only a window's worth sent, another queued and the rest stored
+        assert_eq!(sent.len(), 2);
+        assert_eq!(client.ack_state.pending_direct_notifs.len(), 2);
+        assert!(client.flags.check_storage);
+
+        let version = |smsgs: &[ServerMessage]| match smsgs {
+            [ServerMessage::Notification(notif)] => notif.version.clone(),
+            _ => panic!("Expected a single Notification: {smsgs:?}"),
+        };
+        // Each Ack makes room for a queued notification
+        let smsgs = client.on_client_msg(ack(&["1"])).await.unwrap();
+        assert_eq!(version(&smsgs), "3");
+        let smsgs = client.on_client_msg(ack(&["2"])).await.unwrap();
+        assert_eq!(version(&smsgs), "4");
+        // Then the stored notification's read back once all are Ack'd
+        let smsgs = client.on_client_msg(ack(&["3", "4"])).await.unwrap();
+        assert_eq!(version(&smsgs), "5");
+    }
+
+    /// A `WebPushClient` that's read storage on connect
+    async fn wpclient_check_storage(
+        db: MockDbClient,
+        settings: Settings,
+    ) -> (WebPushClient, Vec<ServerMessage>) {
+        WebPushClient::new(
+            DUMMY_UAID,
+            UA.to_owned(),
+            Default::default(),
+            ClientFlags {
+                check_storage: true,
+                ..Default::default()
+            },
+            ms_since_epoch(),
+            None,
+            None,
+            vec![],
+            Arc::new(AppState {
+                db: db.into_boxed_arc(),
+                settings,
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap()
+    }
+
+    fn versions(smsgs: &[ServerMessage]) -> Vec<&str> {
+        smsgs
+            .iter()
+            .map(|smsg| match smsg {
+                ServerMessage::Notification(notif) => notif.version.as_str(),
+                _ => panic!("Expected a Notification: {smsg:?}"),
+            })
+            .collect()
+    }
+
+    /// Generate a dummy timestamp `Notification` received at `timestamp`
+    fn timestamp_notif(version: &str, timestamp: u64) -> Notification {
+        Notification {
+            version: version.to_owned(),
+            timestamp,
+            sortkey_timestamp: Some(timestamp * 1000),
+            ..new_timestamp_notif(&DUMMY_CHID, 60)
+        }
+    }
+
+    /// Generate a dummy topic `Notification` received at `timestamp`
+    fn topic_notif(version: &str, timestamp: u64) -> Notification {
+        Notification {
+            version: version.to_owned(),
+            topic: Some(version.to_owned()),
+            timestamp,
+            sortkey_timestamp: None,
+            ..new_timestamp_notif(&DUMMY_CHID, 60)
+        }
+    }
+
+    #[actix_rt::test]
+    async fn storage_page_size() {
+        let mut db = MockDbClient::new();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .withf(|_, limit| limit == &4)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .withf(|_, _, limit| limit == &3)
+            .return_once(|_, _, _| Ok(Default::default()));
+        let (_, smsgs) = wpclient_check_storage(
+            db,
+            Settings {
+                storage_page_size: 3,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(smsgs.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn storage_session_limit() {
+        let now = sec_since_epoch();
+        let mut db = MockDbClient::new();
+        let mut seq = mockall::Sequence::new();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _| Ok(Default::default()));
+        // Only the remainder of the session limit's read
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .withf(|_, _, limit| limit == &2)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: Some(now * 1000 + 1000),
+                    messages: vec![timestamp_notif("1", now), timestamp_notif("2", now + 1)],
+                })
+            });
+        // Then no further reads: only advancing past the Ack'd messages
+        db.expect_increment_storage()
+            .times(1)
+            .in_sequence(&mut seq)
+            .withf(move |_, ts| ts == &(now * 1000 + 1000))
+            .return_once(|_, _| Ok(()));
+        let (mut client, smsgs) = wpclient_check_storage(
+            db,
+            Settings {
+                storage_session_limit: 2,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(versions(&smsgs), ["1", "2"]);
+
+        let smsgs = client
+            .on_client_msg(ClientMessage::Ack {
+                updates: ["1", "2"]
+                    .into_iter()
+                    .map(|version| ClientAck {
+                        channel_id: DUMMY_CHID,
+                        version: version.to_owned(),
+                    })
+                    .collect(),
+            })
+            .await
+            .unwrap();
+        assert!(smsgs.is_empty());
+        assert!(!client.flags.check_storage);
+    }
+
+    #[actix_rt::test]
+    async fn storage_session_limit_spilled() {
+        let now = sec_since_epoch();
+        let mut db = MockDbClient::new();
+        let mut seq = mockall::Sequence::new();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _| Ok(Default::default()));
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: Some(now * 1000),
+                    messages: vec![timestamp_notif("1", now)],
+                })
+            });
+        db.expect_save_message()
+            .times(1)
+            .in_sequence(&mut seq)
+            .withf(|_, notif| notif.version == "spilled")
+            .return_once(|_, _| Ok(()));
+        db.expect_increment_storage()
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_once(|_, _| Ok(()));
+        // The spilled message's read back despite the session limit
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .in_sequence(&mut seq)
+            .withf(|_, _, limit| limit == &1)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: Some(now * 1000 + 1000),
+                    messages: vec![timestamp_notif("spilled", now + 1)],
+                })
+            });
+        let (mut client, smsgs) = wpclient_check_storage(
+            db,
+            Settings {
+                inflight_window: 1,
+                storage_session_limit: 1,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(versions(&smsgs), ["1"]);
+
+        // Queued, then spilled once the queue's also full
+        for version in ["queued", "spilled"] {
+            let smsgs = client
+                .on_server_notif(ServerNotification::Notification(Notification {
+                    channel_id: DUMMY_CHID,
+                    version: version.to_owned(),
+                    ttl: 60,
+                    timestamp: now,
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+            assert!(smsgs.is_empty());
+        }
+
+        let smsgs = client.on_client_msg(ack(&["1"])).await.unwrap();
+        assert_eq!(versions(&smsgs), ["queued"]);
+        let smsgs = client.on_client_msg(ack(&["queued"])).await.unwrap();
+        assert_eq!(versions(&smsgs), ["spilled"]);
+    }
+
+    #[actix_rt::test]
+    async fn storage_topic_first() {
+        let now = sec_since_epoch();
+        let mut db = MockDbClient::new();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .return_once(move |_, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: None,
+                    messages: vec![topic_notif("topic", now)],
+                })
+            });
+        let (_, smsgs) = wpclient_check_storage(db, Default::default()).await;
+        // Topic messages are sent regardless of older timestamp messages
+        assert_eq!(versions(&smsgs), ["topic"]);
+    }
+
+    #[actix_rt::test]
+    async fn storage_interleave() {
+        let now = sec_since_epoch();
+        let mut db = MockDbClient::new();
+        db.expect_fetch_topic_messages()
+            .times(1)
+            .withf(|_, limit| limit == &150)
+            .return_once(move |_, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: None,
+                    messages: vec![
+                        topic_notif("topic2", now - 5),
+                        topic_notif("topic1", now - 20),
+                    ],
+                })
+            });
+        db.expect_fetch_timestamp_messages()
+            .times(1)
+            .withf(|_, ts, limit| ts.is_none() && limit == &3)
+            .return_once(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: None,
+                    messages: vec![
+                        timestamp_notif("ts1", now - 30),
+                        timestamp_notif("ts2", now - 10),
+                        timestamp_notif("ts3", now - 1),
+                    ],
+                })
+            });
+        let (client, smsgs) = wpclient_check_storage(
+            db,
+            Settings {
+                storage_page_size: 3,
+                storage_interleave: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        // Oldest first, limited to the page size
+        assert_eq!(versions(&smsgs), ["ts1", "topic1", "ts2"]);
+        // The timestamp "pointer" only advances past those sent
+        assert_eq!(
+            client.ack_state.unacked_stored_highest,
+            Some((now - 10) * 1000)
+        );
+        assert!(client.flags.increment_storage);
+    }
+
+    #[actix_rt::test]
+    async fn hibernate() {
+        let (client, _) = wpclient(DUMMY_UAID, Default::default()).await;
+        let uid = client.uid;
+        let mut client = IdentifiedClient::from(client);
+        send_direct(client.wake(), "foo").await;
+        // Not while awaiting an Ack
+        assert!(!client.hibernate());
+
+        let active = client.wake();
+        active.on_client_msg(ack(&["foo"])).await.unwrap();
+        active
+            .ack_state
+            .nack_redeliveries
+            .insert("bar".to_owned(), 1);
+        assert!(client.hibernate());
+        assert!(client.is_hibernating());
+        assert!(mem::size_of::<HibernatedClient>() < mem::size_of::<WebPushClient>());
+
+        let woken = client.wake();
+        assert_eq!(woken.uaid, DUMMY_UAID);
+        assert_eq!(woken.uid, uid);
+        assert_eq!(woken.ua_info.metrics_browser, "Firefox");
+        // Pending redeliveries are still counted
+        assert_eq!(woken.ack_state.nack_redeliveries.get("bar"), Some(&1));
+        assert!(!client.is_hibernating());
+    }
 }
@@ -1,16 +1,32 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, panic::AssertUnwindSafe, sync::Arc, time::Duration};
 
-use cadence::CountedExt;
+use actix_web::rt;
+use cadence::{CountedExt, StatsdClient};
+use futures::FutureExt;
 use uuid::Uuid;
 
 use autoconnect_common::{
     broadcast::Broadcast,
     protocol::{BroadcastValue, ClientAck, ClientMessage, ServerMessage},
 };
-use autopush_common::{endpoint::make_endpoint, util::sec_since_epoch};
+use autopush_common::{endpoint::make_endpoint, notification::Notification, util::sec_since_epoch};
 
 use super::WebPushClient;
-use crate::error::{SMError, SMErrorKind};
+use crate::{
+    error::{SMError, SMErrorKind},
+    resume::ResumeToken,
+};
+
+/// Nack code: the Client failed to decrypt the Notification. Dropped
+const NACK_DECRYPTION_FAILURE: i32 = 301;
+/// Nack code: the Notification wasn't delivered to the application (e.g. the
+/// user declined it). Dropped
+const NACK_NOT_DELIVERED: i32 = 302;
+/// Nack code: any other (potentially transient) Client error. Redelivered
+/// (up to `Settings::nack_max_redeliveries` times) with backoff
+const NACK_OTHER_ERROR: i32 = 303;
+/// Upper bound of the Nack redelivery backoff
+const NACK_MAX_REDELIVERY_DELAY: Duration = Duration::from_secs(60 * 60);
 
 impl WebPushClient {
     /// Handle a WebPush `ClientMessage` sent from the user agent over the
@@ -34,10 +50,7 @@ impl WebPushClient {
                 .await?
                 .map_or_else(Vec::new, |smsg| vec![smsg])),
             ClientMessage::Ack { updates } => self.ack(&updates).await,
-            ClientMessage::Nack { code, .. } => {
-                self.nack(code);
-                Ok(vec![])
-            }
+            ClientMessage::Nack { code, version } => self.nack(code, &version).await,
             ClientMessage::Ping => Ok(vec![self.ping()?]),
         }
     }
@@ -177,7 +190,10 @@ impl WebPushClient {
     }
 
     /// Acknowledge receipt of one or more Push Notifications
-    async fn ack(&mut self, updates: &[ClientAck]) -> Result<Vec<ServerMessage>, SMError> {
+    pub(super) async fn ack(
+        &mut self,
+        updates: &[ClientAck],
+    ) -> Result<Vec<ServerMessage>, SMError> {
         trace!("✅ WebPushClient:ack");
         let _ = self.app_state.metrics.incr("ua.command.ack");
 
@@ -197,6 +213,7 @@ impl WebPushClient {
                        "version" => &notif.version
                 );
                 self.ack_state.unacked_direct_notifs.remove(pos);
+                self.ack_state.nack_redeliveries.remove(&notif.version);
                 self.stats.direct_acked += 1;
                 continue;
             };
@@ -233,40 +250,170 @@ impl WebPushClient {
                         .await?;
                 }
                 self.ack_state.unacked_stored_notifs.remove(pos);
+                self.ack_state.nack_redeliveries.remove(&notif.version);
                 self.stats.stored_acked += 1;
                 continue;
             };
         }
 
-        if self.ack_state.unacked_notifs() {
-            // Wait for the Client to Ack all notifications before further
-            // processing
-            Ok(vec![])
-        } else {
-            self.post_process_all_acked().await
+        // Room in the in-flight window may have freed up for queued Direct
+        // notifications
+        let mut smsgs = self.release_pending_direct();
+        // Otherwise wait for the Client to Ack all notifications before
+        // further processing
+        if !self.ack_state.unacked_notifs() {
+            smsgs.extend(self.post_process_all_acked().await?);
         }
+        Ok(smsgs)
     }
 
-    /// Negative Acknowledgement (a Client error occurred) of one or more Push
-    /// Notifications
-    fn nack(&mut self, code: Option<i32>) {
+    /// Negative Acknowledgement (a Client error occurred) of a Push
+    /// Notification
+    ///
+    /// The Nack's code determines whether the Notification's dropped,
+    /// redelivered later or (for unknown codes) left unacknowledged. Either
+    /// way the error's reported via metrics tagged with the sender's VAPID
+    /// `sub`
+    async fn nack(
+        &mut self,
+        code: Option<i32>,
+        version: &str,
+    ) -> Result<Vec<ServerMessage>, SMError> {
         trace!("WebPushClient:nack");
         // only metric codes expected from the client (or 0)
         let code = code
             .and_then(|code| (301..=303).contains(&code).then_some(code))
             .unwrap_or(0);
-        self.app_state
-            .metrics
-            .incr_with_tags("ua.command.nack")
-            .with_tag("code", &code.to_string())
-            .send();
         self.stats.nacks += 1;
+        let Some((notif, stored)) = (code != 0).then(|| self.take_unacked(version)).flatten()
+        else {
+            // Unknown code or an unknown (or already Ack'd) version
+            self.emit_nack_metrics(code, None, "ignore");
+            return Ok(vec![]);
+        };
+
+        let settings = &self.app_state.settings;
+        let redeliveries = self
+            .ack_state
+            .nack_redeliveries
+            .get(&notif.version)
+            .copied()
+            .unwrap_or_default();
+        let redelivery_delay = match code {
+            NACK_DECRYPTION_FAILURE | NACK_NOT_DELIVERED => None,
+            NACK_OTHER_ERROR if redeliveries < settings.nack_max_redeliveries => Some(
+                settings
+                    .nack_redelivery_delay
+                    .saturating_mul(2u32.saturating_pow(redeliveries))
+                    .min(NACK_MAX_REDELIVERY_DELAY),
+            ),
+            // Exhausted its redeliveries
+            _ => None,
+        };
+        if redelivery_delay.is_some() {
+            self.ack_state
+                .nack_redeliveries
+                .insert(notif.version.clone(), redeliveries + 1);
+        } else {
+            self.ack_state.nack_redeliveries.remove(&notif.version);
+        }
+        self.emit_nack_metrics(
+            code,
+            notif.vapid_sub.as_deref(),
+            if redelivery_delay.is_some() {
+                "redeliver"
+            } else {
+                "drop"
+            },
+        );
+
+        // Stored Topic messages are removed from storage in either case:
+        // redelivered messages are held in memory (like Direct messages)
+        if stored && notif.sortkey_timestamp.is_none() {
+            self.app_state
+                .db
+                .remove_message(&self.uaid, &notif.chidmessageid())
+                .await?;
+        }
+        if let Some(delay) = redelivery_delay {
+            self.schedule_redelivery(notif, code, delay);
+        }
+
+        let mut smsgs = self.release_pending_direct();
+        if !self.ack_state.unacked_notifs() {
+            smsgs.extend(self.post_process_all_acked().await?);
+        }
+        Ok(smsgs)
+    }
+
+    /// Remove a Nack'd Notification from the unacked lists, returning it
+    /// along with whether it was read from storage
+    fn take_unacked(&mut self, version: &str) -> Option<(Notification, bool)> {
+        let ack_state = &mut self.ack_state;
+        if let Some(pos) = ack_state
+            .unacked_direct_notifs
+            .iter()
+            .position(|n| n.version == version)
+        {
+            return Some((ack_state.unacked_direct_notifs.remove(pos), false));
+        }
+        let pos = ack_state
+            .unacked_stored_notifs
+            .iter()
+            .position(|n| n.version == version)?;
+        Some((ack_state.unacked_stored_notifs.remove(pos), true))
+    }
+
+    /// Redeliver a Nack'd Notification (as a Direct Notification) after
+    /// `delay`
+    ///
+    /// The Notification's stored instead if the Client has since disconnected.
+    /// Pending redeliveries are only held in memory by this node: they're lost
+    /// if it restarts beforehand, or if storing them fails (reported with the
+    /// `redeliver_failed` action)
+    fn schedule_redelivery(&self, mut notif: Notification, code: i32, delay: Duration) {
+        debug!("WebPushClient:nack redelivering in {:?}", delay;
+               "channel_id" => notif.channel_id.as_hyphenated().to_string(),
+               "version" => &notif.version);
+        let app_state = Arc::clone(&self.app_state);
+        let uaid = self.uaid;
+        let vapid_sub = notif.vapid_sub.clone();
+        let redeliver = async move {
+            rt::time::sleep(delay).await;
+            if app_state.clients.notify(uaid, notif.clone()).await.is_ok() {
+                return Ok(());
+            }
+            if notif.expired(sec_since_epoch()) {
+                return Ok(());
+            }
+            // See `save_and_notify_unacked_direct_notifs`
+            notif.sortkey_timestamp = Some(0);
+            app_state.db.save_message(&uaid, notif).await
+        };
+        let metrics = Arc::clone(&self.app_state.metrics);
+        rt::spawn(async move {
+            let failure = match AssertUnwindSafe(redeliver).catch_unwind().await {
+                Ok(Ok(())) => return,
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => "panicked".to_owned(),
+            };
+            warn!("WebPushClient:nack redelivery failed: {}", failure;
+                  "uaid" => uaid.as_simple().to_string());
+            emit_nack_metrics(&metrics, code, vapid_sub.as_deref(), "redeliver_failed");
+        });
+    }
+
+    fn emit_nack_metrics(&self, code: i32, vapid_sub: Option<&str>, action: &str) {
+        emit_nack_metrics(&self.app_state.metrics, code, vapid_sub, action);
     }
 
     /// Handle a WebPush Ping
     ///
     /// Note this is the WebPush Protocol level's Ping: this differs from the
     /// lower level WebSocket Ping frame (handled by the `webpush_ws` handler).
+    ///
+    /// The reply refreshes the Client's session resume token, so a long lived
+    /// session remains resumable.
     fn ping(&mut self) -> Result<ServerMessage, SMError> {
         trace!("WebPushClient:ping");
         // TODO: why is this 45 vs the comment describing a minute? and 45
@@ -275,7 +422,9 @@ impl WebPushClient {
         if sec_since_epoch() - self.last_ping >= 45 {
             trace!("🏓WebPushClient Got a WebPush Ping, sending WebPush Pong");
             self.last_ping = sec_since_epoch();
-            Ok(ServerMessage::Ping)
+            Ok(ServerMessage::Ping {
+                resume_token: ResumeToken::issue(self.uaid, &self.app_state),
+            })
         } else {
             Err(SMErrorKind::ExcessivePing.into())
         }
@@ -330,3 +479,12 @@ impl WebPushClient {
         }
     }
 }
+
+fn emit_nack_metrics(metrics: &StatsdClient, code: i32, vapid_sub: Option<&str>, action: &str) {
+    metrics
+        .incr_with_tags("ua.command.nack")
+        .with_tag("code", &code.to_string())
+        .with_tag("action", action)
+        .with_tag("sub", vapid_sub.unwrap_or("none"))
+        .send();
+}
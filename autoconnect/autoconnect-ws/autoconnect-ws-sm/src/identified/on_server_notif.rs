@@ -15,14 +15,18 @@ impl WebPushClient {
     /// node recieving it when a User has logged into that same node twice to
     /// "Ghost" (disconnect) the first user's session for its second session.
     ///
+    /// `ServerNotification::Ack` is emitted by the RFC 8030 message
+    /// acknowledgement endpoint.
+    ///
     /// Other variants are emitted by autoendpoint
     pub async fn on_server_notif(
         &mut self,
         snotif: ServerNotification,
     ) -> Result<Vec<ServerMessage>, SMError> {
         match snotif {
-            ServerNotification::Notification(notif) => Ok(vec![self.notif(notif)?]),
+            ServerNotification::Notification(notif) => self.notif(notif).await,
             ServerNotification::CheckStorage => self.check_storage().await,
+            ServerNotification::Ack(ack) => self.ack(&[ack]).await,
             ServerNotification::Disconnect => Err(SMErrorKind::Ghost.into()),
         }
     }
@@ -38,13 +42,63 @@ impl WebPushClient {
     }
 
     /// Send a Direct Push Notification to this user
-    fn notif(&mut self, notif: Notification) -> Result<ServerMessage, SMError> {
-        trace!("WebPushClient::notif Sending a direct notif");
+    ///
+    /// Queued instead when the Client's in-flight window is full, or stored
+    /// when the queue is also full
+    async fn notif(&mut self, notif: Notification) -> Result<Vec<ServerMessage>, SMError> {
+        let window = self.app_state.settings.inflight_window;
+        if self.ack_state.inflight() < window {
+            return Ok(vec![self.send_direct(notif)]);
+        }
+        if self.ack_state.pending_direct_notifs.len() < window {
+            trace!("WebPushClient::notif In-flight window full, queueing a direct notif");
+            let _ = self.app_state.metrics.incr("ua.notification.queued");
+            self.ack_state.pending_direct_notifs.push_back(notif);
+            return Ok(vec![]);
+        }
+        debug!("WebPushClient::notif In-flight window and queue full, storing a direct notif");
+        let _ = self.app_state.metrics.incr("ua.notification.spilled");
+        let mut notif = notif;
+        // See `save_and_notify_unacked_direct_notifs`
+        notif.sortkey_timestamp = Some(0);
+        self.app_state.db.save_message(&self.uaid, notif).await?;
+        self.stats.direct_storage += 1;
+        self.spilled_direct += 1;
+        if !self.flags.check_storage {
+            // Read it back once the Client's Ack'd everything in flight
+            self.flags.check_storage = true;
+            self.flags.include_topic = true;
+        }
+        Ok(vec![])
+    }
+
+    fn send_direct(&mut self, notif: Notification) -> ServerMessage {
+        trace!("WebPushClient::send_direct Sending a direct notif");
         if notif.ttl != 0 {
             self.ack_state.unacked_direct_notifs.push(notif.clone());
         }
         self.emit_send_metrics(&notif, "Direct");
-        Ok(ServerMessage::Notification(notif))
+        ServerMessage::Notification(notif)
+    }
+
+    /// Send queued Direct Push Notifications that now fit in the Client's
+    /// in-flight window (after it's Ack'd or Nack'd some)
+    ///
+    /// Queued Notifications that expired while waiting are dropped
+    pub(super) fn release_pending_direct(&mut self) -> Vec<ServerMessage> {
+        let window = self.app_state.settings.inflight_window;
+        let now_sec = sec_since_epoch();
+        let mut smsgs = vec![];
+        while self.ack_state.inflight() < window {
+            let Some(notif) = self.ack_state.pending_direct_notifs.pop_front() else {
+                break;
+            };
+            if notif.expired(now_sec) {
+                continue;
+            }
+            smsgs.push(self.send_direct(notif));
+        }
+        smsgs
     }
 
     /// Top level read of Push Notifications from storage
@@ -65,6 +119,22 @@ impl WebPushClient {
     pub(super) async fn check_storage_loop(&mut self) -> Result<Vec<ServerMessage>, SMError> {
         trace!("🗄️ WebPushClient::check_storage_loop");
         while self.flags.check_storage {
+            if self.session_limit_reached() {
+                // Leave the remainder in storage for the next session
+                debug!("🗄️ WebPushClient::check_storage_loop session limit reached");
+                let _ = self.app_state.metrics.incr("ua.notification.session_limit");
+                self.flags.check_storage = false;
+                break;
+            }
+            if self
+                .ack_state
+                .inflight_available(self.app_state.settings.inflight_window)
+                == 0
+            {
+                // Resumed once the Client's Ack'd everything in flight
+                trace!("🗄️ WebPushClient::check_storage_loop in-flight window full");
+                return Ok(vec![]);
+            }
             let smsgs = self.check_storage_advance().await?;
             if !smsgs.is_empty() {
                 self.check_msg_limit().await?;
@@ -115,27 +185,60 @@ impl WebPushClient {
 
         // Filter out TTL expired messages
         let now_sec = sec_since_epoch();
-        // Topic messages require immediate deletion from the db
-        let mut expired_topic_sort_keys = vec![];
+        // Removed Topic messages require immediate deletion from the db
+        let mut removed_topic_sort_keys = vec![];
         messages.retain(|msg| {
             if !msg.expired(now_sec) {
                 return true;
             }
             if msg.sortkey_timestamp.is_none() {
-                expired_topic_sort_keys.push(msg.chidmessageid());
+                removed_topic_sort_keys.push(msg.chidmessageid());
             }
             false
         });
+        // Skip messages already received in a resumed session: treat them as
+        // Ack'd, so Topic messages are also deleted
+        if !self.ack_state.resumed.is_empty() {
+            let resumed = &mut self.ack_state.resumed;
+            let before = messages.len();
+            messages.retain(|msg| {
+                let Some(pos) = resumed
+                    .iter()
+                    .position(|ack| ack.channel_id == msg.channel_id && ack.version == msg.version)
+                else {
+                    return true;
+                };
+                resumed.swap_remove(pos);
+                if msg.sortkey_timestamp.is_none() {
+                    removed_topic_sort_keys.push(msg.chidmessageid());
+                }
+                false
+            });
+            let skipped = before - messages.len();
+            if skipped > 0 {
+                debug!(
+                    "🗄️ WebPushClient::check_storage_advance skipped {} resumed",
+                    skipped
+                );
+                self.stats.resumed_acked += skipped as i32;
+                let _ = self
+                    .app_state
+                    .metrics
+                    .count("ua.notification.resumed", skipped as i64);
+            }
+        }
         // TODO: A batch remove_messages would be nicer
-        for sort_key in expired_topic_sort_keys {
-            trace!("🉑 removing expired topic sort key: {sort_key}");
+        for sort_key in removed_topic_sort_keys {
+            trace!("🉑 removing topic sort key: {sort_key}");
             self.app_state
                 .db
                 .remove_message(&self.uaid, &sort_key)
                 .await?;
         }
 
-        self.flags.increment_storage = !include_topic && timestamp.is_some();
+        // Interleaved reads may include both Topic and timestamp messages
+        self.flags.increment_storage =
+            (!include_topic || self.app_state.settings.storage_interleave) && timestamp.is_some();
 
         if messages.is_empty() {
             trace!("🗄️ WebPushClient::check_storage_advance empty response (filtered expired)");
@@ -160,13 +263,16 @@ impl WebPushClient {
             self.sent_from_storage, count
         );
         self.sent_from_storage += count;
+        self.stats.stored_retrieved += count as i32;
         Ok(smsgs)
     }
 
-    /// Read a chunk (max count 10 returned) of Notifications from storage
+    /// Read a chunk (max count `Settings::storage_page_size` returned, fewer
+    /// when limited by `storage_read_limit`) of Notifications from storage
     ///
     /// This alternates between reading Topic Notifications and Timestamp
-    /// Notifications which are stored separately in storage.
+    /// Notifications which are stored separately in storage (or interleaves
+    /// them when `Settings::storage_interleave` is set).
     ///
     /// Topic Messages differ in that they replace pending Notifications with
     /// new ones if they have matching Topic names. They are used when a sender
@@ -180,15 +286,19 @@ impl WebPushClient {
             .unacked_stored_highest
             .or(self.current_timestamp);
         trace!("🗄️ WebPushClient::do_check_storage {:?}", &timestamp);
+        let page_size = self.app_state.settings.storage_page_size;
+        if self.app_state.settings.storage_interleave {
+            return self.do_check_storage_interleaved(timestamp).await;
+        }
         // if we're to include topic messages, do those first.
         // NOTE: Bigtable can't fetch `current_timestamp`, so we can't rely on
         // `fetch_topic_messages()` returning a reasonable timestamp.
         let topic_resp = if self.flags.include_topic {
             trace!("🗄️ WebPushClient::do_check_storage: fetch_topic_messages");
-            // Get the most recent max 11 messages.
+            // Get the most recent max page size + 1 messages.
             self.app_state
                 .db
-                .fetch_topic_messages(&self.uaid, 11)
+                .fetch_topic_messages(&self.uaid, self.storage_read_limit(page_size + 1))
                 .await?
         } else {
             Default::default()
@@ -199,14 +309,7 @@ impl WebPushClient {
                 "🗄️ WebPushClient::do_check_storage: Topic message returns: {:#?}",
                 topic_resp.messages
             );
-            self.app_state
-                .metrics
-                .count_with_tags(
-                    "notification.message.retrieved",
-                    topic_resp.messages.len() as i64,
-                )
-                .with_tag("topic", "true")
-                .send();
+            self.emit_retrieved_metrics(topic_resp.messages.len(), true);
             return Ok(CheckStorageResponse {
                 include_topic: true,
                 messages: topic_resp.messages,
@@ -229,21 +332,14 @@ impl WebPushClient {
         let timestamp_resp = self
             .app_state
             .db
-            .fetch_timestamp_messages(&self.uaid, timestamp, 10)
+            .fetch_timestamp_messages(&self.uaid, timestamp, self.storage_read_limit(page_size))
             .await?;
         if !timestamp_resp.messages.is_empty() {
             trace!(
                 "🗄️ WebPushClient::do_check_storage: Timestamp message returns: {:#?}",
                 timestamp_resp.messages
             );
-            self.app_state
-                .metrics
-                .count_with_tags(
-                    "notification.message.retrieved",
-                    timestamp_resp.messages.len() as i64,
-                )
-                .with_tag("topic", "false")
-                .send();
+            self.emit_retrieved_metrics(timestamp_resp.messages.len(), false);
         }
 
         Ok(CheckStorageResponse {
@@ -255,6 +351,113 @@ impl WebPushClient {
         })
     }
 
+    /// Read a chunk of Notifications from storage, interleaving Topic and
+    /// timestamp Notifications by age (oldest first)
+    ///
+    /// Both kinds are read on every call, so the response's `include_topic`
+    /// only reflects whether any Topic Notifications were included. All of
+    /// the user's Topic Notifications are read (up to `Settings::msg_limit`)
+    /// so they're ordered by age across pages, not only within one.
+    async fn do_check_storage_interleaved(
+        &self,
+        timestamp: Option<u64>,
+    ) -> Result<CheckStorageResponse, SMError> {
+        let page_size = self.app_state.settings.storage_page_size;
+        let limit = self.storage_read_limit(page_size);
+        trace!(
+            "🗄️ WebPushClient::do_check_storage_interleaved limit: {}",
+            limit
+        );
+        let mut topic_messages = self
+            .app_state
+            .db
+            .fetch_topic_messages(&self.uaid, self.app_state.settings.msg_limit as usize)
+            .await?
+            .messages;
+        let timestamp_messages = self
+            .app_state
+            .db
+            .fetch_timestamp_messages(&self.uaid, timestamp, limit)
+            .await?
+            .messages;
+        if !topic_messages.is_empty() {
+            self.emit_retrieved_metrics(topic_messages.len(), true);
+        }
+        if !timestamp_messages.is_empty() {
+            self.emit_retrieved_metrics(timestamp_messages.len(), false);
+        }
+
+        // Merge the two, preserving the order of the timestamp messages so
+        // those included are always a prefix of the ones read (the timestamp
+        // "pointer" can't skip over any)
+        topic_messages.sort_by_key(|msg| msg.timestamp);
+        let mut topic_messages = topic_messages.into_iter().peekable();
+        let mut timestamp_messages = timestamp_messages.into_iter().peekable();
+        let mut messages = Vec::with_capacity(limit);
+        let mut include_topic = false;
+        let mut highest = None;
+        while messages.len() < limit {
+            let next_topic = match (topic_messages.peek(), timestamp_messages.peek()) {
+                (Some(topic), Some(timestamped)) => topic.timestamp <= timestamped.timestamp,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if next_topic {
+                messages.extend(topic_messages.next());
+                include_topic = true;
+            } else if let Some(msg) = timestamp_messages.next() {
+                highest = msg.sortkey_timestamp.or(highest);
+                messages.push(msg);
+            }
+        }
+        Ok(CheckStorageResponse {
+            include_topic,
+            messages,
+            timestamp: highest.or(timestamp),
+        })
+    }
+
+    /// The maximum number of Notifications the next read from storage may
+    /// return: at most `page_size`, limited by the room in the Client's
+    /// in-flight window and what remains of `Settings::storage_session_limit`
+    fn storage_read_limit(&self, page_size: usize) -> usize {
+        let settings = &self.app_state.settings;
+        let mut limit = page_size.min(self.ack_state.inflight_available(settings.inflight_window));
+        if let Some(remaining) = self.session_limit_remaining() {
+            limit = limit.min(remaining as usize);
+        }
+        limit
+    }
+
+    /// The number of stored Notifications this session may still send under
+    /// `Settings::storage_session_limit` (`None` for no limit)
+    ///
+    /// Direct Notifications spilled to storage during this session extend the
+    /// limit, as they're only delivered by reading them back
+    fn session_limit_remaining(&self) -> Option<u32> {
+        let limit = self.app_state.settings.storage_session_limit;
+        (limit > 0).then(|| {
+            limit
+                .saturating_add(self.spilled_direct)
+                .saturating_sub(self.stats.stored_retrieved as u32)
+        })
+    }
+
+    /// Whether this session's sent `Settings::storage_session_limit` stored
+    /// Notifications
+    fn session_limit_reached(&self) -> bool {
+        self.session_limit_remaining() == Some(0)
+    }
+
+    fn emit_retrieved_metrics(&self, count: usize, topic: bool) {
+        self.app_state
+            .metrics
+            .count_with_tags("notification.message.retrieved", count as i64)
+            .with_tag("topic", &topic.to_string())
+            .send();
+    }
+
     /// Update the user's last Message read timestamp (for timestamp Messages)
     ///
     /// Called when a Client's Ack'd all timestamp messages sent to it to move
@@ -3,10 +3,11 @@ extern crate slog_scope;
 
 mod error;
 mod identified;
+mod resume;
 mod unidentified;
 
-pub use error::SMError;
-pub use identified::WebPushClient;
+pub use error::{SMError, SMErrorKind};
+pub use identified::{HibernatedClient, IdentifiedClient, WebPushClient};
 pub use unidentified::UnidentifiedClient;
 
 #[cfg(debug_assertions)]
@@ -0,0 +1,95 @@
+//! Session resume tokens
+//!
+//! A resume token is issued in the Hello response and refreshed with each
+//! Ping reply. A Client reconnecting shortly after losing its connection (to
+//! any node sharing the same `crypto_key`) presents its latest token in its
+//! next Hello along with the Notifications it had received during the previous
+//! session. Those Notifications are skipped when read back from storage instead
+//! of being resent as duplicates.
+use std::time::Duration;
+
+use fernet::{Fernet, MultiFernet};
+use uuid::Uuid;
+
+use autoconnect_settings::AppState;
+
+/// Identifies a previous session of a given uaid
+///
+/// The token's age is the timestamp of its Fernet token
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct ResumeToken {
+    pub uaid: Uuid,
+}
+
+impl ResumeToken {
+    pub fn new(uaid: Uuid) -> Self {
+        Self { uaid }
+    }
+
+    /// Issue a new token for `uaid` (when session resumption is enabled)
+    pub fn issue(uaid: Uuid, app_state: &AppState) -> Option<String> {
+        (!app_state.settings.resume_token_ttl.is_zero())
+            .then(|| Self::new(uaid).encrypt(&app_state.fernet))
+    }
+
+    pub fn encrypt(&self, fernet: &MultiFernet) -> String {
+        fernet.encrypt(self.uaid.as_simple().to_string().as_bytes())
+    }
+
+    /// Decrypt a token, returning `None` for invalid tokens or those issued
+    /// longer than `ttl` ago
+    pub fn decrypt(token: &str, fernet_keys: &[Fernet], ttl: Duration) -> Option<Self> {
+        let plaintext = fernet_keys
+            .iter()
+            .find_map(|fernet| fernet.decrypt_with_ttl(token, ttl.as_secs()).ok())?;
+        let uaid = Uuid::try_parse(std::str::from_utf8(&plaintext).ok()?).ok()?;
+        Some(Self { uaid })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fernet::{Fernet, MultiFernet};
+
+    use autoconnect_common::test_support::DUMMY_UAID;
+
+    use super::ResumeToken;
+
+    fn fernet_keys() -> Vec<Fernet> {
+        vec![Fernet::new(&Fernet::generate_key()).unwrap()]
+    }
+
+    #[test]
+    fn roundtrip() {
+        let fernet_keys = fernet_keys();
+        let fernet = MultiFernet::new(fernet_keys.clone());
+        let ttl = Duration::from_secs(60);
+        let token = ResumeToken::new(DUMMY_UAID);
+        let encrypted = token.encrypt(&fernet);
+        assert_eq!(
+            ResumeToken::decrypt(&encrypted, &fernet_keys, ttl),
+            Some(token)
+        );
+
+        // Invalid or signed by another key
+        assert_eq!(ResumeToken::decrypt("foo", &fernet_keys, ttl), None);
+        assert_eq!(
+            ResumeToken::decrypt(&encrypted, &self::fernet_keys(), ttl),
+            None
+        );
+    }
+
+    #[test]
+    fn expired() {
+        let fernet_keys = fernet_keys();
+        let token = ResumeToken::new(DUMMY_UAID).encrypt(&MultiFernet::new(fernet_keys.clone()));
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(
+            ResumeToken::decrypt(&token, &fernet_keys, Duration::ZERO),
+            None
+        );
+        assert!(ResumeToken::decrypt(&token, &fernet_keys, Duration::from_secs(60)).is_some());
+    }
+}
@@ -5,7 +5,7 @@ use uuid::Uuid;
 
 use autoconnect_common::{
     broadcast::{Broadcast, BroadcastSubs, BroadcastSubsInit},
-    protocol::{BroadcastValue, ClientMessage, ServerMessage},
+    protocol::{BroadcastValue, ClientAck, ClientMessage, ServerMessage},
 };
 use autoconnect_settings::{AppState, Settings};
 use autopush_common::{
@@ -16,6 +16,7 @@ use autopush_common::{
 use crate::{
     error::{SMError, SMErrorKind},
     identified::{ClientFlags, WebPushClient},
+    resume::ResumeToken,
 };
 
 /// Represents a Client waiting for (or yet to process) a Hello message
@@ -57,6 +58,8 @@ impl UnidentifiedClient {
             uaid,
             broadcasts,
             _channel_ids,
+            resume_token,
+            acked,
         } = msg
         else {
             return Err(SMError::invalid_message(
@@ -107,6 +110,13 @@ impl UnidentifiedClient {
                 .send();
         }
 
+        let resumed = if existing_user {
+            self.resume(&uaid, resume_token, acked)
+        } else {
+            vec![]
+        };
+        let resume_token = ResumeToken::issue(uaid, &self.app_state);
+
         let (broadcast_subs, broadcasts) = self
             .broadcast_init(&Broadcast::from_hashmap(broadcasts.unwrap_or_default()))
             .await;
@@ -118,6 +128,7 @@ impl UnidentifiedClient {
             user.connected_at,
             user.current_timestamp,
             (!existing_user).then_some(user),
+            resumed,
             self.app_state,
         )
         .await?;
@@ -127,6 +138,7 @@ impl UnidentifiedClient {
             use_webpush: true,
             status: 200,
             broadcasts,
+            resume_token,
         };
         let smsgs = std::iter::once(smsg).chain(check_storage_smsgs);
         Ok((wpclient, smsgs))
@@ -181,6 +193,44 @@ impl UnidentifiedClient {
         })
     }
 
+    /// Validate a Hello's session resume token, returning its delivery cursor
+    /// (the Notifications the Client's already received) when valid
+    ///
+    /// Invalid or expired tokens are ignored: the Client's simply resent any
+    /// unacknowledged Notifications as usual
+    fn resume(
+        &self,
+        uaid: &Uuid,
+        resume_token: Option<String>,
+        acked: Option<Vec<ClientAck>>,
+    ) -> Vec<ClientAck> {
+        let settings = self.app_settings();
+        let Some(resume_token) = resume_token else {
+            return vec![];
+        };
+        if settings.resume_token_ttl.is_zero() {
+            return vec![];
+        }
+        let valid = ResumeToken::decrypt(
+            &resume_token,
+            &self.app_state.fernet_keys,
+            settings.resume_token_ttl,
+        )
+        .is_some_and(|token| &token.uaid == uaid);
+        self.app_state
+            .metrics
+            .incr_with_tags("ua.session.resume")
+            .with_tag("status", if valid { "resumed" } else { "invalid" })
+            .send();
+        if !valid {
+            return vec![];
+        }
+        let mut acked = acked.unwrap_or_default();
+        // The cursor can't reasonably exceed the stored messages limit
+        acked.truncate(settings.msg_limit as usize);
+        acked
+    }
+
     /// Initialize `Broadcast`s for a new `WebPushClient`
     async fn broadcast_init(
         &self,
@@ -210,15 +260,15 @@ struct GetOrCreateUser {
 
 #[cfg(test)]
 mod tests {
-    use std::{str::FromStr, sync::Arc};
+    use std::{str::FromStr, sync::Arc, time::Duration};
 
     use autoconnect_common::{
-        protocol::ClientMessage,
+        protocol::{ClientAck, ClientMessage, ServerMessage},
         test_support::{hello_again_db, hello_db, DUMMY_CHID, DUMMY_UAID, UA},
     };
     use autoconnect_settings::AppState;
 
-    use crate::error::SMErrorKind;
+    use crate::{error::SMErrorKind, resume::ResumeToken};
 
     use super::UnidentifiedClient;
 
@@ -296,6 +346,8 @@ mod tests {
             uaid: Some("".to_owned()),
             _channel_ids: None,
             broadcasts: None,
+            resume_token: None,
+            acked: None,
         };
         client.on_client_msg(msg).await.expect("Hello failed");
     }
@@ -307,10 +359,47 @@ mod tests {
             uaid: Some("invalid".to_owned()),
             _channel_ids: None,
             broadcasts: None,
+            resume_token: None,
+            acked: None,
         };
         client.on_client_msg(msg).await.expect("Hello failed");
     }
 
+    #[tokio::test]
+    async fn hello_resume() {
+        let app_state = Arc::new(AppState {
+            db: hello_again_db(DUMMY_UAID).into_boxed_arc(),
+            ..Default::default()
+        });
+        let resume_token = ResumeToken::new(DUMMY_UAID).encrypt(&app_state.fernet);
+        let client = UnidentifiedClient::new(UA.to_owned(), Arc::clone(&app_state));
+        let msg = ClientMessage::Hello {
+            uaid: Some(DUMMY_UAID.to_string()),
+            _channel_ids: None,
+            broadcasts: None,
+            resume_token: Some(resume_token.clone()),
+            acked: Some(vec![ClientAck {
+                channel_id: DUMMY_CHID,
+                version: "foo".to_owned(),
+            }]),
+        };
+        let (_, smsgs) = client.on_client_msg(msg).await.expect("Hello failed");
+        let smsgs: Vec<_> = smsgs.into_iter().collect();
+        let [ServerMessage::Hello {
+            resume_token: Some(new_token),
+            ..
+        }] = smsgs.as_slice()
+        else {
+            panic!("Expected a Hello w/ a resume_token: {smsgs:?}");
+        };
+        // A fresh token's issued for the new session
+        assert_ne!(new_token, &resume_token);
+        let token =
+            ResumeToken::decrypt(new_token, &app_state.fernet_keys, Duration::from_secs(60))
+                .unwrap();
+        assert_eq!(token.uaid, DUMMY_UAID);
+    }
+
     #[tokio::test]
     async fn hello_bad_user() {}
 }
@@ -0,0 +1,466 @@
+//! WebSocket permessage-deflate compression (RFC 7692)
+//!
+//! actix-ws (via actix-http's WebSocket codec) neither negotiates extensions
+//! nor exposes a frame's RSV1 bit (which flags a compressed message). When the
+//! extension's negotiated, this module replaces actix-ws's `Session` and
+//! `MessageStream` with equivalents that (de)compress data frames, reusing
+//! actix-http's frame `Parser`.
+//!
+//! Compression contexts are never kept between messages
+//! (`server_no_context_takeover` and `client_no_context_takeover`), bounding
+//! each connection's memory to that of the message being (de)compressed.
+use std::{io, sync::Arc};
+
+use actix_http::{
+    body::{BodyStream, MessageBody},
+    error::PayloadError,
+    header::SEC_WEBSOCKET_EXTENSIONS,
+    ws::{handshake, CloseReason, Message, OpCode, Parser, ProtocolError},
+};
+use actix_web::{
+    web::{Bytes, BytesMut},
+    HttpRequest, HttpResponse,
+};
+use async_trait::async_trait;
+use cadence::{Counted, Histogrammed, StatsdClient};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::mpsc;
+
+use autoconnect_common::protocol::{
+    codec::{Codec, Frame},
+    ServerMessage,
+};
+use autoconnect_settings::Settings;
+
+use crate::{error::WSError, session::Session};
+
+/// The extension name
+const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+/// The negotiated extension (in the response's `Sec-WebSocket-Extensions`)
+const PERMESSAGE_DEFLATE_RESPONSE: &str =
+    "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+/// The empty deflate block ending each compressed message, which is removed
+/// before sending (RFC 7692 section 7.2.1)
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+/// The frame header bit flagging a compressed message
+const RSV1: u8 = 0x40;
+
+/// Select an acceptable permessage-deflate offer from the Client's
+/// `Sec-WebSocket-Extensions`, returning the response header value
+pub fn negotiate(offers: &str) -> Option<&'static str> {
+    for offer in offers.split(',') {
+        let mut params = offer.split(';').map(str::trim);
+        if params.next() != Some(PERMESSAGE_DEFLATE) {
+            continue;
+        }
+        let acceptable = params.all(|param| {
+            let (name, value) = param.split_once('=').map_or((param, None), |(n, v)| {
+                (n.trim(), Some(v.trim().trim_matches('"')))
+            });
+            match name {
+                "server_no_context_takeover" | "client_no_context_takeover" => value.is_none(),
+                // The Client's window size doesn't affect our inflating
+                "client_max_window_bits" => true,
+                // Our deflate implementation only supports the default
+                // (maximum) window size
+                "server_max_window_bits" => value == Some("15"),
+                _ => false,
+            }
+        });
+        if acceptable {
+            return Some(PERMESSAGE_DEFLATE_RESPONSE);
+        }
+    }
+    None
+}
+
+/// Perform the WebSocket handshake with the permessage-deflate `extension`,
+/// returning the equivalents of [actix_ws::handle]
+pub fn handle(
+    req: &HttpRequest,
+    payload: impl Stream<Item = Result<Bytes, PayloadError>> + Unpin + 'static,
+    extension: &'static str,
+    codec: Arc<dyn Codec>,
+    settings: &Settings,
+    metrics: Arc<StatsdClient>,
+) -> Result<
+    (
+        HttpResponse,
+        DeflateSession,
+        impl Stream<Item = Result<Message, ProtocolError>> + Unpin,
+    ),
+    actix_web::Error,
+> {
+    let mut response = handshake(req.head())?;
+    response.insert_header((SEC_WEBSOCKET_EXTENSIONS, extension));
+    let (tx, rx) = mpsc::channel(32);
+    let body = stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|bytes| (Ok::<_, actix_web::Error>(bytes), rx))
+    });
+    let session = DeflateSession {
+        tx,
+        codec,
+        threshold: settings.ws_deflate_threshold,
+        metrics,
+    };
+    let reader = MessageReader {
+        payload,
+        buf: BytesMut::new(),
+        max_size: settings.ws_deflate_max_message_size,
+        partial: None,
+        eof: false,
+    };
+    let msg_stream = Box::pin(stream::unfold(reader, |mut reader| async move {
+        reader.next_message().await.map(|msg| (msg, reader))
+    }));
+    Ok((
+        response.message_body(BodyStream::new(body).boxed())?.into(),
+        session,
+        msg_stream,
+    ))
+}
+
+/// Compress a message's payload, minus its trailing empty block
+fn deflate(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut compress = Compress::new(Compression::fast(), false);
+    let mut out = Vec::with_capacity(payload.len() + TRAILER.len());
+    loop {
+        let consumed = compress.total_in() as usize;
+        compress
+            .compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)
+            .map_err(io::Error::other)?;
+        // The flush is complete when the output isn't filled
+        if compress.total_in() as usize == payload.len() && out.len() < out.capacity() {
+            break;
+        }
+        out.reserve(out.capacity().max(64));
+    }
+    if out.ends_with(&TRAILER) {
+        out.truncate(out.len() - TRAILER.len());
+    }
+    Ok(out)
+}
+
+/// Decompress a message's payload, failing if it exceeds `max_size`
+fn inflate(payload: &[u8], max_size: usize) -> Result<Vec<u8>, ProtocolError> {
+    let input = [payload, &TRAILER].concat();
+    let mut decompress = Decompress::new(false);
+    let mut out = Vec::with_capacity((payload.len() * 4).max(64).min(max_size + 1));
+    loop {
+        let (consumed, produced) = (decompress.total_in(), decompress.total_out());
+        let status = decompress
+            .decompress_vec(&input[consumed as usize..], &mut out, FlushDecompress::Sync)
+            .map_err(|e| ProtocolError::Io(io::Error::other(e)))?;
+        if out.len() > max_size {
+            return Err(ProtocolError::Overflow);
+        }
+        let input_done = decompress.total_in() as usize == input.len();
+        if status == Status::StreamEnd || (input_done && out.len() < out.capacity()) {
+            return Ok(out);
+        }
+        if out.len() == out.capacity() {
+            out.reserve_exact(out.capacity().min(max_size + 1 - out.capacity()));
+        } else if (consumed, produced) == (decompress.total_in(), decompress.total_out()) {
+            return Err(ProtocolError::Io(io::Error::other(
+                "Truncated compressed message",
+            )));
+        }
+    }
+}
+
+/// Implements our `Session`, compressing outgoing data frames of at least
+/// `threshold` bytes
+pub struct DeflateSession {
+    tx: mpsc::Sender<Bytes>,
+    codec: Arc<dyn Codec>,
+    threshold: usize,
+    metrics: Arc<StatsdClient>,
+}
+
+impl DeflateSession {
+    async fn write(&mut self, buf: BytesMut) -> Result<(), WSError> {
+        self.tx
+            .send(buf.freeze())
+            .await
+            .map_err(|_| actix_ws::Closed)?;
+        Ok(())
+    }
+
+    /// Frame a data message, compressing it when worthwhile
+    fn data_frame(&self, payload: &[u8], op: OpCode) -> Result<BytesMut, WSError> {
+        let mut buf = BytesMut::new();
+        if payload.len() >= self.threshold {
+            let compressed = deflate(payload).map_err(ProtocolError::Io)?;
+            let ratio = compressed.len() * 100 / payload.len();
+            let _ = self.metrics.histogram("ua.ws.deflate.ratio", ratio as u64);
+            if compressed.len() < payload.len() {
+                let _ = self.metrics.count(
+                    "ua.ws.deflate.bytes_saved",
+                    (payload.len() - compressed.len()) as i64,
+                );
+                Parser::write_message(&mut buf, compressed, op, true, false);
+                buf[0] |= RSV1;
+                return Ok(buf);
+            }
+        }
+        Parser::write_message(&mut buf, payload, op, true, false);
+        Ok(buf)
+    }
+}
+
+#[async_trait]
+impl Session for DeflateSession {
+    async fn send(&mut self, msg: ServerMessage) -> Result<(), WSError> {
+        let buf = match self.codec.encode(&msg)? {
+            Frame::Text(text) => self.data_frame(text.as_bytes(), OpCode::Text)?,
+            Frame::Binary(bytes) => self.data_frame(&bytes, OpCode::Binary)?,
+        };
+        self.write(buf).await
+    }
+
+    async fn ping(&mut self, msg: &[u8]) -> Result<(), WSError> {
+        let mut buf = BytesMut::new();
+        Parser::write_message(&mut buf, msg, OpCode::Ping, true, false);
+        self.write(buf).await
+    }
+
+    async fn pong(&mut self, msg: &[u8]) -> Result<(), WSError> {
+        let mut buf = BytesMut::new();
+        Parser::write_message(&mut buf, msg, OpCode::Pong, true, false);
+        self.write(buf).await
+    }
+
+    async fn close(mut self, reason: Option<CloseReason>) -> Result<(), WSError> {
+        let mut buf = BytesMut::new();
+        Parser::write_close(&mut buf, reason, false);
+        self.write(buf).await
+    }
+}
+
+/// Reads `Message`s from the Client, inflating compressed data messages
+struct MessageReader<S> {
+    payload: S,
+    buf: BytesMut,
+    /// Maximum size of a frame and of an inflated message
+    max_size: usize,
+    /// A fragmented data message being read: its opcode, whether it's
+    /// compressed and its payload so far
+    partial: Option<(OpCode, bool, BytesMut)>,
+    eof: bool,
+}
+
+impl<S> MessageReader<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    async fn next_message(&mut self) -> Option<Result<Message, ProtocolError>> {
+        loop {
+            let rsv1 = self.buf.first().is_some_and(|b| b & RSV1 != 0);
+            match Parser::parse(&mut self.buf, true, self.max_size) {
+                Ok(Some((finished, opcode, payload))) => {
+                    match self.on_frame(finished, opcode, rsv1, payload.unwrap_or_default()) {
+                        Ok(Some(msg)) => return Some(Ok(msg)),
+                        Ok(None) => continue,
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                Ok(None) => (),
+                Err(e) => return Some(Err(e)),
+            }
+            if self.eof {
+                return None;
+            }
+            match self.payload.next().await {
+                Some(Ok(bytes)) => self.buf.extend_from_slice(&bytes),
+                Some(Err(e)) => {
+                    return Some(Err(ProtocolError::Io(io::Error::other(e.to_string()))))
+                }
+                None => self.eof = true,
+            }
+        }
+    }
+
+    /// Handle a parsed frame, returning a `Message` once one's complete
+    fn on_frame(
+        &mut self,
+        finished: bool,
+        opcode: OpCode,
+        rsv1: bool,
+        payload: BytesMut,
+    ) -> Result<Option<Message>, ProtocolError> {
+        match opcode {
+            OpCode::Ping => return Ok(Some(Message::Ping(payload.freeze()))),
+            OpCode::Pong => return Ok(Some(Message::Pong(payload.freeze()))),
+            OpCode::Close => {
+                return Ok(Some(Message::Close(Parser::parse_close_payload(&payload))))
+            }
+            OpCode::Text | OpCode::Binary => {
+                if self.partial.is_some() {
+                    return Err(ProtocolError::ContinuationStarted);
+                }
+                self.partial = Some((opcode, rsv1, payload));
+            }
+            OpCode::Continue => {
+                let Some((_, _, partial)) = &mut self.partial else {
+                    return Err(ProtocolError::ContinuationNotStarted);
+                };
+                if partial.len() + payload.len() > self.max_size {
+                    return Err(ProtocolError::Overflow);
+                }
+                partial.extend_from_slice(&payload);
+            }
+            OpCode::Bad => return Err(ProtocolError::BadOpCode),
+        }
+        if !finished {
+            return Ok(None);
+        }
+
+        let Some((opcode, compressed, payload)) = self.partial.take() else {
+            return Err(ProtocolError::ContinuationNotStarted);
+        };
+        let payload = if compressed {
+            Bytes::from(inflate(&payload, self.max_size)?)
+        } else {
+            payload.freeze()
+        };
+        Ok(Some(if opcode == OpCode::Text {
+            let text = String::from_utf8(payload.to_vec())
+                .map_err(|e| ProtocolError::Io(io::Error::other(e)))?;
+            Message::Text(text.into())
+        } else {
+            Message::Binary(payload)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::{error::PayloadError, ws::Message};
+    use actix_web::web::Bytes;
+    use futures::{stream, StreamExt};
+
+    use super::*;
+
+    /// "Hello" compressed, as per RFC 7692 section 7.2.3.1
+    const HELLO_DEFLATED: [u8; 7] = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+
+    #[test]
+    fn negotiate_offers() {
+        assert_eq!(negotiate(""), None);
+        assert_eq!(negotiate("x-webkit-deflate-frame"), None);
+        assert_eq!(
+            negotiate("permessage-deflate; client_max_window_bits"),
+            Some(PERMESSAGE_DEFLATE_RESPONSE)
+        );
+        // A smaller server window isn't supported but the fallback offer is
+        assert_eq!(
+            negotiate("permessage-deflate; server_max_window_bits=10"),
+            None
+        );
+        assert_eq!(
+            negotiate(
+                "permessage-deflate; server_max_window_bits=10, permessage-deflate; \
+                 server_no_context_takeover"
+            ),
+            Some(PERMESSAGE_DEFLATE_RESPONSE)
+        );
+        assert_eq!(negotiate("permessage-deflate; foo=bar"), None);
+    }
+
+    #[test]
+    fn deflate_roundtrip() {
+        assert_eq!(inflate(&HELLO_DEFLATED, 1024).unwrap(), b"Hello");
+
+        let payload = "foo".repeat(1000);
+        let compressed = deflate(payload.as_bytes()).unwrap();
+        assert!(compressed.len() < payload.len());
+        assert!(!compressed.ends_with(&TRAILER));
+        assert_eq!(inflate(&compressed, 4096).unwrap(), payload.as_bytes());
+
+        // Inflating beyond the max size fails
+        assert!(matches!(
+            inflate(&compressed, 2999),
+            Err(ProtocolError::Overflow)
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn read_messages() {
+        // Masked (with a zero key) client frames: a compressed Text message,
+        // an uncompressed Ping and a compressed message in 2 fragments
+        let mut bytes = vec![0x80 | RSV1 | 0x1, 0x80 | 7, 0, 0, 0, 0];
+        bytes.extend(HELLO_DEFLATED);
+        bytes.extend([0x89, 0x80, 0, 0, 0, 0]);
+        bytes.extend([RSV1 | 0x1, 0x80 | 3, 0, 0, 0, 0]);
+        bytes.extend(&HELLO_DEFLATED[..3]);
+        bytes.extend([0x80, 0x80 | 4, 0, 0, 0, 0]);
+        bytes.extend(&HELLO_DEFLATED[3..]);
+        // Deliver the frames split across arbitrary chunks
+        let chunks: Vec<Result<Bytes, PayloadError>> = bytes
+            .chunks(5)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let mut reader = MessageReader {
+            payload: stream::iter(chunks),
+            buf: BytesMut::new(),
+            max_size: 1024,
+            partial: None,
+            eof: false,
+        };
+
+        let Some(Ok(Message::Text(text))) = reader.next_message().await else {
+            panic!("Expected Text");
+        };
+        assert_eq!(text, "Hello");
+        assert!(matches!(
+            reader.next_message().await,
+            Some(Ok(Message::Ping(_)))
+        ));
+        let Some(Ok(Message::Text(text))) = reader.next_message().await else {
+            panic!("Expected Text");
+        };
+        assert_eq!(text, "Hello");
+        assert!(reader.next_message().await.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn session_threshold() {
+        let (tx, rx) = mpsc::channel(2);
+        let mut session = DeflateSession {
+            tx,
+            codec: Arc::new(autoconnect_common::protocol::codec::JsonCodec),
+            threshold: 256,
+            metrics: Arc::new(StatsdClient::builder("", cadence::NopMetricSink).build()),
+        };
+        session
+            .send(ServerMessage::Ping { resume_token: None })
+            .await
+            .unwrap();
+        let broadcasts = (0..50)
+            .map(|i| {
+                (
+                    format!("foo/{i}"),
+                    autoconnect_common::protocol::BroadcastValue::Value("v1".to_owned()),
+                )
+            })
+            .collect();
+        session
+            .send(ServerMessage::Broadcast { broadcasts })
+            .await
+            .unwrap();
+        drop(session);
+
+        let frames: Vec<Bytes> = stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|bytes| (bytes, rx))
+        })
+        .collect()
+        .await;
+        // Small messages are sent uncompressed
+        assert_eq!(&frames[0][..], &[0x81, 2, b'{', b'}']);
+        // Large ones are compressed
+        assert_eq!(frames[1][0], 0x80 | RSV1 | 0x1);
+    }
+}
@@ -3,6 +3,7 @@ use std::{error::Error, fmt};
 use actix_ws::CloseCode;
 use backtrace::Backtrace;
 
+use autoconnect_common::protocol::codec::CodecError;
 use autoconnect_ws_sm::{SMError, WebPushClient};
 use autopush_common::{errors::ReportableError, sentry::event_from_error};
 
@@ -104,6 +105,9 @@ pub enum WSErrorKind {
     #[error("Couldn't parse WebSocket message JSON: {0}")]
     Json(#[from] serde_json::Error),
 
+    #[error("Couldn't encode or decode WebSocket message: {0}")]
+    Codec(#[source] CodecError),
+
     #[error("WebSocket protocol error: {0}")]
     Protocol(#[from] actix_ws::ProtocolError),
 
@@ -126,6 +130,16 @@ pub enum WSErrorKind {
     RegistryDisconnected,
 }
 
+impl From<CodecError> for WSErrorKind {
+    fn from(e: CodecError) -> Self {
+        match e {
+            CodecError::Json(e) => WSErrorKind::Json(e),
+            CodecError::UnexpectedFrame(_) => WSErrorKind::UnsupportedMessage(e.to_string()),
+            e => WSErrorKind::Codec(e),
+        }
+    }
+}
+
 impl WSErrorKind {
     /// Whether this error is reported to Sentry
     fn is_sentry_event(&self) -> bool {
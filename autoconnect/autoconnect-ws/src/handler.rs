@@ -1,32 +1,40 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use actix_ws::{CloseReason, Message};
 use futures::{channel::mpsc, Stream, StreamExt};
-use tokio::{select, time::timeout};
+use rand::Rng;
+use tokio::{
+    pin, select,
+    time::{sleep, timeout, Instant},
+};
 
-use autoconnect_common::protocol::{ServerMessage, ServerNotification};
+use autoconnect_common::protocol::{
+    codec::{Codec, Frame},
+    ClientMessage, ServerMessage, ServerNotification,
+};
 use autoconnect_settings::AppState;
-use autoconnect_ws_sm::{UnidentifiedClient, WebPushClient};
+use autoconnect_ws_sm::{IdentifiedClient, UnidentifiedClient, WebPushClient};
 
 use crate::{
     error::{WSError, WSErrorKind},
     ping::PingManager,
-    session::{Session, SessionImpl},
+    session::Session,
 };
 
 type MessageStreamResult = Result<actix_ws::Message, actix_ws::ProtocolError>;
 
 /// WebPush WebSocket handler Task
 pub fn spawn_webpush_ws(
-    session: actix_ws::Session,
-    msg_stream: actix_ws::MessageStream,
+    session: impl Session + 'static,
+    msg_stream: impl Stream<Item = MessageStreamResult> + Unpin + 'static,
     app_state: Arc<AppState>,
     ua: String,
+    codec: Arc<dyn Codec>,
 ) {
     actix_rt::spawn(async move {
         let client = UnidentifiedClient::new(ua, app_state);
-        let mut session = SessionImpl::new(session);
-        let close_reason = webpush_ws(client, &mut session, msg_stream)
+        let mut session = session;
+        let close_reason = webpush_ws(client, codec.as_ref(), &mut session, msg_stream)
             .await
             .unwrap_or_else(|e| {
                 trace!("spawn_webpush_ws: Error: {}", e);
@@ -54,13 +62,14 @@ pub fn spawn_webpush_ws(
 /// - the lifecycle/cleanup of the Client
 pub(crate) async fn webpush_ws(
     client: UnidentifiedClient,
+    codec: &dyn Codec,
     session: &mut impl Session,
     mut msg_stream: impl Stream<Item = MessageStreamResult> + Unpin,
 ) -> Result<Option<CloseReason>, WSError> {
     // NOTE: UnidentifiedClient doesn't require shutdown/cleanup, so its
     // Error's propagated. We don't propagate Errors afterwards to handle
     // shutdown/cleanup of WebPushClient
-    let (mut client, smsgs) = match unidentified_ws(client, &mut msg_stream).await {
+    let (client, smsgs) = match unidentified_ws(client, codec, &mut msg_stream).await {
         Ok(t) => t,
         Err(e) => {
             e.capture_sentry_event(None);
@@ -70,7 +79,17 @@ pub(crate) async fn webpush_ws(
 
     // Client now identified: add them to the registry to recieve ServerNotifications
     let mut snotif_stream = client.registry_connect().await;
-    let result = identified_ws(&mut client, smsgs, session, msg_stream, &mut snotif_stream).await;
+    let mut client = IdentifiedClient::from(client);
+    let result = identified_ws(
+        &mut client,
+        smsgs,
+        codec,
+        session,
+        msg_stream,
+        &mut snotif_stream,
+    )
+    .await;
+    let mut client = client.into_client();
     client.registry_disconnect().await;
 
     snotif_stream.close();
@@ -80,7 +99,7 @@ pub(crate) async fn webpush_ws(
     client.shutdown(result.as_ref().err().map(|e| e.to_string()));
 
     if let Err(ref e) = result {
-        e.capture_sentry_event(Some(client));
+        e.capture_sentry_event(Some(*client));
     }
     result
 }
@@ -91,6 +110,7 @@ pub(crate) async fn webpush_ws(
 /// an identified `WebPushClient` on success.
 async fn unidentified_ws(
     client: UnidentifiedClient,
+    codec: &dyn Codec,
     msg_stream: &mut (impl Stream<Item = MessageStreamResult> + Unpin),
 ) -> Result<(WebPushClient, impl IntoIterator<Item = ServerMessage>), WSError> {
     let stream_with_timeout = timeout(
@@ -105,7 +125,7 @@ async fn unidentified_ws(
     trace!("❓unidentified_ws: Handshake msg: {:?}", msg);
 
     let client_msg = match msg {
-        Message::Text(ref bytestring) => bytestring.parse()?,
+        Message::Text(_) | Message::Binary(_) => decode(codec, msg)?,
         _ => {
             return Err(WSErrorKind::UnsupportedMessage("Expected Text".to_owned()).into());
         }
@@ -133,9 +153,16 @@ async fn unidentified_ws(
 ///   out the Client for not responding to a previous Ping in time. The Ping
 ///   encourages the connection to keep alive (it's more likely to be dropped if
 ///   completely idle) and aids in detecting Clients that are no longer connected
+///
+/// Additionally, Clients sending no messages nor receiving any Notifications
+/// for `hibernate_idle_timeout` are hibernated (see
+/// `IdentifiedClient::hibernate`), and when `broadcast_fanout_jitter` is enabled
+/// Broadcast changes are sent to the Client after a random delay within it
+/// (instead of waiting for the next Ping)
 async fn identified_ws(
-    client: &mut WebPushClient,
+    client: &mut IdentifiedClient,
     smsgs: impl IntoIterator<Item = ServerMessage>,
+    codec: &dyn Codec,
     session: &mut impl Session,
     mut msg_stream: impl Stream<Item = MessageStreamResult> + Unpin,
     snotif_stream: &mut mpsc::UnboundedReceiver<ServerNotification>,
@@ -146,10 +173,18 @@ async fn identified_ws(
             "identified_ws: New WebPushClient, ServerMessage -> session: {:#?}",
             smsg
         );
-        session.text(smsg).await?;
+        session.send(smsg).await?;
     }
 
     let mut ping_manager = PingManager::new(client.app_settings()).await;
+    let hibernate_idle_timeout = client.app_settings().hibernate_idle_timeout;
+    let idle = sleep(hibernate_idle_timeout);
+    pin!(idle);
+    let mut fanout_jitter = client.app_settings().broadcast_fanout_jitter;
+    let mut broadcast_changes = client.broadcast_changes().await;
+    let fanout = sleep(fanout_jitter);
+    pin!(fanout);
+    let mut fanout_pending = false;
     let close_reason = loop {
         select! {
             maybe_result = msg_stream.next() => {
@@ -161,7 +196,7 @@ async fn identified_ws(
                 let msg = result?;
                 trace!("identified_ws: msg: {:#?}", msg);
                 let client_msg = match msg {
-                    Message::Text(ref bytestring) => bytestring.parse()?,
+                    Message::Text(_) | Message::Binary(_) => decode(codec, msg)?,
                     Message::Nop => continue,
                     Message::Close(reason) => break reason,
                     Message::Ping(bytes) => {
@@ -174,9 +209,10 @@ async fn identified_ws(
                     },
                     _ => return Err(WSErrorKind::UnsupportedMessage("Expected Text, etc.".to_owned()).into())
                 };
-                for smsg in client.on_client_msg(client_msg).await? {
+                idle.as_mut().reset(Instant::now() + hibernate_idle_timeout);
+                for smsg in client.wake().on_client_msg(client_msg).await? {
                     trace!("identified_ws: msg_stream, ServerMessage -> session {:#?}", smsg);
-                    session.text(smsg).await?;
+                    session.send(smsg).await?;
                 }
             },
 
@@ -185,9 +221,10 @@ async fn identified_ws(
                     trace!("identified_ws: snotif_stream EOF");
                     return Err(WSErrorKind::RegistryDisconnected.into());
                 };
-                for smsg in client.on_server_notif(snotif).await? {
+                idle.as_mut().reset(Instant::now() + hibernate_idle_timeout);
+                for smsg in client.wake().on_server_notif(snotif).await? {
                     trace!("identified_ws: snotif_stream, ServerMessage -> session {:#?}", smsg);
-                    session.text(smsg).await?;
+                    session.send(smsg).await?;
                 }
             }
 
@@ -197,8 +234,43 @@ async fn identified_ws(
                 result?;
                 ping_manager.ws_ping_or_broadcast(client, session).await?;
             }
+
+            result = broadcast_changes.changed(), if !fanout_jitter.is_zero() && !fanout_pending => {
+                if result.is_err() {
+                    // Unexpected: the tracker should outlive the Client
+                    fanout_jitter = Duration::ZERO;
+                    continue;
+                }
+                let delay = rand::thread_rng().gen_range(Duration::ZERO..=fanout_jitter);
+                trace!("📢identified_ws: Broadcast changed, fanout in {:?}", delay);
+                fanout_pending = true;
+                fanout.as_mut().reset(Instant::now() + delay);
+            }
+
+            _ = &mut fanout, if fanout_pending => {
+                fanout_pending = false;
+                ping_manager.ws_broadcast(client, session).await?;
+            }
+
+            _ = &mut idle, if !hibernate_idle_timeout.is_zero() && !client.is_hibernating() => {
+                if !client.hibernate() {
+                    // Still awaiting Acks, etc: try again later
+                    idle.as_mut().reset(Instant::now() + hibernate_idle_timeout);
+                }
+            }
         }
     };
 
     Ok(close_reason)
 }
+
+/// Decode a Text or Binary data frame from the Client via the negotiated
+/// `Codec`
+fn decode(codec: &dyn Codec, msg: Message) -> Result<ClientMessage, WSError> {
+    let frame = match msg {
+        Message::Text(bytestring) => Frame::Text(bytestring.to_string()),
+        Message::Binary(bytes) => Frame::Binary(bytes.to_vec()),
+        _ => return Err(WSErrorKind::UnsupportedMessage("Expected data frame".to_owned()).into()),
+    };
+    Ok(codec.decode(frame)?)
+}
@@ -1,16 +1,25 @@
 #[macro_use]
 extern crate slog_scope;
 
+use std::sync::Arc;
+
 use actix_web::{
-    http::header::{HeaderValue, USER_AGENT},
+    http::header::{HeaderValue, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL, USER_AGENT},
     web, Error, HttpRequest, HttpResponse,
 };
+use cadence::CountedExt;
 
+use autoconnect_common::protocol::codec;
 use autoconnect_settings::AppState;
 
+pub use push_stream::{push_stream_handler, MessageId};
+use session::SessionImpl;
+
+mod deflate;
 mod error;
 mod handler;
 mod ping;
+mod push_stream;
 mod session;
 #[cfg(test)]
 mod test;
@@ -22,7 +31,6 @@ pub async fn ws_handler(
     app_state: web::Data<AppState>,
 ) -> Result<HttpResponse, Error> {
     debug!("🔌 Got connection");
-    let (response, session, msg_stream) = actix_ws::handle(&req, body)?;
     let ua = req
         .headers()
         .get(USER_AGENT)
@@ -30,6 +38,48 @@ pub async fn ws_handler(
         .to_str()
         .unwrap_or_default()
         .to_owned();
-    handler::spawn_webpush_ws(session, msg_stream, app_state.into_inner(), ua);
+    let codec = codec::negotiate(
+        req.headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|v| v.to_str().ok()),
+    );
+    // Clients may send multiple Sec-WebSocket-Extensions headers
+    let extensions = req
+        .headers()
+        .get_all(SEC_WEBSOCKET_EXTENSIONS)
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+    let deflate = app_state
+        .settings
+        .ws_deflate
+        .then(|| deflate::negotiate(&extensions))
+        .flatten();
+
+    let app_state = app_state.into_inner();
+    let mut response = if let Some(extension) = deflate {
+        let _ = app_state.metrics.incr("ua.ws.deflate.negotiated");
+        let (response, session, msg_stream) = deflate::handle(
+            &req,
+            body.into_inner(),
+            extension,
+            Arc::clone(&codec),
+            &app_state.settings,
+            Arc::clone(&app_state.metrics),
+        )?;
+        handler::spawn_webpush_ws(session, msg_stream, app_state, ua, codec.clone());
+        response
+    } else {
+        let (response, session, msg_stream) = actix_ws::handle(&req, body)?;
+        let session = SessionImpl::new(session, Arc::clone(&codec));
+        handler::spawn_webpush_ws(session, msg_stream, app_state, ua, codec.clone());
+        response
+    };
+    if let Some(subprotocol) = codec.subprotocol() {
+        response.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(subprotocol),
+        );
+    }
     Ok(response)
 }
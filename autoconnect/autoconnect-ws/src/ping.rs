@@ -2,7 +2,7 @@ use tokio::time::{interval, Interval};
 
 use autoconnect_common::{broadcast::Broadcast, protocol::ServerMessage};
 use autoconnect_settings::Settings;
-use autoconnect_ws_sm::WebPushClient;
+use autoconnect_ws_sm::IdentifiedClient;
 
 use crate::{
     error::{WSError, WSErrorKind},
@@ -64,20 +64,11 @@ impl PingManager {
     /// Send the Client a WebSocket Ping or WebPush Broadcast, if one is pending
     pub async fn ws_ping_or_broadcast(
         &mut self,
-        client: &mut WebPushClient,
+        client: &mut IdentifiedClient,
         session: &mut impl Session,
     ) -> Result<(), WSError> {
-        if let Some(broadcasts) = client.broadcast_delta().await {
-            let smsg = ServerMessage::Broadcast {
-                broadcasts: Broadcast::vec_into_hashmap(broadcasts),
-            };
-            trace!("📢PingManager::ws_ping_or_broadcast {:#?}", smsg);
-            session.text(smsg).await?;
-            // Broadcasts don't recieve a Pong but sync against the next Ping
-            // anyway
-            debug_assert!(matches!(self.waiting, Waiting::ToPing));
-            self.ping_or_timeout.reset();
-        } else {
+        debug_assert!(matches!(self.waiting, Waiting::ToPing));
+        if !self.ws_broadcast(client, session).await? {
             trace!("🏓PingManager::ws_ping_or_broadcast ping");
             session.ping(&[]).await?;
             self.set_waiting(Waiting::ForPong, client.app_settings())
@@ -86,6 +77,30 @@ impl PingManager {
         Ok(())
     }
 
+    /// Send the Client a WebPush Broadcast, if one is pending
+    ///
+    /// Returns whether one was sent
+    pub async fn ws_broadcast(
+        &mut self,
+        client: &mut IdentifiedClient,
+        session: &mut impl Session,
+    ) -> Result<bool, WSError> {
+        let Some(broadcasts) = client.broadcast_delta().await else {
+            return Ok(false);
+        };
+        let smsg = ServerMessage::Broadcast {
+            broadcasts: Broadcast::vec_into_hashmap(broadcasts),
+        };
+        trace!("📢PingManager::ws_broadcast {:#?}", smsg);
+        session.send(smsg).await?;
+        // Broadcasts don't recieve a Pong but sync against the next Ping
+        // anyway (a pending Pong timeout's left running)
+        if let Waiting::ToPing = self.waiting {
+            self.ping_or_timeout.reset();
+        }
+        Ok(true)
+    }
+
     /// Receive a WebSocket Pong from the Client
     ///
     /// Resetting the timer kicked off from the last WebSocket Ping
@@ -0,0 +1,309 @@
+//! RFC 8030 push message receipt over a long lived streaming HTTP response
+//!
+//! An alternative to the WebSocket protocol for user agents speaking the IETF
+//! Web Push protocol: after subscribing (via `POST /subscribe`) the user agent
+//! issues a `GET` for its subscription set and push messages are written to the
+//! response as they arrive. actix-web doesn't support HTTP/2 server push, so
+//! each push message is written as a Server-Sent Event instead (over either
+//! HTTP/1.1 or HTTP/2).
+//!
+//! The stream is driven by the same `WebPushClient` as the WebSocket handler:
+//! messages are read from storage and must be acknowledged (via `DELETE` of
+//! the message resource, which is forwarded through the `ClientRegistry`)
+//! before further stored messages are sent.
+//!
+//! The message resource is an encrypted [MessageId]: it's only known to the
+//! reader of the push stream, which is the only client it acknowledges
+//! messages for.
+use actix_web::{
+    http::header::{self, HeaderValue},
+    web::{self, Bytes},
+    Error, HttpRequest, HttpResponse,
+};
+use fernet::MultiFernet;
+use futures::{channel::mpsc as futures_mpsc, stream, StreamExt};
+use tokio::{select, sync::mpsc, time::interval};
+use uuid::Uuid;
+
+use autoconnect_common::protocol::{ClientMessage, ServerMessage, ServerNotification};
+use autoconnect_settings::AppState;
+use autoconnect_ws_sm::{SMErrorKind, UnidentifiedClient, WebPushClient};
+
+use crate::error::{WSError, WSErrorKind};
+
+/// Number of pending events buffered for a slow reader
+const EVENT_BUFFER: usize = 16;
+
+/// Identifies a push message delivered over a push stream
+///
+/// Encrypted into the push message resource, so the resource can't be derived
+/// from the (public) subscription set and message details.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MessageId {
+    pub uaid: Uuid,
+    /// The local ID of the push stream's connection
+    pub uid: Uuid,
+    pub channel_id: Uuid,
+    pub version: String,
+}
+
+impl MessageId {
+    /// Encode and encrypt the message ID
+    pub fn encrypt(&self, fernet: &MultiFernet) -> String {
+        let id_str = format!(
+            "{}:{}:{}:{}",
+            self.uaid.as_simple(),
+            self.uid.as_simple(),
+            self.channel_id.as_simple(),
+            self.version
+        );
+        fernet.encrypt(id_str.as_bytes())
+    }
+
+    /// Decrypt and decode the message ID, returning `None` when invalid
+    pub fn decrypt(fernet: &MultiFernet, message_id: &str) -> Option<Self> {
+        let decrypted = String::from_utf8(fernet.decrypt(message_id).ok()?).ok()?;
+        let mut parts = decrypted.splitn(4, ':');
+        let mut next_uuid = || Uuid::try_parse(parts.next()?).ok();
+        let (uaid, uid, channel_id) = (next_uuid()?, next_uuid()?, next_uuid()?);
+        Some(Self {
+            uaid,
+            uid,
+            channel_id,
+            version: parts.next()?.to_owned(),
+        })
+    }
+}
+
+/// Handles RFC 8030 clients receiving push messages for their subscription set
+pub async fn push_stream_handler(
+    req: HttpRequest,
+    uaid: Uuid,
+    app_state: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    debug!("🌊 Got push stream request"; "uaid" => uaid.as_simple().to_string());
+    let ua = req
+        .headers()
+        .get(header::USER_AGENT)
+        .unwrap_or(&HeaderValue::from_static(""))
+        .to_str()
+        .unwrap_or_default()
+        .to_owned();
+    let fernet = app_state.fernet.clone();
+    let client = UnidentifiedClient::new(ua, app_state.into_inner());
+    let hello = ClientMessage::Hello {
+        uaid: Some(uaid.as_simple().to_string()),
+        _channel_ids: None,
+        broadcasts: None,
+        resume_token: None,
+        acked: None,
+    };
+    let (client, smsgs) = match client.on_client_msg(hello).await {
+        Ok((client, smsgs)) => (client, smsgs.into_iter().collect::<Vec<_>>()),
+        Err(e) if matches!(e.kind, SMErrorKind::AlreadyConnected) => {
+            return Ok(HttpResponse::Conflict().body("Already connected to another node"));
+        }
+        Err(e) => {
+            let e = WSError::from(e);
+            e.capture_sentry_event(None);
+            return Err(actix_web::error::ErrorInternalServerError(e));
+        }
+    };
+    if client.uaid != uaid {
+        // Unknown subscription set: the Hello issued a new (unsaved) uaid
+        return Ok(HttpResponse::NotFound().body("Subscription set not found"));
+    }
+
+    let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+    spawn_push_stream(client, smsgs, tx, fernet);
+    let body = stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (Ok::<_, Error>(event), rx))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .streaming(body))
+}
+
+/// Push stream Task
+fn spawn_push_stream(
+    mut client: WebPushClient,
+    smsgs: Vec<ServerMessage>,
+    tx: mpsc::Sender<Bytes>,
+    fernet: MultiFernet,
+) {
+    actix_rt::spawn(async move {
+        let mut snotif_stream = client.registry_connect().await;
+        let result = push_stream(&mut client, smsgs, &tx, &fernet, &mut snotif_stream).await;
+        client.registry_disconnect().await;
+
+        snotif_stream.close();
+        while let Some(snotif) = snotif_stream.next().await {
+            client.on_server_notif_shutdown(snotif);
+        }
+        client.shutdown(result.as_ref().err().map(|e| e.to_string()));
+
+        if let Err(e) = result {
+            trace!("spawn_push_stream: Error: {}", e);
+            // Let the client know why the stream ended (if still listening)
+            let _ = tx
+                .send(Bytes::from(format!(
+                    "event: close\ndata: {}\n\n",
+                    e.close_description()
+                )))
+                .await;
+            e.capture_sentry_event(Some(client));
+        }
+    });
+}
+
+/// The push stream handler
+///
+/// Similar to the WebSocket handler's `identified_ws`, this waits on
+/// `ServerNotification`s from the `ClientRegistry` (notifications from
+/// autoendpoint and Acks from the client), writing any resulting push messages
+/// to the response. A comment line is written every `auto_ping_interval` to
+/// keep the connection from idling out.
+async fn push_stream(
+    client: &mut WebPushClient,
+    smsgs: Vec<ServerMessage>,
+    tx: &mpsc::Sender<Bytes>,
+    fernet: &MultiFernet,
+    snotif_stream: &mut futures_mpsc::UnboundedReceiver<ServerNotification>,
+) -> Result<(), WSError> {
+    // Send any initial notifications from storage
+    for smsg in smsgs {
+        if !send_event(tx, fernet, &client.uaid, &client.uid, &smsg).await? {
+            return Ok(());
+        }
+    }
+
+    let mut keepalive = interval(client.app_settings().auto_ping_interval);
+    keepalive.tick().await;
+    loop {
+        select! {
+            _ = tx.closed() => {
+                trace!("push_stream: response stream closed");
+                return Ok(());
+            }
+
+            maybe_snotif = snotif_stream.next() => {
+                let Some(snotif) = maybe_snotif else {
+                    trace!("push_stream: snotif_stream EOF");
+                    return Err(WSErrorKind::RegistryDisconnected.into());
+                };
+                for smsg in client.on_server_notif(snotif).await? {
+                    if !send_event(tx, fernet, &client.uaid, &client.uid, &smsg).await? {
+                        return Ok(());
+                    }
+                }
+            }
+
+            _ = keepalive.tick() => {
+                if tx.send(Bytes::from_static(b":\n\n")).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Write a push message to the response as a Server-Sent Event
+///
+/// The event's `id` is the push message resource, which is acknowledged by a
+/// `DELETE` request. Returns false when the client's gone away.
+async fn send_event(
+    tx: &mpsc::Sender<Bytes>,
+    fernet: &MultiFernet,
+    uaid: &Uuid,
+    uid: &Uuid,
+    smsg: &ServerMessage,
+) -> Result<bool, WSError> {
+    let ServerMessage::Notification(notif) = smsg else {
+        // Only push messages are relevant to RFC 8030 clients
+        return Ok(true);
+    };
+    let message_id = MessageId {
+        uaid: *uaid,
+        uid: *uid,
+        channel_id: notif.channel_id,
+        version: notif.version.clone(),
+    };
+    let event = format!(
+        "id: /message/{}\nevent: push\ndata: {}\n\n",
+        message_id.encrypt(fernet),
+        smsg.to_json()?
+    );
+    trace!("🌊 push_stream: event -> response {}", event);
+    Ok(tx.send(Bytes::from(event)).await.is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use autoconnect_common::{
+        protocol::ServerMessage,
+        test_support::{DUMMY_CHID, DUMMY_UAID},
+    };
+    use autopush_common::notification::Notification;
+    use fernet::{Fernet, MultiFernet};
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use super::{send_event, MessageId};
+
+    fn new_fernet() -> MultiFernet {
+        MultiFernet::new(vec![Fernet::new(&Fernet::generate_key()).unwrap()])
+    }
+
+    #[actix_rt::test]
+    async fn event_format() {
+        let fernet = new_fernet();
+        let uid = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::channel(1);
+        let notif = Notification {
+            channel_id: DUMMY_CHID,
+            version: "foo:bar".to_owned(),
+            ..Default::default()
+        };
+        assert!(send_event(
+            &tx,
+            &fernet,
+            &DUMMY_UAID,
+            &uid,
+            &ServerMessage::Notification(notif)
+        )
+        .await
+        .unwrap());
+        let event = String::from_utf8(rx.recv().await.unwrap().to_vec()).unwrap();
+        let (id, rest) = event
+            .strip_prefix("id: /message/")
+            .and_then(|event| event.split_once('\n'))
+            .unwrap();
+        assert!(rest.starts_with("event: push\ndata: {"));
+        assert!(rest.ends_with("}\n\n"));
+        assert_eq!(
+            MessageId::decrypt(&fernet, id),
+            Some(MessageId {
+                uaid: DUMMY_UAID,
+                uid,
+                channel_id: DUMMY_CHID,
+                version: "foo:bar".to_owned(),
+            })
+        );
+        // Only valid with the server's key
+        assert_eq!(MessageId::decrypt(&new_fernet(), id), None);
+
+        // Non push messages are skipped
+        assert!(send_event(
+            &tx,
+            &fernet,
+            &DUMMY_UAID,
+            &uid,
+            &ServerMessage::Ping { resume_token: None }
+        )
+        .await
+        .unwrap());
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -1,21 +1,26 @@
+use std::sync::Arc;
+
 use actix_http::ws::CloseReason;
 use async_trait::async_trait;
 use mockall::automock;
 
-use autoconnect_common::protocol::ServerMessage;
+use autoconnect_common::protocol::{
+    codec::{Codec, Frame},
+    ServerMessage,
+};
 
 use crate::error::WSError;
 
 /// Trait wrapping [`actix_ws::Session`] so it can be replaced by e.g. a mock.
 ///
 /// This takes `ServerMessage`s and returns `WSErrors` to ease integration and
-/// usage via mocking. `Binary` WebSocket messages aren't supported (as we
-/// don't use them).
+/// usage via mocking. Messages are framed by the negotiated `Codec`.
 #[automock] // must appear before #[async_trait]
 #[async_trait]
 pub trait Session {
-    /// See [`actix_ws::Session::text`]
-    async fn text(&mut self, msg: ServerMessage) -> Result<(), WSError>;
+    /// Send a message as a text or binary frame (per the session's `Codec`).
+    /// See [`actix_ws::Session::text`] and [`actix_ws::Session::binary`]
+    async fn send(&mut self, msg: ServerMessage) -> Result<(), WSError>;
 
     /// See [`actix_ws::Session::ping`]
     async fn ping(&mut self, msg: &[u8]) -> Result<(), WSError>;
@@ -31,18 +36,23 @@ pub trait Session {
 #[derive(Clone)]
 pub struct SessionImpl {
     inner: actix_ws::Session,
+    codec: Arc<dyn Codec>,
 }
 
 impl SessionImpl {
-    pub fn new(inner: actix_ws::Session) -> Self {
-        SessionImpl { inner }
+    pub fn new(inner: actix_ws::Session, codec: Arc<dyn Codec>) -> Self {
+        SessionImpl { inner, codec }
     }
 }
 
 #[async_trait]
 impl Session for SessionImpl {
-    async fn text(&mut self, msg: ServerMessage) -> Result<(), WSError> {
-        Ok(self.inner.text(msg.to_json()?).await?)
+    async fn send(&mut self, msg: ServerMessage) -> Result<(), WSError> {
+        match self.codec.encode(&msg)? {
+            Frame::Text(text) => self.inner.text(text).await?,
+            Frame::Binary(bytes) => self.inner.binary(bytes).await?,
+        }
+        Ok(())
     }
 
     async fn ping(&mut self, msg: &[u8]) -> Result<(), WSError> {
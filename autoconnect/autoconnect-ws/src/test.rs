@@ -4,7 +4,10 @@ use async_stream::stream;
 use futures::pin_mut;
 
 use autoconnect_common::{
-    protocol::ServerMessage,
+    protocol::{
+        codec::{JsonCodec, MessagePackCodec},
+        BroadcastValue, ServerMessage,
+    },
     test_support::{hello_db, HELLO, UA},
 };
 use autoconnect_settings::{AppState, Settings};
@@ -34,7 +37,7 @@ async fn handshake_timeout() {
         yield Ok(actix_ws::Message::Text(HELLO.into()));
     };
     pin_mut!(s);
-    let err = webpush_ws(client, &mut MockSession::new(), s)
+    let err = webpush_ws(client, &JsonCodec, &mut MockSession::new(), s)
         .await
         .unwrap_err();
     assert!(matches!(err.kind, WSErrorKind::HandshakeTimeout));
@@ -48,7 +51,7 @@ async fn basic() {
     });
     let mut session = MockSession::new();
     session
-        .expect_text()
+        .expect_send()
         .times(1)
         .withf(|msg| matches!(msg, ServerMessage::Hello { .. }))
         .return_once(|_| Ok(()));
@@ -58,7 +61,28 @@ async fn basic() {
         Ok(actix_ws::Message::Text(HELLO.into())),
         Ok(actix_ws::Message::Nop),
     ]);
-    webpush_ws(client, &mut session, s)
+    webpush_ws(client, &JsonCodec, &mut session, s)
+        .await
+        .expect("Handler failed");
+}
+
+#[actix_web::test]
+async fn msgpack_hello() {
+    let client = uclient(AppState {
+        db: hello_db().into_boxed_arc(),
+        ..Default::default()
+    });
+    let mut session = MockSession::new();
+    session
+        .expect_send()
+        .times(1)
+        .withf(|msg| matches!(msg, ServerMessage::Hello { .. }))
+        .return_once(|_| Ok(()));
+
+    let hello: serde_json::Value = serde_json::from_str(HELLO).unwrap();
+    let hello = rmp_serde::to_vec_named(&hello).unwrap();
+    let s = futures::stream::iter(vec![Ok(actix_ws::Message::Binary(hello.into()))]);
+    webpush_ws(client, &MessagePackCodec, &mut session, s)
         .await
         .expect("Handler failed");
 }
@@ -74,7 +98,7 @@ async fn websocket_ping() {
         ..AppState::from_settings(settings).unwrap()
     });
     let mut session = MockSession::new();
-    session.expect_text().times(1).return_once(|_| Ok(()));
+    session.expect_send().times(1).return_once(|_| Ok(()));
     session.expect_ping().times(1).return_once(|_| Ok(()));
 
     let s = stream! {
@@ -82,7 +106,100 @@ async fn websocket_ping() {
         tokio::time::sleep(Duration::from_secs_f32(0.2)).await;
     };
     pin_mut!(s);
-    webpush_ws(client, &mut session, s)
+    webpush_ws(client, &JsonCodec, &mut session, s)
+        .await
+        .expect("Handler failed");
+}
+
+#[actix_web::test]
+async fn hibernate_and_wake() {
+    let settings = Settings {
+        hibernate_idle_timeout: Duration::from_secs_f32(0.1),
+        ..Settings::test_settings()
+    };
+    let client = uclient(AppState {
+        db: hello_db().into_boxed_arc(),
+        ..AppState::from_settings(settings).unwrap()
+    });
+    let mut session = MockSession::new();
+    let mut seq = mockall::Sequence::new();
+    session
+        .expect_send()
+        .times(1)
+        .in_sequence(&mut seq)
+        .withf(|msg| matches!(msg, ServerMessage::Hello { .. }))
+        .return_once(|_| Ok(()));
+    // The hibernated Client's woken by its next message
+    session
+        .expect_send()
+        .times(1)
+        .in_sequence(&mut seq)
+        .withf(|msg| matches!(msg, ServerMessage::Ping { .. }))
+        .return_once(|_| Ok(()));
+
+    let s = stream! {
+        yield Ok(actix_ws::Message::Text(HELLO.into()));
+        tokio::time::sleep(Duration::from_secs_f32(0.2)).await;
+        yield Ok(actix_ws::Message::Text("{}".into()));
+    };
+    pin_mut!(s);
+    webpush_ws(client, &JsonCodec, &mut session, s)
+        .await
+        .expect("Handler failed");
+}
+
+#[actix_web::test]
+async fn broadcast_fanout() {
+    let settings = Settings {
+        broadcast_fanout_jitter: Duration::from_secs_f32(0.1),
+        ..Settings::test_settings()
+    };
+    let app_state = AppState {
+        db: hello_db().into_boxed_arc(),
+        ..AppState::from_settings(settings).unwrap()
+    };
+    let broadcaster = Arc::clone(&app_state.broadcaster);
+    broadcaster
+        .write()
+        .await
+        .add_broadcast(("foo/bar".to_owned(), "v1".to_owned()).into());
+    let client = uclient(app_state);
+    let mut session = MockSession::new();
+    let mut seq = mockall::Sequence::new();
+    session
+        .expect_send()
+        .times(1)
+        .in_sequence(&mut seq)
+        .withf(|msg| matches!(msg, ServerMessage::Hello { .. }))
+        .return_once(|_| Ok(()));
+    // Sent well before the next auto Ping
+    session
+        .expect_send()
+        .times(1)
+        .in_sequence(&mut seq)
+        .withf(|msg| match msg {
+            ServerMessage::Broadcast { broadcasts } => {
+                broadcasts.get("foo/bar") == Some(&BroadcastValue::Value("v2".to_owned()))
+            }
+            _ => false,
+        })
+        .return_once(|_| Ok(()));
+    session.expect_ping().never();
+
+    let s = stream! {
+        yield Ok(actix_ws::Message::Text(
+            r#"{"messageType": "hello", "use_webpush": true,
+                "broadcasts": {"foo/bar": "v1"}}"#.into()
+        ));
+        tokio::time::sleep(Duration::from_secs_f32(0.05)).await;
+        broadcaster
+            .write()
+            .await
+            .add_broadcast(("foo/bar".to_owned(), "v2".to_owned()).into());
+        tokio::time::sleep(Duration::from_secs_f32(0.2)).await;
+    };
+    pin_mut!(s);
+    webpush_ws(client, &JsonCodec, &mut session, s)
         .await
         .expect("Handler failed");
 }
@@ -99,7 +216,7 @@ async fn auto_ping_timeout() {
         ..AppState::from_settings(settings).unwrap()
     });
     let mut session = MockSession::new();
-    session.expect_text().times(1).return_once(|_| Ok(()));
+    session.expect_send().times(1).return_once(|_| Ok(()));
     session.expect_ping().times(1).return_once(|_| Ok(()));
 
     let s = stream! {
@@ -107,7 +224,9 @@ async fn auto_ping_timeout() {
         tokio::time::sleep(Duration::from_secs_f32(0.35)).await;
     };
     pin_mut!(s);
-    let err = webpush_ws(client, &mut session, s).await.unwrap_err();
+    let err = webpush_ws(client, &JsonCodec, &mut session, s)
+        .await
+        .unwrap_err();
     assert!(matches!(err.kind, WSErrorKind::PongTimeout));
 }
 
@@ -123,7 +242,7 @@ async fn auto_ping_timeout_after_pong() {
         ..AppState::from_settings(settings).unwrap()
     });
     let mut session = MockSession::new();
-    session.expect_text().times(1).return_once(|_| Ok(()));
+    session.expect_send().times(1).return_once(|_| Ok(()));
     session.expect_ping().times(2).returning(|_| Ok(()));
 
     let s = stream! {
@@ -133,6 +252,8 @@ async fn auto_ping_timeout_after_pong() {
         tokio::time::sleep(Duration::from_secs_f32(0.35)).await;
     };
     pin_mut!(s);
-    let err = webpush_ws(client, &mut session, s).await.unwrap_err();
+    let err = webpush_ws(client, &JsonCodec, &mut session, s)
+        .await
+        .unwrap_err();
     assert!(matches!(err.kind, WSErrorKind::PongTimeout));
 }
@@ -1,6 +1,6 @@
 //! Error types and transformations
 
-use crate::headers::vapid::VapidError;
+use crate::headers::{content_coding::ContentCodingError, vapid::VapidError};
 use crate::routers::RouterError;
 use actix_web::{
     dev::ServiceResponse,
@@ -98,6 +98,10 @@ pub enum ApiErrorKind {
     #[error("{0}")]
     InvalidEncryption(String),
 
+    /// A malformed `aes128gcm` payload
+    #[error(transparent)]
+    InvalidContentCoding(#[from] ContentCodingError),
+
     /// Used if the API version given is not v1 or v2
     #[error("Invalid API version")]
     InvalidApiVersion,
@@ -136,6 +140,7 @@ impl ApiErrorKind {
 
             ApiErrorKind::Validation(_)
             | ApiErrorKind::InvalidEncryption(_)
+            | ApiErrorKind::InvalidContentCoding(_)
             | ApiErrorKind::NoTTL
             | ApiErrorKind::InvalidRouterType
             | ApiErrorKind::InvalidRouterToken
@@ -173,6 +178,7 @@ impl ApiErrorKind {
 
             ApiErrorKind::Validation(_) => "validation",
             ApiErrorKind::InvalidEncryption(_) => "invalid_encryption",
+            ApiErrorKind::InvalidContentCoding(e) => e.metric_label(),
             ApiErrorKind::NoTTL => "no_ttl",
             ApiErrorKind::InvalidRouterType => "invalid_router_type",
             ApiErrorKind::InvalidRouterToken => "invalid_router_token",
@@ -210,6 +216,7 @@ impl ApiErrorKind {
             ApiErrorKind::Database(e) => e.is_sentry_event(),
             // Ignore common webpush errors
             ApiErrorKind::NoTTL | ApiErrorKind::InvalidEncryption(_) |
+            ApiErrorKind::InvalidContentCoding(_) |
             // Ignore common VAPID erros
             ApiErrorKind::VapidError(_)
                 | ApiErrorKind::Jwt(_)
@@ -256,6 +263,8 @@ impl ApiErrorKind {
 
             ApiErrorKind::InvalidEncryption(_) => Some(110),
 
+            ApiErrorKind::InvalidContentCoding(e) => Some(e.errno()),
+
             ApiErrorKind::NoTTL => Some(111),
 
             ApiErrorKind::LogCheck => Some(999),
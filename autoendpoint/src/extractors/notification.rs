@@ -1,11 +1,13 @@
-use crate::error::{ApiError, ApiErrorKind};
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::extractors::{
     message_id::MessageId, notification_headers::NotificationHeaders, subscription::Subscription,
 };
+use crate::headers::content_coding::Aes128GcmHeader;
 use crate::server::AppState;
+use crate::settings::AesGcmPolicy;
 use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
 use autopush_common::util::{b64_encode_url, ms_since_epoch, sec_since_epoch};
-use cadence::CountedExt;
+use cadence::{CountedExt, StatsdClient};
 use fernet::MultiFernet;
 use futures::{future, FutureExt};
 use std::collections::HashMap;
@@ -50,14 +52,20 @@ impl FromRequest for Notification {
                     ApiErrorKind::PayloadError(e)
                 })?;
 
+            let headers = NotificationHeaders::from_request(&req, !data.is_empty())?;
+
+            if !data.is_empty() && headers.encoding.as_deref() == Some("aesgcm") {
+                Self::check_aesgcm_policy(&subscription, &app_state)?;
+            }
+
+            Self::check_content_coding(&data, &headers, &app_state.metrics)?;
+
             // Convert data to base64
             let data = if data.is_empty() {
                 None
             } else {
                 Some(b64_encode_url(&data.to_vec()))
             };
-
-            let headers = NotificationHeaders::from_request(&req, data.is_some())?;
             let timestamp = sec_since_epoch();
             let sort_key_timestamp = ms_since_epoch();
             let message_id = Self::generate_message_id(
@@ -94,6 +102,7 @@ impl FromRequest for Notification {
 impl From<Notification> for autopush_common::notification::Notification {
     fn from(notification: Notification) -> Self {
         let topic = notification.headers.topic.clone();
+        let vapid_sub = notification.subscription.vapid_sub();
         let sortkey_timestamp = topic.is_none().then_some(notification.sort_key_timestamp);
         autopush_common::notification::Notification {
             channel_id: notification.subscription.channel_id,
@@ -111,6 +120,7 @@ impl From<Notification> for autopush_common::notification::Notification {
                     Some(headers)
                 }
             },
+            vapid_sub,
         }
     }
 }
@@ -150,6 +160,52 @@ impl Notification {
         message_id.encrypt(fernet)
     }
 
+    /// The aes128gcm encryption parameters are carried in the payload itself,
+    /// so validate them before storing or routing
+    fn check_content_coding(
+        data: &[u8],
+        headers: &NotificationHeaders,
+        metrics: &StatsdClient,
+    ) -> ApiResult<()> {
+        if data.is_empty() || headers.encoding.as_deref() != Some("aes128gcm") {
+            return Ok(());
+        }
+        Aes128GcmHeader::parse(data).map_err(|e| {
+            metrics
+                .incr_with_tags("notification.bad_content_coding")
+                .with_tag("reason", e.metric_label())
+                .send();
+            ApiErrorKind::InvalidContentCoding(e)
+        })?;
+        Ok(())
+    }
+
+    /// Record the use of the legacy `aesgcm` content encoding and apply the
+    /// configured `aesgcm_policy`
+    fn check_aesgcm_policy(subscription: &Subscription, app_state: &AppState) -> ApiResult<()> {
+        // The VAPID `sub` is normally too high cardinality to tag metrics
+        // with, but the (shrinking) set of aesgcm senders is what we need to
+        // contact to drive their migration
+        let sub = subscription
+            .vapid_sub()
+            .unwrap_or_else(|| "none".to_owned());
+        let policy = app_state.settings.aesgcm_policy;
+        app_state
+            .metrics
+            .incr_with_tags("notification.encoding.deprecated")
+            .with_tag("encoding", "aesgcm")
+            .with_tag("policy", &format!("{policy:?}").to_lowercase())
+            .with_tag("sub", &sub)
+            .send();
+        if policy == AesGcmPolicy::Reject {
+            return Err(ApiErrorKind::InvalidEncryption(
+                "aesgcm Content-Encoding is no longer supported, use aes128gcm".to_owned(),
+            )
+            .into());
+        }
+        Ok(())
+    }
+
     pub fn has_topic(&self) -> bool {
         self.headers.topic.is_some()
     }
@@ -171,6 +227,9 @@ impl Notification {
         map.insert("ttl", serde_json::to_value(self.headers.ttl).unwrap());
         map.insert("topic", serde_json::to_value(&self.headers.topic).unwrap());
         map.insert("timestamp", serde_json::to_value(self.timestamp).unwrap());
+        if let Some(vapid_sub) = self.subscription.vapid_sub() {
+            map.insert("vapid_sub", serde_json::to_value(vapid_sub).unwrap());
+        }
 
         if let Some(data) = &self.data {
             map.insert("data", serde_json::to_value(data).unwrap());
@@ -182,3 +241,72 @@ impl Notification {
         map
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Notification;
+    use crate::error::ApiError;
+    use crate::extractors::notification_headers::NotificationHeaders;
+    use crate::headers::content_coding::tests::make_body;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+    use cadence::{SpyMetricSink, StatsdClient};
+    use std::sync::Arc;
+
+    /// Malformed aes128gcm payloads are rejected with their errno, and
+    /// counted
+    #[actix_rt::test]
+    async fn bad_content_coding() {
+        let (metrics, sink) = SpyMetricSink::new();
+        let client = Arc::new(StatsdClient::from_sink("autopush", sink));
+        let app = test::init_service(App::new().route(
+            "/",
+            web::post().to(move |req: HttpRequest, body: web::Bytes| {
+                let client = client.clone();
+                async move {
+                    let headers = NotificationHeaders::from_request(&req, !body.is_empty())?;
+                    Notification::check_content_coding(&body, &headers, &client)?;
+                    Ok::<_, ApiError>(HttpResponse::Ok().finish())
+                }
+            }),
+        ))
+        .await;
+
+        for (body, errno, reason) in [
+            (
+                make_body(4096, 65, 65, 100)[..20].to_vec(),
+                115,
+                "truncated_header",
+            ),
+            (make_body(17, 65, 65, 100), 116, "invalid_record_size"),
+            (make_body(8192, 65, 65, 4097), 118, "record_too_large"),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/")
+                .insert_header(("TTL", "60"))
+                .insert_header(("Content-Encoding", "aes128gcm"))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+            let json: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(json["errno"], errno);
+
+            let metric = String::from_utf8(metrics.try_recv().unwrap()).unwrap();
+            assert_eq!(
+                metric,
+                format!("autopush.notification.bad_content_coding:1|c|#reason:{reason}")
+            );
+        }
+
+        // Well formed payloads pass
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header(("TTL", "60"))
+            .insert_header(("Content-Encoding", "aes128gcm"))
+            .set_payload(make_body(4096, 65, 65, 100))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+        assert!(metrics.try_recv().is_err());
+    }
+}
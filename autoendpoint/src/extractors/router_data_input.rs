@@ -19,6 +19,8 @@ pub struct RouterDataInput {
     pub channel_id: Option<Uuid>,
     pub key: Option<String>,
     pub aps: Option<String>,
+    /// A secret to sign requests to the user with, for routers supporting it
+    pub secret: Option<String>,
 }
 
 impl FromRequest for RouterDataInput {
@@ -38,9 +40,12 @@ impl FromRequest for RouterDataInput {
             // Validate the token according to each router's token schema
             let is_valid = match path_args.router_type {
                 RouterType::WebPush => true,
-                RouterType::FCM | RouterType::GCM | RouterType::APNS => {
-                    VALID_TOKEN.is_match(&data.token)
-                }
+                RouterType::FCM
+                | RouterType::GCM
+                | RouterType::APNS
+                | RouterType::HMS
+                | RouterType::UnifiedPush
+                | RouterType::Webhook => VALID_TOKEN.is_match(&data.token),
                 #[cfg(feature = "stub")]
                 RouterType::STUB => data.token.as_str() == "success",
             };
@@ -1,8 +1,11 @@
 use crate::error::{ApiError, ApiResult};
 use crate::routers::apns::router::ApnsRouter;
 use crate::routers::fcm::router::FcmRouter;
+use crate::routers::hms::router::HmsRouter;
 #[cfg(feature = "stub")]
 use crate::routers::stub::router::StubRouter;
+use crate::routers::unifiedpush::router::UnifiedPushRouter;
+use crate::routers::webhook::router::WebhookRouter;
 use crate::routers::webpush::WebPushRouter;
 use crate::routers::Router;
 use crate::server::AppState;
@@ -14,13 +17,16 @@ use std::fmt::{self, Display};
 use std::str::FromStr;
 use std::sync::Arc;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum RouterType {
     WebPush,
     FCM,
     GCM,
     APNS,
+    HMS,
+    UnifiedPush,
+    Webhook,
     #[cfg(feature = "stub")]
     STUB,
 }
@@ -34,6 +40,9 @@ impl FromStr for RouterType {
             "fcm" => Ok(RouterType::FCM),
             "gcm" => Ok(RouterType::GCM),
             "apns" => Ok(RouterType::APNS),
+            "hms" => Ok(RouterType::HMS),
+            "unifiedpush" => Ok(RouterType::UnifiedPush),
+            "webhook" => Ok(RouterType::Webhook),
             #[cfg(feature = "stub")]
             "stub" => Ok(RouterType::STUB),
             _ => Err(()),
@@ -48,6 +57,9 @@ impl Display for RouterType {
             RouterType::FCM => "fcm",
             RouterType::GCM => "gcm",
             RouterType::APNS => "apns",
+            RouterType::HMS => "hms",
+            RouterType::UnifiedPush => "unifiedpush",
+            RouterType::Webhook => "webhook",
             #[cfg(feature = "stub")]
             RouterType::STUB => "stub",
         })
@@ -60,6 +72,9 @@ pub struct Routers {
     webpush: WebPushRouter,
     fcm: Arc<FcmRouter>,
     apns: Arc<ApnsRouter>,
+    hms: Arc<HmsRouter>,
+    unifiedpush: Arc<UnifiedPushRouter>,
+    webhook: Arc<WebhookRouter>,
     #[cfg(feature = "stub")]
     stub: Arc<StubRouter>,
 }
@@ -73,28 +88,41 @@ impl FromRequest for Routers {
             .into_inner()
             .expect("No server state found");
 
-        future::ok(Routers {
+        future::ok(Routers::new(&app_state))
+    }
+}
+
+impl Routers {
+    /// Build the routers from the server state
+    pub fn new(app_state: &AppState) -> Self {
+        Routers {
             webpush: WebPushRouter {
                 db: app_state.db.clone(),
                 metrics: app_state.metrics.clone(),
                 http: app_state.http.clone(),
                 endpoint_url: app_state.settings.endpoint_url(),
+                router_auth_key: app_state.router_auth_key.clone(),
+                breakers: app_state.breakers.clone(),
             },
             fcm: app_state.fcm_router.clone(),
             apns: app_state.apns_router.clone(),
+            hms: app_state.hms_router.clone(),
+            unifiedpush: app_state.unifiedpush_router.clone(),
+            webhook: app_state.webhook_router.clone(),
             #[cfg(feature = "stub")]
             stub: app_state.stub_router.clone(),
-        })
+        }
     }
-}
 
-impl Routers {
     /// Get the router which handles the router type
     pub fn get(&self, router_type: RouterType) -> &dyn Router {
         match router_type {
             RouterType::WebPush => &self.webpush,
             RouterType::FCM | RouterType::GCM => self.fcm.as_ref(),
             RouterType::APNS => self.apns.as_ref(),
+            RouterType::HMS => self.hms.as_ref(),
+            RouterType::UnifiedPush => self.unifiedpush.as_ref(),
+            RouterType::Webhook => self.webhook.as_ref(),
             #[cfg(feature = "stub")]
             RouterType::STUB => self.stub.as_ref(),
         }
@@ -43,6 +43,16 @@ pub struct Subscription {
     pub tracking_id: Option<String>,
 }
 
+impl Subscription {
+    /// The `sub` claim of the VAPID authorization, if any
+    pub fn vapid_sub(&self) -> Option<String> {
+        self.vapid
+            .as_ref()
+            .and_then(|vapid| vapid.vapid.claims().ok())
+            .map(|claims| claims.sub)
+    }
+}
+
 impl FromRequest for Subscription {
     type Error = ApiError;
     type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
@@ -0,0 +1,203 @@
+/// Parses the `aes128gcm` content-coding header that prefixes an encrypted
+/// payload, as described by
+/// `https://datatracker.ietf.org/doc/html/rfc8188#section-2.1`
+///
+/// ```text
+/// +-----------+--------+-----------+---------------+
+/// | salt (16) | rs (4) | idlen (1) | keyid (idlen) |
+/// +-----------+--------+-----------+---------------+
+/// ```
+///
+/// WebPush (RFC 8291) further requires the message to be encrypted as a
+/// single record, with `keyid` holding the application server's uncompressed
+/// ECDH public key.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Aes128GcmHeader {
+    pub salt: [u8; SALT_LENGTH],
+    pub rs: u32,
+    pub keyid: Vec<u8>,
+}
+
+/// The length of the salt
+const SALT_LENGTH: usize = 16;
+/// The length of the fixed portion of the header (salt, rs and idlen)
+const FIXED_HEADER_LENGTH: usize = SALT_LENGTH + 4 + 1;
+/// The length of the AEAD_AES_128_GCM authentication tag
+const TAG_LENGTH: usize = 16;
+/// The smallest permitted record size: the tag plus a padding delimiter
+/// octet (RFC 8188 section 2.1)
+const MIN_RECORD_SIZE: u32 = TAG_LENGTH as u32 + 2;
+/// The length of an uncompressed P-256 public key, the largest `keyid`
+/// WebPush permits (RFC 8291 section 4)
+const MAX_KEYID_LENGTH: usize = 65;
+/// The largest WebPush record: push services need only accept 4096 byte
+/// payloads (RFC 8030 section 7.2), so user agents need not decrypt larger
+/// records
+pub const MAX_RECORD_LENGTH: usize = 4096;
+
+/// Reasons an `aes128gcm` payload was rejected
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum ContentCodingError {
+    #[error("Truncated aes128gcm content-coding header")]
+    TruncatedHeader,
+
+    #[error("Invalid aes128gcm record size {0}")]
+    InvalidRecordSize(u32),
+
+    #[error("Invalid aes128gcm keyid length {0}")]
+    InvalidKeyIdLength(usize),
+
+    #[error("aes128gcm record of {0} bytes exceeds the 4096 byte maximum")]
+    RecordTooLarge(usize),
+}
+
+impl ContentCodingError {
+    /// Get the associated error number
+    pub fn errno(&self) -> usize {
+        match self {
+            ContentCodingError::TruncatedHeader => 115,
+            ContentCodingError::InvalidRecordSize(_) => 116,
+            ContentCodingError::InvalidKeyIdLength(_) => 117,
+            ContentCodingError::RecordTooLarge(_) => 118,
+        }
+    }
+
+    /// Specify the label to use for metrics reporting.
+    pub fn metric_label(&self) -> &'static str {
+        match self {
+            ContentCodingError::TruncatedHeader => "truncated_header",
+            ContentCodingError::InvalidRecordSize(_) => "invalid_record_size",
+            ContentCodingError::InvalidKeyIdLength(_) => "invalid_keyid_length",
+            ContentCodingError::RecordTooLarge(_) => "record_too_large",
+        }
+    }
+}
+
+impl Aes128GcmHeader {
+    /// Parse and validate the header of an `aes128gcm` encoded `body`.
+    ///
+    /// The (single) record following the header may not exceed
+    /// [MAX_RECORD_LENGTH]. A larger declared record size is valid, as the
+    /// record may be shorter than it.
+    pub fn parse(body: &[u8]) -> Result<Self, ContentCodingError> {
+        if body.len() < FIXED_HEADER_LENGTH {
+            return Err(ContentCodingError::TruncatedHeader);
+        }
+        let (salt, rest) = body.split_at(SALT_LENGTH);
+        let (rs, rest) = rest.split_at(4);
+        let (idlen, rest) = rest.split_at(1);
+        let rs = u32::from_be_bytes(rs.try_into().expect("rs is 4 bytes"));
+        let idlen = idlen[0] as usize;
+
+        if rs < MIN_RECORD_SIZE {
+            return Err(ContentCodingError::InvalidRecordSize(rs));
+        }
+        if idlen > MAX_KEYID_LENGTH {
+            return Err(ContentCodingError::InvalidKeyIdLength(idlen));
+        }
+        if rest.len() < idlen {
+            return Err(ContentCodingError::TruncatedHeader);
+        }
+        let (keyid, record) = rest.split_at(idlen);
+
+        // The record must at least hold the padding delimiter and tag
+        if record.len() <= TAG_LENGTH {
+            return Err(ContentCodingError::TruncatedHeader);
+        }
+        if record.len() > MAX_RECORD_LENGTH {
+            return Err(ContentCodingError::RecordTooLarge(record.len()));
+        }
+        // A record larger than `rs` means the payload spans multiple records
+        if record.len() > rs as usize {
+            return Err(ContentCodingError::InvalidRecordSize(rs));
+        }
+
+        Ok(Self {
+            salt: salt.try_into().expect("salt is 16 bytes"),
+            rs,
+            keyid: keyid.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Aes128GcmHeader, ContentCodingError, MAX_RECORD_LENGTH};
+
+    /// Build a payload with the given header values and record length
+    pub fn make_body(rs: u32, idlen: u8, keyid_len: usize, record_len: usize) -> Vec<u8> {
+        let mut body = vec![7u8; 16];
+        body.extend(rs.to_be_bytes());
+        body.push(idlen);
+        body.extend(vec![4u8; keyid_len]);
+        body.extend(vec![0u8; record_len]);
+        body
+    }
+
+    #[test]
+    fn parse_succeeds() {
+        let body = make_body(4096, 65, 65, 100);
+        let header = Aes128GcmHeader::parse(&body).unwrap();
+        assert_eq!(header.salt, [7u8; 16]);
+        assert_eq!(header.rs, 4096);
+        assert_eq!(header.keyid, vec![4u8; 65]);
+    }
+
+    #[test]
+    fn truncated_header() {
+        let body = make_body(4096, 65, 65, 100);
+        assert_eq!(
+            Aes128GcmHeader::parse(&body[..20]),
+            Err(ContentCodingError::TruncatedHeader)
+        );
+        // keyid cut short
+        assert_eq!(
+            Aes128GcmHeader::parse(&body[..50]),
+            Err(ContentCodingError::TruncatedHeader)
+        );
+        // no room for the tag
+        let body = make_body(4096, 65, 65, 16);
+        assert_eq!(
+            Aes128GcmHeader::parse(&body),
+            Err(ContentCodingError::TruncatedHeader)
+        );
+    }
+
+    #[test]
+    fn invalid_record_size() {
+        let body = make_body(17, 65, 65, 100);
+        assert_eq!(
+            Aes128GcmHeader::parse(&body),
+            Err(ContentCodingError::InvalidRecordSize(17))
+        );
+        // Multiple records
+        let body = make_body(50, 65, 65, 100);
+        assert_eq!(
+            Aes128GcmHeader::parse(&body),
+            Err(ContentCodingError::InvalidRecordSize(50))
+        );
+    }
+
+    #[test]
+    fn oversize_keyid() {
+        let body = make_body(4096, 66, 66, 100);
+        assert_eq!(
+            Aes128GcmHeader::parse(&body),
+            Err(ContentCodingError::InvalidKeyIdLength(66))
+        );
+    }
+
+    #[test]
+    fn record_too_large() {
+        // Only the actual record length is bounded
+        let body = make_body(8192, 65, 65, 100);
+        assert!(Aes128GcmHeader::parse(&body).is_ok());
+        let body = make_body(8192, 65, 65, MAX_RECORD_LENGTH);
+        assert!(Aes128GcmHeader::parse(&body).is_ok());
+        let body = make_body(8192, 65, 65, MAX_RECORD_LENGTH + 1);
+        assert_eq!(
+            Aes128GcmHeader::parse(&body),
+            Err(ContentCodingError::RecordTooLarge(MAX_RECORD_LENGTH + 1))
+        );
+    }
+}
@@ -1,3 +1,4 @@
+pub mod content_coding;
 pub mod crypto_key;
 pub mod util;
 pub mod vapid;
@@ -0,0 +1,380 @@
+//! Circuit breakers around bridge and autoconnect node deliveries
+//!
+//! A degraded upstream would otherwise make every request wait out its full
+//! timeout. Each FCM project, APNs channel and autoconnect node has its own
+//! breaker, which opens once the ratio of transient failures in a window
+//! exceeds the configured threshold. While open, bridged notifications fail
+//! fast and WebPush notifications go straight to storage. After `open_secs` a
+//! single trial request is let through, closing the breaker again if it
+//! succeeds.
+
+pub mod settings;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use autopush_common::util::sec_since_epoch;
+use cadence::{CountedExt, StatsdClient};
+use serde::Serialize;
+
+use crate::routers::breaker::settings::BreakerSettings;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Requests are let through, counting failures
+    Closed,
+    /// Requests fail fast
+    Open,
+    /// A trial request is let through to probe the upstream
+    HalfOpen,
+}
+
+struct Breaker {
+    state: BreakerState,
+    /// When (in seconds since the epoch) the current window started, or the
+    /// breaker last opened or let a trial request through
+    since: u64,
+    requests: u32,
+    failures: u32,
+}
+
+impl Breaker {
+    fn new(now: u64) -> Self {
+        Breaker {
+            state: BreakerState::Closed,
+            since: now,
+            requests: 0,
+            failures: 0,
+        }
+    }
+}
+
+/// The circuit breakers, keyed by the upstream they guard (e.g.
+/// "fcm:<project ID>", "apns:<channel>" or "webpush:<node ID>")
+pub struct CircuitBreakers {
+    settings: BreakerSettings,
+    metrics: Arc<StatsdClient>,
+    /// Only upstreams with recent failures have a breaker, closed ones
+    /// expiring once their window passes
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+impl CircuitBreakers {
+    /// Create a new `CircuitBreakers`
+    pub fn new(settings: BreakerSettings, metrics: Arc<StatsdClient>) -> Self {
+        Self {
+            settings,
+            metrics,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether a request to the upstream may be made. Every allowed
+    /// request's outcome must be passed to `record`.
+    pub fn allow(&self, key: &str) -> bool {
+        let allowed = self.allow_at(key, sec_since_epoch());
+        if !allowed {
+            self.incr_metric("circuit_breaker.rejected", key);
+        }
+        allowed
+    }
+
+    /// Record the outcome of a request to the upstream. Only transient
+    /// failures (timeouts, connection errors and 5xx responses) count against
+    /// it.
+    pub fn record(&self, key: &str, success: bool) {
+        if let Some(state) = self.record_at(key, success, sec_since_epoch()) {
+            match state {
+                BreakerState::Open => warn!("Circuit breaker for {} opened", key),
+                _ => info!("Circuit breaker for {} closed", key),
+            }
+            self.incr_metric(
+                match state {
+                    BreakerState::Open => "circuit_breaker.opened",
+                    _ => "circuit_breaker.closed",
+                },
+                key,
+            );
+        }
+    }
+
+    /// The number of breakers in each state for each platform, for
+    /// reporting. (Individual upstreams, e.g. node IDs, aren't exposed.)
+    pub fn counts(&self) -> HashMap<String, HashMap<BreakerState, usize>> {
+        self.counts_at(sec_since_epoch())
+    }
+
+    fn counts_at(&self, now: u64) -> HashMap<String, HashMap<BreakerState, usize>> {
+        let breakers = self.breakers.lock().unwrap();
+        let mut counts: HashMap<String, HashMap<BreakerState, usize>> = HashMap::new();
+        for (key, breaker) in breakers.iter().filter(|(_, b)| !self.expired(b, now)) {
+            *counts
+                .entry(platform(key).to_owned())
+                .or_default()
+                .entry(breaker.state)
+                .or_default() += 1;
+        }
+        counts
+    }
+
+    /// Whether a breaker is closed and its window passed without further
+    /// requests. Open breakers are kept, for `allow_at` to let a trial
+    /// request through.
+    fn expired(&self, breaker: &Breaker, now: u64) -> bool {
+        breaker.state == BreakerState::Closed && now >= breaker.since + self.settings.window_secs
+    }
+
+    fn allow_at(&self, key: &str, now: u64) -> bool {
+        if !self.settings.enabled {
+            return true;
+        }
+        let mut breakers = self.breakers.lock().unwrap();
+        let Some(breaker) = breakers.get_mut(key) else {
+            return true;
+        };
+        match breaker.state {
+            BreakerState::Closed => true,
+            // Let one trial request through per `open_secs`, in case an
+            // earlier one's outcome was never recorded
+            BreakerState::Open | BreakerState::HalfOpen => {
+                if now < breaker.since + self.settings.open_secs {
+                    return false;
+                }
+                breaker.state = BreakerState::HalfOpen;
+                breaker.since = now;
+                true
+            }
+        }
+    }
+
+    /// Returns the breaker's new state, if it opened or closed
+    fn record_at(&self, key: &str, success: bool, now: u64) -> Option<BreakerState> {
+        if !self.settings.enabled {
+            return None;
+        }
+        let mut breakers = self.breakers.lock().unwrap();
+        if !breakers.contains_key(key) {
+            if success {
+                return None;
+            }
+            // Upstreams which failed once are rarely requested again (e.g.
+            // nodes which have gone away), so clear out stale breakers as
+            // new ones are added
+            breakers.retain(|_, breaker| !self.expired(breaker, now));
+        }
+        let breaker = breakers
+            .entry(key.to_owned())
+            .or_insert_with(|| Breaker::new(now));
+        match breaker.state {
+            BreakerState::HalfOpen => {
+                if success {
+                    breakers.remove(key);
+                    return Some(BreakerState::Closed);
+                }
+                breaker.state = BreakerState::Open;
+                breaker.since = now;
+                return Some(BreakerState::Open);
+            }
+            // A request allowed before the breaker opened
+            BreakerState::Open => return None,
+            BreakerState::Closed => {}
+        }
+        if now >= breaker.since + self.settings.window_secs {
+            if success {
+                // Forget upstreams which have recovered
+                breakers.remove(key);
+                return None;
+            }
+            *breaker = Breaker::new(now);
+        }
+        breaker.requests += 1;
+        if !success {
+            breaker.failures += 1;
+        }
+        if breaker.requests >= self.settings.min_requests
+            && breaker.failures as f64 >= breaker.requests as f64 * self.settings.failure_ratio
+        {
+            breaker.state = BreakerState::Open;
+            breaker.since = now;
+            return Some(BreakerState::Open);
+        }
+        None
+    }
+
+    fn incr_metric(&self, name: &str, key: &str) {
+        // Node IDs are too many to tag with, so only tag the platform
+        self.metrics
+            .incr_with_tags(name)
+            .with_tag("platform", platform(key))
+            .send();
+    }
+}
+
+/// The platform of a breaker's upstream, e.g. "fcm" for "fcm:<project ID>"
+fn platform(key: &str) -> &str {
+    key.split(':').next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BreakerState, CircuitBreakers};
+    use crate::routers::breaker::settings::BreakerSettings;
+    use cadence::StatsdClient;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    const KEY: &str = "fcm:test-project";
+
+    fn make_breakers() -> CircuitBreakers {
+        CircuitBreakers::new(
+            BreakerSettings {
+                enabled: true,
+                window_secs: 60,
+                min_requests: 4,
+                failure_ratio: 0.5,
+                open_secs: 30,
+            },
+            Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+        )
+    }
+
+    fn state(breakers: &CircuitBreakers) -> Option<BreakerState> {
+        breakers
+            .breakers
+            .lock()
+            .unwrap()
+            .get(KEY)
+            .map(|breaker| breaker.state)
+    }
+
+    /// The breaker opens once enough requests in the window fail
+    #[test]
+    fn opens_on_failures() {
+        let breakers = make_breakers();
+        assert_eq!(breakers.record_at(KEY, true, 100), None);
+        assert_eq!(breakers.record_at(KEY, false, 100), None);
+        assert_eq!(breakers.record_at(KEY, true, 101), None);
+        assert_eq!(breakers.record_at(KEY, true, 101), None);
+        assert!(breakers.allow_at(KEY, 102));
+        assert_eq!(
+            breakers.record_at(KEY, false, 102),
+            Some(BreakerState::Open)
+        );
+        assert!(!breakers.allow_at(KEY, 103));
+        assert!(breakers.allow_at("fcm:other-project", 103));
+        assert_eq!(state(&breakers), Some(BreakerState::Open));
+    }
+
+    /// Failures in an earlier window aren't counted
+    #[test]
+    fn window_resets() {
+        let breakers = make_breakers();
+        for _ in 0..3 {
+            breakers.record_at(KEY, false, 100);
+        }
+        assert_eq!(breakers.record_at(KEY, false, 160), None);
+        assert_eq!(breakers.record_at(KEY, true, 220), None);
+        assert_eq!(state(&breakers), None);
+    }
+
+    /// An open breaker lets a trial request through after `open_secs`,
+    /// closing if it succeeds
+    #[test]
+    fn half_open_trial() {
+        let breakers = make_breakers();
+        for _ in 0..4 {
+            breakers.record_at(KEY, false, 100);
+        }
+        assert!(!breakers.allow_at(KEY, 129));
+        assert!(breakers.allow_at(KEY, 130));
+        assert_eq!(state(&breakers), Some(BreakerState::HalfOpen));
+        // Only the one trial request
+        assert!(!breakers.allow_at(KEY, 131));
+        assert_eq!(
+            breakers.record_at(KEY, false, 131),
+            Some(BreakerState::Open)
+        );
+        assert!(!breakers.allow_at(KEY, 160));
+        assert!(breakers.allow_at(KEY, 161));
+        assert_eq!(
+            breakers.record_at(KEY, true, 162),
+            Some(BreakerState::Closed)
+        );
+        assert!(breakers.allow_at(KEY, 162));
+        assert_eq!(state(&breakers), None);
+    }
+
+    #[test]
+    fn disabled() {
+        let breakers = CircuitBreakers::new(
+            BreakerSettings::default(),
+            Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+        );
+        for _ in 0..100 {
+            breakers.record(KEY, false);
+        }
+        assert!(breakers.allow(KEY));
+        assert_eq!(state(&breakers), None);
+    }
+
+    /// Only per-platform counts are reported, and closed breakers expire
+    /// once their window passes
+    #[test]
+    fn counts_and_expiry() {
+        let breakers = make_breakers();
+        for _ in 0..4 {
+            breakers.record_at(KEY, false, 100);
+        }
+        breakers.record_at("webpush:http://node-1", false, 100);
+        breakers.record_at("webpush:http://node-2", false, 120);
+        assert_eq!(
+            breakers.counts_at(125),
+            HashMap::from([
+                ("fcm".to_owned(), HashMap::from([(BreakerState::Open, 1)])),
+                (
+                    "webpush".to_owned(),
+                    HashMap::from([(BreakerState::Closed, 2)])
+                ),
+            ])
+        );
+        // The first node's window passed, though the open breaker is kept
+        assert_eq!(
+            breakers.counts_at(160),
+            HashMap::from([
+                ("fcm".to_owned(), HashMap::from([(BreakerState::Open, 1)])),
+                (
+                    "webpush".to_owned(),
+                    HashMap::from([(BreakerState::Closed, 1)])
+                ),
+            ])
+        );
+        // Reporting doesn't remove it, adding another breaker does
+        assert_eq!(breakers.breakers.lock().unwrap().len(), 3);
+        breakers.record_at("webpush:http://node-3", false, 160);
+        assert_eq!(breakers.breakers.lock().unwrap().len(), 3);
+        assert!(!breakers
+            .breakers
+            .lock()
+            .unwrap()
+            .contains_key("webpush:http://node-1"));
+    }
+
+    /// Reporting after the open period doesn't affect the breaker, which
+    /// still lets a single trial request through
+    #[test]
+    fn counts_leave_open_breakers() {
+        let breakers = make_breakers();
+        for _ in 0..4 {
+            breakers.record_at(KEY, false, 100);
+        }
+        assert_eq!(
+            breakers.counts_at(200),
+            HashMap::from([("fcm".to_owned(), HashMap::from([(BreakerState::Open, 1)]))])
+        );
+        assert_eq!(state(&breakers), Some(BreakerState::Open));
+        assert!(breakers.allow_at(KEY, 200));
+        assert!(!breakers.allow_at(KEY, 201));
+        assert_eq!(state(&breakers), Some(BreakerState::HalfOpen));
+    }
+}
@@ -0,0 +1,31 @@
+/// Settings for the bridge and node `CircuitBreakers`
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct BreakerSettings {
+    /// Whether deliveries to a failing FCM project, APNs channel or
+    /// autoconnect node are cut short while its breaker is open
+    pub enabled: bool,
+    /// The length (in seconds) of the window failures are counted over
+    pub window_secs: u64,
+    /// The minimum number of requests in a window before its breaker may open
+    pub min_requests: u32,
+    /// The ratio of failed requests in a window (from 0.0 to 1.0) which opens
+    /// the breaker
+    pub failure_ratio: f64,
+    /// How long (in seconds) an open breaker fails fast before letting a
+    /// trial request through
+    pub open_secs: u64,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_secs: 60,
+            min_requests: 20,
+            failure_ratio: 0.5,
+            open_secs: 30,
+        }
+    }
+}
@@ -7,6 +7,8 @@ use autopush_common::db::client::DbClient;
 use autopush_common::util::InsertOpt;
 use cadence::{Counted, CountedExt, StatsdClient, Timed};
 use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use url::Url;
 use uuid::Uuid;
 
 /// Convert a notification into a WebPush message
@@ -37,6 +39,95 @@ pub fn message_size_check(data: &[u8], max_data: usize) -> Result<(), RouterErro
     }
 }
 
+/// The default JSON list of schemes registered endpoints may use
+pub const DEFAULT_ALLOWED_SCHEMES: &str = r#"["https"]"#;
+
+/// The default JSON list of hosts registered endpoints may be on: none
+pub const DEFAULT_ALLOWED_HOSTS: &str = "[]";
+
+/// The schemes and hosts the endpoints registered with a router (e.g.
+/// UnifiedPush distributors or webhooks) may use. A host starting with `.`
+/// also allows its subdomains, while `*` allows any host.
+#[derive(Clone, Debug, Default)]
+pub struct EndpointAllowlist {
+    schemes: Vec<String>,
+    hosts: Vec<String>,
+}
+
+impl EndpointAllowlist {
+    /// Read the allowlist from JSON lists of schemes and hosts
+    pub fn from_json(schemes: &str, hosts: &str) -> serde_json::Result<Self> {
+        Ok(Self {
+            schemes: serde_json::from_str(schemes)?,
+            hosts: serde_json::from_str(hosts)?,
+        })
+    }
+
+    /// if we allow any hosts, the router is "active"
+    pub fn active(&self) -> bool {
+        !self.hosts.is_empty()
+    }
+
+    /// Parse an endpoint URL, checking it uses one of the allowed schemes and
+    /// is on one of the allowed hosts. Returns the reason for rejecting the
+    /// endpoint otherwise.
+    ///
+    /// Endpoints are checked at registration, and again before each send, as
+    /// the allowlist may have changed since.
+    pub fn check(&self, endpoint: &str) -> Result<Url, String> {
+        let url = Url::parse(endpoint).map_err(|e| e.to_string())?;
+        if !self.schemes.iter().any(|s| s == url.scheme()) {
+            return Err(format!("scheme {} is not allowed", url.scheme()));
+        }
+        let host = url.host_str().ok_or("missing host")?;
+        let allowed = self.hosts.iter().any(|allowed| {
+            allowed == "*"
+                || allowed == host
+                || (allowed.starts_with('.') && host.ends_with(allowed.as_str()))
+        });
+        if !allowed {
+            return Err(format!("host {host} is not allowed"));
+        }
+        Ok(url)
+    }
+}
+
+/// Build the HTTP client for sending to allowlisted endpoints. Redirects
+/// aren't followed, as they could lead outside the allowed hosts.
+pub fn endpoint_http_client(timeout: usize) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(Duration::from_secs(timeout as u64))
+        .build()
+}
+
+/// Send a request to an allowlisted endpoint, mapping its response status to
+/// the routing result. `data_len` is reported if the endpoint finds the
+/// request too large.
+pub async fn send_to_endpoint(
+    request: reqwest::RequestBuilder,
+    data_len: usize,
+) -> Result<(), RouterError> {
+    let response = request.send().await.map_err(|e| {
+        if e.is_timeout() {
+            RouterError::RequestTimeout
+        } else {
+            RouterError::Connect(e)
+        }
+    })?;
+
+    let status = response.status();
+    match status {
+        _ if status.is_success() => Ok(()),
+        reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE => Err(RouterError::NotFound),
+        reqwest::StatusCode::PAYLOAD_TOO_LARGE => Err(RouterError::TooMuchData(data_len)),
+        _ => Err(RouterError::Upstream {
+            status: status.to_string(),
+            message: response.text().await.unwrap_or_default(),
+        }),
+    }
+}
+
 /// Handle a bridge error by logging, updating metrics, etc
 pub async fn handle_error(
     error: RouterError,
@@ -119,6 +210,17 @@ pub async fn handle_error(
                 error.errno(),
             );
         }
+        RouterError::CircuitOpen => {
+            debug!("Bridge circuit breaker is open");
+            incr_error_metric(
+                metrics,
+                platform,
+                app_id,
+                "circuit_open",
+                error.status(),
+                error.errno(),
+            );
+        }
         RouterError::TooMuchData(_) => {
             // Do not log this error since it's fairly common.
             incr_error_metric(
@@ -153,6 +255,24 @@ pub async fn handle_error(
     err
 }
 
+/// The modification times of credential files, to tell when they change
+pub fn file_stamps(paths: &[&str]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Increment `notification.bridge.credentials.reload`
+pub fn incr_reload_metric(metrics: &StatsdClient, platform: &str, app_id: &str, result: &str) {
+    metrics
+        .incr_with_tags("notification.bridge.credentials.reload")
+        .with_tag("platform", platform)
+        .with_tag("app_id", app_id)
+        .with_tag("result", result)
+        .send();
+}
+
 /// Increment `notification.bridge.error`
 pub fn incr_error_metric(
     metrics: &StatsdClient,
@@ -16,6 +16,7 @@ const OAUTH_SCOPES: &[&str] = &["https://www.googleapis.com/auth/firebase.messag
 /// Holds application-specific Firebase data and authentication. This client
 /// handles sending notifications to Firebase.
 pub struct FcmClient {
+    project_id: String,
     endpoint: Url,
     timeout: Duration,
     max_data: usize,
@@ -24,11 +25,12 @@ pub struct FcmClient {
 }
 
 impl FcmClient {
-    /// Create an `FcmClient` using the provided credential
+    /// Create an `FcmClient` using the provided credential, sending over the
+    /// project's HTTP client
     pub async fn new(
         settings: &FcmSettings,
         server_credential: FcmServerCredential,
-        http: reqwest::Client,
+        http_client: reqwest::Client,
     ) -> std::io::Result<Self> {
         // `map`ping off of `serde_json::from_str` gets hairy and weird, requiring
         // async blocks and a number of other specialty items. Doing a very stupid
@@ -66,6 +68,7 @@ impl FcmClient {
             }
         };
         Ok(FcmClient {
+            project_id: server_credential.project_id.clone(),
             endpoint: settings
                 .base_url
                 .join(&format!(
@@ -76,16 +79,36 @@ impl FcmClient {
             timeout: Duration::from_secs(settings.timeout as u64),
             max_data: settings.max_data,
             authenticator: auth,
-            http_client: http,
+            http_client,
         })
     }
 
+    /// Start building the HTTP client for a single project, multiplexing its
+    /// requests over long-lived HTTP/2 connections
+    pub fn http_client(settings: &FcmSettings) -> reqwest::ClientBuilder {
+        reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(settings.timeout as u64))
+            .pool_idle_timeout(Duration::from_secs(settings.pool_idle_timeout))
+            .http2_adaptive_window(true)
+            .http2_keep_alive_interval(Duration::from_secs(settings.keep_alive_interval))
+            .http2_keep_alive_while_idle(true)
+    }
+
+    /// The FCM project messages are sent to
+    pub fn project_id(&self) -> &str {
+        &self.project_id
+    }
+
     /// Send the message data to FCM
+    ///
+    /// A `collapse_key` replaces any undelivered message sent with the same
+    /// key
     pub async fn send(
         &self,
         data: HashMap<&'static str, String>,
         routing_token: String,
         ttl: u64,
+        collapse_key: Option<&str>,
     ) -> Result<(), RouterError> {
         // Check the payload size. FCM only cares about the `data` field when
         // checking size.
@@ -93,13 +116,17 @@ impl FcmClient {
         message_size_check(data_json.as_bytes(), self.max_data)?;
 
         // Build the FCM message
+        let mut android = serde_json::json!({
+            "ttl": format!("{ttl}s"),
+            "data": data
+        });
+        if let Some(collapse_key) = collapse_key {
+            android["collapse_key"] = collapse_key.into();
+        }
         let message = serde_json::json!({
             "message": {
                 "token": routing_token,
-                "android": {
-                    "ttl": format!("{ttl}s"),
-                    "data": data
-                }
+                "android": android
             }
         });
 
@@ -181,7 +208,12 @@ pub mod tests {
     use crate::routers::fcm::client::FcmClient;
     use crate::routers::fcm::settings::{FcmServerCredential, FcmSettings};
     use crate::routers::RouterError;
+    use futures::future::join_all;
     use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::copy_bidirectional;
+    use tokio::net::{TcpListener, TcpStream};
     use url::Url;
 
     pub const PROJECT_ID: &str = "yup-test-243420";
@@ -231,17 +263,74 @@ pub mod tests {
         server: &mockito::ServerGuard,
         credential: FcmServerCredential,
     ) -> FcmClient {
-        FcmClient::new(
-            &FcmSettings {
-                base_url: Url::parse(&server.url()).unwrap(),
-                server_credentials: serde_json::json!(credential).to_string(),
-                ..Default::default()
-            },
-            credential,
-            reqwest::Client::new(),
-        )
-        .await
-        .unwrap()
+        let settings = FcmSettings {
+            base_url: Url::parse(&server.url()).unwrap(),
+            server_credentials: serde_json::json!(credential).to_string(),
+            ..Default::default()
+        };
+        let http_client = FcmClient::http_client(&settings).build().unwrap();
+        FcmClient::new(&settings, credential, http_client)
+            .await
+            .unwrap()
+    }
+
+    /// Concurrent requests are multiplexed over a single HTTP/2 connection
+    #[tokio::test]
+    async fn concurrent_sends_share_connection() {
+        let mut server = mockito::Server::new_async().await;
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let fcm_mock = mock_fcm_endpoint_builder(&mut server, PROJECT_ID)
+            .expect(5)
+            .create_async()
+            .await;
+
+        // Count the connections made to FCM through a proxy
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let upstream = server.host_with_port();
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut inbound, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let upstream = upstream.clone();
+                tokio::spawn(async move {
+                    let mut outbound = TcpStream::connect(upstream).await.unwrap();
+                    let _ = copy_bidirectional(&mut inbound, &mut outbound).await;
+                });
+            }
+        });
+
+        let credential = FcmServerCredential {
+            project_id: PROJECT_ID.to_owned(),
+            is_gcm: None,
+            server_access_token: make_service_key(&server),
+        };
+        let settings = FcmSettings {
+            base_url: Url::parse(&proxy_url).unwrap(),
+            server_credentials: serde_json::json!(credential).to_string(),
+            ..Default::default()
+        };
+        // FCM negotiates HTTP/2 via TLS, which the mock server doesn't offer
+        let http_client = FcmClient::http_client(&settings)
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        let client = FcmClient::new(&settings, credential, http_client)
+            .await
+            .unwrap();
+
+        let sends = (0..5).map(|_| {
+            let mut data = HashMap::new();
+            data.insert("is_test", "true".to_string());
+            client.send(data, "test-token".to_string(), 42, None)
+        });
+        for result in join_all(sends).await {
+            assert!(result.is_ok(), "result = {result:?}");
+        }
+        fcm_mock.assert();
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
     }
 
     /// The FCM client uses the access token and parameters to build the
@@ -269,7 +358,7 @@ pub mod tests {
         let mut data = HashMap::new();
         data.insert("is_test", "true".to_string());
 
-        let result = client.send(data, "test-token".to_string(), 42).await;
+        let result = client.send(data, "test-token".to_string(), 42, None).await;
         assert!(result.is_ok(), "result = {result:?}");
         fcm_mock.assert();
     }
@@ -296,7 +385,7 @@ pub mod tests {
             .await;
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(HashMap::new(), "test-token".to_string(), 42, None)
             .await;
         assert!(result.is_err());
         assert!(
@@ -327,7 +416,7 @@ pub mod tests {
             .await;
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(HashMap::new(), "test-token".to_string(), 42, None)
             .await;
         assert!(result.is_err());
         assert!(
@@ -358,7 +447,7 @@ pub mod tests {
             .await;
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(HashMap::new(), "test-token".to_string(), 42, None)
             .await;
         assert!(result.is_err());
         assert!(
@@ -393,7 +482,7 @@ pub mod tests {
             .await;
 
         let result = client
-            .send(HashMap::new(), "test-token".to_string(), 42)
+            .send(HashMap::new(), "test-token".to_string(), 42, None)
             .await;
         assert!(result.is_err());
         assert!(
@@ -3,7 +3,10 @@ use autopush_common::{db::client::DbClient, MAX_NOTIFICATION_TTL};
 use crate::error::ApiResult;
 use crate::extractors::notification::Notification;
 use crate::extractors::router_data_input::RouterDataInput;
-use crate::routers::common::{build_message_data, handle_error, incr_success_metrics};
+use crate::routers::breaker::CircuitBreakers;
+use crate::routers::common::{
+    build_message_data, file_stamps, handle_error, incr_reload_metric, incr_success_metrics,
+};
 use crate::routers::fcm::client::FcmClient;
 use crate::routers::fcm::error::FcmError;
 use crate::routers::fcm::settings::{FcmServerCredential, FcmSettings};
@@ -11,8 +14,10 @@ use crate::routers::{Router, RouterError, RouterResponse};
 use async_trait::async_trait;
 use cadence::StatsdClient;
 use serde_json::Value;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
 use url::Url;
 use uuid::Uuid;
 
@@ -23,7 +28,14 @@ pub struct FcmRouter {
     metrics: Arc<StatsdClient>,
     db: Box<dyn DbClient>,
     /// A map from application ID to an authenticated FCM client
-    clients: HashMap<String, FcmClient>,
+    clients: RwLock<HashMap<String, Arc<FcmClient>>>,
+    /// A map from project ID to the HTTP client shared by the project's
+    /// applications (and kept when their credentials are reloaded)
+    http_clients: Mutex<HashMap<String, reqwest::Client>>,
+    /// The modification times of each application's credential files when
+    /// its client was last built
+    credential_stamps: Mutex<HashMap<String, Vec<Option<SystemTime>>>>,
+    breakers: Arc<CircuitBreakers>,
 }
 
 impl FcmRouter {
@@ -31,12 +43,17 @@ impl FcmRouter {
     pub async fn new(
         settings: FcmSettings,
         endpoint_url: Url,
-        http: reqwest::Client,
         metrics: Arc<StatsdClient>,
         db: Box<dyn DbClient>,
+        breakers: Arc<CircuitBreakers>,
     ) -> Result<Self, FcmError> {
         let server_credentials = settings.credentials()?;
-        let clients = Self::create_clients(&settings, server_credentials, http.clone())
+        let credential_stamps = server_credentials
+            .iter()
+            .map(|(app_id, credential)| (app_id.clone(), file_stamps(&credential.files())))
+            .collect();
+        let mut http_clients = HashMap::new();
+        let clients = Self::create_clients(&settings, server_credentials, &mut http_clients)
             .await
             .map_err(FcmError::OAuthClientBuild)?;
         Ok(Self {
@@ -44,7 +61,10 @@ impl FcmRouter {
             endpoint_url,
             metrics,
             db,
-            clients,
+            clients: RwLock::new(clients),
+            http_clients: Mutex::new(http_clients),
+            credential_stamps: Mutex::new(credential_stamps),
+            breakers,
         })
     }
 
@@ -52,23 +72,70 @@ impl FcmRouter {
     async fn create_clients(
         settings: &FcmSettings,
         server_credentials: HashMap<String, FcmServerCredential>,
-        http: reqwest::Client,
-    ) -> std::io::Result<HashMap<String, FcmClient>> {
+        http_clients: &mut HashMap<String, reqwest::Client>,
+    ) -> std::io::Result<HashMap<String, Arc<FcmClient>>> {
         let mut clients = HashMap::new();
 
         for (profile, server_credential) in server_credentials {
+            let http_client =
+                project_http_client(settings, http_clients, &server_credential.project_id)?;
             clients.insert(
                 profile,
-                FcmClient::new(settings, server_credential, http.clone()).await?,
+                Arc::new(FcmClient::new(settings, server_credential, http_client).await?),
             );
         }
         trace!("Initialized {} FCM clients", clients.len());
         Ok(clients)
     }
 
+    /// Rebuild the clients of applications whose credential files changed.
+    /// A client which fails to rebuild is kept, until its files change again.
+    pub async fn reload_credentials(&self) {
+        let Ok(server_credentials) = self.settings.credentials() else {
+            // Already validated by `new`
+            return;
+        };
+        for (app_id, server_credential) in server_credentials {
+            let stamps = file_stamps(&server_credential.files());
+            if self.credential_stamps.lock().unwrap().get(&app_id) == Some(&stamps) {
+                continue;
+            }
+            self.credential_stamps
+                .lock()
+                .unwrap()
+                .insert(app_id.clone(), stamps);
+
+            let http_client = project_http_client(
+                &self.settings,
+                &mut self.http_clients.lock().unwrap(),
+                &server_credential.project_id,
+            );
+            let client = match http_client {
+                Ok(http_client) => {
+                    FcmClient::new(&self.settings, server_credential, http_client).await
+                }
+                Err(e) => Err(e),
+            };
+            match client {
+                Ok(client) => {
+                    info!("Reloaded FCM credentials for {}", app_id);
+                    self.clients
+                        .write()
+                        .unwrap()
+                        .insert(app_id.clone(), Arc::new(client));
+                    incr_reload_metric(&self.metrics, "fcm", &app_id, "success");
+                }
+                Err(e) => {
+                    error!("Error reloading FCM credentials for {}: {}", app_id, e);
+                    incr_reload_metric(&self.metrics, "fcm", &app_id, "error");
+                }
+            }
+        }
+    }
+
     /// if we have any clients defined, this connection is "active"
     pub fn active(&self) -> bool {
-        !self.clients.is_empty()
+        !self.clients.read().unwrap().is_empty()
     }
 
     /// Do the gauntlet check to get the routing credentials, these are the
@@ -116,6 +183,25 @@ impl FcmRouter {
     }
 }
 
+/// Get the HTTP client of an FCM project, building it for the project's first
+/// application
+fn project_http_client(
+    settings: &FcmSettings,
+    http_clients: &mut HashMap<String, reqwest::Client>,
+    project_id: &str,
+) -> std::io::Result<reqwest::Client> {
+    Ok(match http_clients.entry(project_id.to_owned()) {
+        Entry::Occupied(entry) => entry.get().clone(),
+        Entry::Vacant(entry) => entry
+            .insert(
+                FcmClient::http_client(settings)
+                    .build()
+                    .map_err(std::io::Error::other)?,
+            )
+            .clone(),
+    })
+}
+
 #[async_trait(?Send)]
 impl Router for FcmRouter {
     fn register(
@@ -123,7 +209,7 @@ impl Router for FcmRouter {
         router_data_input: &RouterDataInput,
         app_id: &str,
     ) -> Result<HashMap<String, Value>, RouterError> {
-        if !self.clients.contains_key(app_id) {
+        if !self.clients.read().unwrap().contains_key(app_id) {
             return Err(FcmError::InvalidAppId(app_id.to_owned()).into());
         }
 
@@ -159,13 +245,29 @@ impl Router for FcmRouter {
         // Send the notification to FCM
         let client = self
             .clients
+            .read()
+            .unwrap()
             .get(&app_id)
+            .cloned()
             .ok_or_else(|| FcmError::InvalidAppId(app_id.clone()))?;
 
         let message_data = build_message_data(notification)?;
         let platform = "fcmv1";
         trace!("Sending message to {platform}: [{:?}]", &app_id);
-        if let Err(e) = client.send(message_data, routing_token, ttl).await {
+        // Topic messages replace each other, as should their notifications
+        let collapse_key = notification.headers.topic.as_deref();
+        let breaker = format!("fcm:{}", client.project_id());
+        let result = if self.breakers.allow(&breaker) {
+            let result = client
+                .send(message_data, routing_token, ttl, collapse_key)
+                .await;
+            self.breakers
+                .record(&breaker, !matches!(&result, Err(e) if e.is_transient()));
+            result
+        } else {
+            Err(RouterError::CircuitOpen)
+        };
+        if let Err(e) = result {
             return Err(handle_error(
                 e,
                 &self.metrics,
@@ -195,6 +297,8 @@ impl Router for FcmRouter {
 mod tests {
     use crate::error::ApiErrorKind;
     use crate::extractors::routers::RouterType;
+    use crate::routers::breaker::settings::BreakerSettings;
+    use crate::routers::breaker::CircuitBreakers;
     use crate::routers::common::tests::{make_notification, CHANNEL_ID};
     use crate::routers::fcm::client::tests::{
         make_service_key, mock_fcm_endpoint_builder, mock_token_endpoint, GCM_PROJECT_ID,
@@ -212,6 +316,7 @@ mod tests {
     use cadence::StatsdClient;
     use mockall::predicate;
     use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
     use url::Url;
 
     const FCM_TOKEN: &str = "test-token";
@@ -223,33 +328,135 @@ mod tests {
         gcm_credential: String,
         db: Box<dyn DbClient>,
     ) -> FcmRouter {
-        let url = &server.url();
+        make_router_with_credentials(
+            server,
+            serde_json::json!({
+                "dev": {
+                    "project_id": PROJECT_ID,
+                    "credential": fcm_credential
+                },
+                GCM_PROJECT_ID: {
+                    "project_id": GCM_PROJECT_ID,
+                    "credential": gcm_credential,
+                    "is_gcm": true,
+                }
+            })
+            .to_string(),
+            db,
+        )
+        .await
+    }
+
+    /// Create a router for testing, using the given credentials setting
+    async fn make_router_with_credentials(
+        server: &mockito::ServerGuard,
+        server_credentials: String,
+        db: Box<dyn DbClient>,
+    ) -> FcmRouter {
         FcmRouter::new(
             FcmSettings {
-                base_url: Url::parse(url).unwrap(),
-                server_credentials: serde_json::json!({
-                    "dev": {
-                        "project_id": PROJECT_ID,
-                        "credential": fcm_credential
-                    },
-                    GCM_PROJECT_ID: {
-                        "project_id": GCM_PROJECT_ID,
-                        "credential": gcm_credential,
-                        "is_gcm": true,
-                    }
-                })
-                .to_string(),
+                base_url: Url::parse(&server.url()).unwrap(),
+                server_credentials,
                 ..Default::default()
             },
             Url::parse("http://localhost:8080/").unwrap(),
-            reqwest::Client::new(),
             Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
             db,
+            Arc::new(CircuitBreakers::new(
+                BreakerSettings::default(),
+                Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+            )),
         )
         .await
         .unwrap()
     }
 
+    /// Applications of the same project share its HTTP client
+    #[tokio::test]
+    async fn project_http_clients() {
+        let server = mockito::Server::new_async().await;
+        let router = make_router_with_credentials(
+            &server,
+            serde_json::json!({
+                "dev": {
+                    "project_id": PROJECT_ID,
+                    "credential": make_service_key(&server)
+                },
+                "dev-beta": {
+                    "project_id": PROJECT_ID,
+                    "credential": make_service_key(&server)
+                },
+                GCM_PROJECT_ID: {
+                    "project_id": GCM_PROJECT_ID,
+                    "credential": "test",
+                    "is_gcm": true,
+                }
+            })
+            .to_string(),
+            MockDbClient::new().into_boxed_arc(),
+        )
+        .await;
+        assert_eq!(router.clients.read().unwrap().len(), 3);
+        let mut projects: Vec<_> = router
+            .http_clients
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        projects.sort();
+        assert_eq!(projects, [GCM_PROJECT_ID, PROJECT_ID]);
+    }
+
+    /// Changed credential files are reloaded, keeping the old client if the
+    /// new credential is invalid
+    #[tokio::test]
+    async fn reload_credentials() {
+        let server = mockito::Server::new_async().await;
+        let key_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(key_file.path(), make_service_key(&server)).unwrap();
+        let router = make_router_with_credentials(
+            &server,
+            serde_json::json!({
+                "dev": {
+                    "project_id": PROJECT_ID,
+                    "credential": key_file.path()
+                }
+            })
+            .to_string(),
+            MockDbClient::new().into_boxed_arc(),
+        )
+        .await;
+        let client = || router.clients.read().unwrap()["dev"].clone();
+        let touch = |secs| {
+            key_file
+                .as_file()
+                .set_modified(SystemTime::now() + Duration::from_secs(secs))
+                .unwrap()
+        };
+        let original = client();
+
+        router.reload_credentials().await;
+        assert!(Arc::ptr_eq(&original, &client()), "Reloaded unchanged key");
+
+        touch(10);
+        router.reload_credentials().await;
+        let reloaded = client();
+        assert!(
+            !Arc::ptr_eq(&original, &reloaded),
+            "Changed key not reloaded"
+        );
+
+        std::fs::write(key_file.path(), "invalid").unwrap();
+        touch(20);
+        router.reload_credentials().await;
+        assert!(
+            Arc::ptr_eq(&reloaded, &client()),
+            "Invalid key replaced client"
+        );
+        assert_eq!(router.http_clients.lock().unwrap().len(), 1);
+    }
+
     /// Create default user router data
     fn default_router_data() -> HashMap<String, serde_json::Value> {
         let mut map = HashMap::new();
@@ -280,7 +487,8 @@ mod tests {
                             "data": {
                                 "chid": CHANNEL_ID
                             },
-                            "ttl": "60s"
+                            "ttl": "60s",
+                            "collapse_key": "test-topic"
                         },
                         "token": "test-token"
                     }
@@ -323,7 +531,8 @@ mod tests {
                                 "cryptokey": "test-crypto-key",
                                 "enckey": "test-encryption-key"
                             },
-                            "ttl": "60s"
+                            "ttl": "60s",
+                            "collapse_key": "test-topic"
                         },
                         "token": "test-token"
                     }
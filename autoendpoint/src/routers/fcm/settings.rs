@@ -42,6 +42,11 @@ pub struct FcmSettings {
     pub base_url: Url,
     /// The number of seconds to wait for FCM requests to complete
     pub timeout: usize,
+    /// How often (in seconds) idle HTTP/2 connections to FCM are pinged to
+    /// keep them open
+    pub keep_alive_interval: u64,
+    /// How long (in seconds) idle connections to FCM are kept open
+    pub pool_idle_timeout: u64,
 }
 
 /// Credential information for each application
@@ -53,6 +58,18 @@ pub struct FcmServerCredential {
     pub server_access_token: String,
 }
 
+impl FcmServerCredential {
+    /// The files the credential is read from, if any
+    pub fn files(&self) -> Vec<&str> {
+        // Serialized JSON keys are inline, anything else may be a path
+        if self.server_access_token.contains('{') {
+            vec![]
+        } else {
+            vec![&self.server_access_token]
+        }
+    }
+}
+
 impl Default for FcmSettings {
     fn default() -> Self {
         Self {
@@ -61,6 +78,8 @@ impl Default for FcmSettings {
             max_data: 4096,
             base_url: Url::parse("https://fcm.googleapis.com").unwrap(),
             timeout: 3,
+            keep_alive_interval: 30,
+            pool_idle_timeout: 600,
         }
     }
 }
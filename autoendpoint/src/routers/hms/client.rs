@@ -0,0 +1,427 @@
+use crate::routers::common::message_size_check;
+use crate::routers::hms::error::HmsError;
+use crate::routers::hms::settings::{HmsCredential, HmsSettings};
+use crate::routers::RouterError;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// The response code HMS returns for an accepted message
+const SUCCESS: &str = "80000000";
+/// Some of the message's tokens were invalid. We only ever send to one.
+const PARTIAL_SUCCESS: &str = "80100000";
+/// All of the message's tokens were invalid
+const INVALID_TOKENS: &str = "80300007";
+/// The access token could not be authenticated
+const OAUTH_FAILED: &str = "80200001";
+/// The access token has expired
+const OAUTH_EXPIRED: &str = "80200003";
+
+/// How long before expiry a cached access token is refreshed
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// An OAuth access token, along with when it expires
+struct AccessToken {
+    token: String,
+    expires_at: Instant,
+}
+
+/// Holds application-specific HMS data and authentication. This client
+/// handles sending notifications to HMS Push Kit.
+pub struct HmsClient {
+    endpoint: Url,
+    token_url: Url,
+    client_id: String,
+    client_secret: String,
+    timeout: Duration,
+    max_data: usize,
+    access_token: RwLock<Option<AccessToken>>,
+    http_client: reqwest::Client,
+}
+
+impl HmsClient {
+    /// Create an `HmsClient` using the provided credential
+    pub fn new(settings: &HmsSettings, credential: HmsCredential, http: reqwest::Client) -> Self {
+        HmsClient {
+            endpoint: settings
+                .base_url
+                .join(&format!("v1/{}/messages:send", credential.app_id))
+                .expect("App ID is not URL-safe"),
+            token_url: settings.token_url.clone(),
+            client_id: credential.client_id.unwrap_or(credential.app_id),
+            client_secret: credential.client_secret,
+            timeout: Duration::from_secs(settings.timeout as u64),
+            max_data: settings.max_data,
+            access_token: RwLock::new(None),
+            http_client: http,
+        }
+    }
+
+    /// Get an access token, requesting a new one via the OAuth client
+    /// credentials flow if the cached one is missing or about to expire
+    async fn access_token(&self) -> Result<String, HmsError> {
+        if let Some(cached) = self.access_token.read().unwrap().as_ref() {
+            if cached.expires_at > Instant::now() + TOKEN_REFRESH_MARGIN {
+                return Ok(cached.token.clone());
+            }
+        }
+
+        let response = self
+            .http_client
+            .post(self.token_url.clone())
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+            ])
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(HmsError::OAuthToken)?;
+        let status = response.status();
+        if !status.is_success() {
+            warn!("HMS OAuth token request rejected [{status}]");
+            return Err(HmsError::OAuthRejected(status));
+        }
+        let token: TokenResponse = response.json().await.map_err(HmsError::OAuthToken)?;
+
+        *self.access_token.write().unwrap() = Some(AccessToken {
+            token: token.access_token.clone(),
+            expires_at: Instant::now() + Duration::from_secs(token.expires_in),
+        });
+        Ok(token.access_token)
+    }
+
+    /// Drop the cached access token, so the next request fetches a new one
+    fn clear_access_token(&self) {
+        *self.access_token.write().unwrap() = None;
+    }
+
+    /// Send the message data to HMS
+    pub async fn send(
+        &self,
+        data: HashMap<&'static str, String>,
+        routing_token: String,
+        ttl: u64,
+    ) -> Result<(), RouterError> {
+        // Check the payload size. HMS only cares about the `data` field when
+        // checking size. It's sent as a string, serialized via a `Value` so
+        // its keys are ordered.
+        let data_json = serde_json::to_value(&data).unwrap().to_string();
+        message_size_check(data_json.as_bytes(), self.max_data)?;
+
+        // Build the HMS message
+        let message = serde_json::json!({
+            "validate_only": false,
+            "message": {
+                "data": data_json,
+                "android": {
+                    "ttl": format!("{ttl}s")
+                },
+                "token": [routing_token]
+            }
+        });
+
+        let token = self.access_token().await?;
+
+        // Make the request
+        let response = self
+            .http_client
+            .post(self.endpoint.clone())
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&message)
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(|e| {
+                if e.is_timeout() {
+                    RouterError::RequestTimeout
+                } else {
+                    RouterError::Connect(e)
+                }
+            })?;
+
+        let status = response.status();
+        if status == StatusCode::UNAUTHORIZED {
+            self.clear_access_token();
+            return Err(RouterError::Authentication);
+        }
+
+        // HMS reports the outcome in the body, often with a 200 status
+        let raw_data = response
+            .bytes()
+            .await
+            .map_err(HmsError::DeserializeResponse)?;
+        let data: HmsResponse = serde_json::from_slice(&raw_data).map_err(|e| {
+            let s = String::from_utf8(raw_data.to_vec()).unwrap_or_else(|e| e.to_string());
+            warn!("Invalid HMS response [{status}] \"{s}\"");
+            HmsError::InvalidResponse(e, s, status)
+        })?;
+
+        match data.code.as_str() {
+            SUCCESS => Ok(()),
+            OAUTH_FAILED | OAUTH_EXPIRED => {
+                self.clear_access_token();
+                Err(RouterError::Authentication)
+            }
+            PARTIAL_SUCCESS | INVALID_TOKENS => Err(RouterError::NotFound),
+            _ => Err(RouterError::Upstream {
+                status: data.code,
+                message: data.msg,
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+struct HmsResponse {
+    code: String,
+    #[serde(default)]
+    msg: String,
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::routers::hms::client::HmsClient;
+    use crate::routers::hms::error::HmsError;
+    use crate::routers::hms::settings::{HmsCredential, HmsSettings};
+    use crate::routers::RouterError;
+    use std::collections::HashMap;
+    use url::Url;
+
+    pub const APP_ID: &str = "104123456";
+    const CLIENT_SECRET: &str = "test-client-secret";
+    const ACCESS_TOKEN: &str = "DAEDAJ7Hh9S6zqHvLdWTwLr0x0ZQ0tXq2TbKDrTEF1vjGrN6t3HIt8CvQ+Oo";
+
+    /// The credential used by the test clients
+    pub fn make_credential() -> HmsCredential {
+        HmsCredential {
+            app_id: APP_ID.to_owned(),
+            client_id: None,
+            client_secret: CLIENT_SECRET.to_owned(),
+        }
+    }
+
+    /// Settings pointing both HMS endpoints at the mock server
+    pub fn make_settings(server: &mockito::ServerGuard) -> HmsSettings {
+        HmsSettings {
+            base_url: Url::parse(&server.url()).unwrap(),
+            token_url: Url::parse(&format!("{}/oauth2/v3/token", server.url())).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    /// Mock the OAuth token endpoint to provide the access token
+    pub async fn mock_token_endpoint(server: &mut mockito::ServerGuard) -> mockito::Mock {
+        server
+            .mock("POST", "/oauth2/v3/token")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("grant_type".into(), "client_credentials".into()),
+                mockito::Matcher::UrlEncoded("client_id".into(), APP_ID.into()),
+                mockito::Matcher::UrlEncoded("client_secret".into(), CLIENT_SECRET.into()),
+            ]))
+            .with_body(
+                serde_json::json!({
+                    "access_token": ACCESS_TOKEN,
+                    "expires_in": 3600,
+                    "token_type": "Bearer"
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await
+    }
+
+    /// Start building a mock for the HMS endpoint
+    pub fn mock_hms_endpoint_builder(server: &mut mockito::ServerGuard, id: &str) -> mockito::Mock {
+        server.mock("POST", format!("/v1/{id}/messages:send").as_str())
+    }
+
+    /// Make an HmsClient for the mock server
+    fn make_client(server: &mockito::ServerGuard) -> HmsClient {
+        HmsClient::new(
+            &make_settings(server),
+            make_credential(),
+            reqwest::Client::new(),
+        )
+    }
+
+    /// The HMS client uses the access token and parameters to build the
+    /// expected HMS request.
+    #[tokio::test]
+    async fn sends_correct_hms_request() {
+        let mut server = mockito::Server::new_async().await;
+
+        let client = make_client(&server);
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let hms_mock = mock_hms_endpoint_builder(&mut server, APP_ID)
+            .match_header("Authorization", format!("Bearer {ACCESS_TOKEN}").as_str())
+            .match_header("Content-Type", "application/json")
+            .match_body(r#"{"message":{"android":{"ttl":"42s"},"data":"{\"is_test\":\"true\"}","token":["test-token"]},"validate_only":false}"#)
+            .with_body(r#"{"code":"80000000","msg":"Success","requestId":"1"}"#)
+            .create();
+
+        let mut data = HashMap::new();
+        data.insert("is_test", "true".to_string());
+
+        let result = client.send(data, "test-token".to_string(), 42).await;
+        assert!(result.is_ok(), "result = {result:?}");
+        hms_mock.assert();
+    }
+
+    /// The access token is cached between requests
+    #[tokio::test]
+    async fn reuses_access_token() {
+        let mut server = mockito::Server::new_async().await;
+
+        let client = make_client(&server);
+        let token_mock = mock_token_endpoint(&mut server).await.expect(1);
+        let hms_mock = mock_hms_endpoint_builder(&mut server, APP_ID)
+            .with_body(r#"{"code":"80000000","msg":"Success"}"#)
+            .expect(2)
+            .create_async()
+            .await;
+
+        for _ in 0..2 {
+            let result = client
+                .send(HashMap::new(), "test-token".to_string(), 42)
+                .await;
+            assert!(result.is_ok(), "result = {result:?}");
+        }
+        token_mock.assert();
+        hms_mock.assert();
+    }
+
+    /// Authorization errors are handled, and drop the cached access token
+    #[tokio::test]
+    async fn unauthorized() {
+        let mut server = mockito::Server::new_async().await;
+
+        let client = make_client(&server);
+        let token_mock = mock_token_endpoint(&mut server).await.expect(2);
+        let _hms_mock = mock_hms_endpoint_builder(&mut server, APP_ID)
+            .with_status(401)
+            .with_body(r#"{"code":"80200003","msg":"OAuth token expired"}"#)
+            .create_async()
+            .await;
+
+        for _ in 0..2 {
+            let result = client
+                .send(HashMap::new(), "test-token".to_string(), 42)
+                .await;
+            assert!(
+                matches!(result.as_ref().unwrap_err(), RouterError::Authentication),
+                "result = {result:?}"
+            );
+        }
+        token_mock.assert();
+    }
+
+    /// A rejected OAuth token request is reported
+    #[tokio::test]
+    async fn oauth_rejected() {
+        let mut server = mockito::Server::new_async().await;
+
+        let client = make_client(&server);
+        let _token_mock = server
+            .mock("POST", "/oauth2/v3/token")
+            .with_status(400)
+            .with_body(r#"{"error":1101,"error_description":"invalid client"}"#)
+            .create_async()
+            .await;
+        let hms_mock = mock_hms_endpoint_builder(&mut server, APP_ID)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let result = client
+            .send(HashMap::new(), "test-token".to_string(), 42)
+            .await;
+        assert!(
+            matches!(
+                result.as_ref().unwrap_err(),
+                RouterError::Hms(HmsError::OAuthRejected(_))
+            ),
+            "result = {result:?}"
+        );
+        hms_mock.assert();
+    }
+
+    /// Invalid registration tokens are reported as not found
+    #[tokio::test]
+    async fn invalid_token() {
+        let mut server = mockito::Server::new_async().await;
+
+        let client = make_client(&server);
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let _hms_mock = mock_hms_endpoint_builder(&mut server, APP_ID)
+            .with_body(r#"{"code":"80300007","msg":"All the tokens are invalid"}"#)
+            .create_async()
+            .await;
+
+        let result = client
+            .send(HashMap::new(), "test-token".to_string(), 42)
+            .await;
+        assert!(
+            matches!(result.as_ref().unwrap_err(), RouterError::NotFound),
+            "result = {result:?}"
+        );
+    }
+
+    /// Other error codes are wrapped and returned
+    #[tokio::test]
+    async fn other_hms_error() {
+        let mut server = mockito::Server::new_async().await;
+
+        let client = make_client(&server);
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let _hms_mock = mock_hms_endpoint_builder(&mut server, APP_ID)
+            .with_status(400)
+            .with_body(r#"{"code":"80100003","msg":"Illegal message structure"}"#)
+            .create_async()
+            .await;
+
+        let result = client
+            .send(HashMap::new(), "test-token".to_string(), 42)
+            .await;
+        assert!(
+            matches!(
+                result.as_ref().unwrap_err(),
+                RouterError::Upstream { status, message }
+                    if status == "80100003" && message == "Illegal message structure"
+            ),
+            "result = {result:?}"
+        );
+    }
+
+    /// Data over the size limit is rejected before anything is sent
+    #[tokio::test]
+    async fn too_much_data() {
+        let mut server = mockito::Server::new_async().await;
+
+        let client = make_client(&server);
+        let hms_mock = mock_hms_endpoint_builder(&mut server, APP_ID)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let mut data = HashMap::new();
+        data.insert("body", "x".repeat(5000));
+
+        let result = client.send(data, "test-token".to_string(), 42).await;
+        assert!(
+            matches!(result.as_ref().unwrap_err(), RouterError::TooMuchData(_)),
+            "result = {result:?}"
+        );
+        hms_mock.assert();
+    }
+}
@@ -0,0 +1,102 @@
+use crate::error::ApiErrorKind;
+use crate::routers::RouterError;
+
+use autopush_common::errors::ReportableError;
+use reqwest::StatusCode;
+
+/// Errors that may occur in the Huawei Mobile Services router
+#[derive(thiserror::Error, Debug)]
+pub enum HmsError {
+    #[error("Failed to decode the credential settings")]
+    CredentialDecode(#[from] serde_json::Error),
+
+    #[error("Error while retrieving an OAuth token")]
+    OAuthToken(#[source] reqwest::Error),
+
+    #[error("OAuth token request was rejected [{0}]")]
+    OAuthRejected(StatusCode),
+
+    #[error("Unable to deserialize HMS response")]
+    DeserializeResponse(#[source] reqwest::Error),
+
+    #[error("Invalid JSON response from HMS")]
+    InvalidResponse(#[source] serde_json::Error, String, StatusCode),
+
+    #[error("No registration token found for user")]
+    NoRegistrationToken,
+
+    #[error("No app ID found for user")]
+    NoAppId,
+
+    #[error("User has invalid app ID {0}")]
+    InvalidAppId(String),
+}
+
+impl HmsError {
+    /// Get the associated HTTP status code
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HmsError::NoRegistrationToken | HmsError::NoAppId | HmsError::InvalidAppId(_) => {
+                StatusCode::GONE
+            }
+
+            HmsError::CredentialDecode(_) => StatusCode::INTERNAL_SERVER_ERROR,
+
+            HmsError::OAuthToken(_)
+            | HmsError::OAuthRejected(_)
+            | HmsError::DeserializeResponse(_)
+            | HmsError::InvalidResponse(_, _, _) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Get the associated error number
+    pub fn errno(&self) -> Option<usize> {
+        match self {
+            HmsError::NoRegistrationToken | HmsError::NoAppId | HmsError::InvalidAppId(_) => {
+                Some(106)
+            }
+
+            HmsError::CredentialDecode(_)
+            | HmsError::OAuthToken(_)
+            | HmsError::OAuthRejected(_)
+            | HmsError::DeserializeResponse(_)
+            | HmsError::InvalidResponse(_, _, _) => None,
+        }
+    }
+}
+
+impl From<HmsError> for ApiErrorKind {
+    fn from(e: HmsError) -> Self {
+        ApiErrorKind::Router(RouterError::Hms(e))
+    }
+}
+
+impl ReportableError for HmsError {
+    fn is_sentry_event(&self) -> bool {
+        matches!(&self, HmsError::InvalidAppId(_) | HmsError::NoAppId)
+    }
+
+    fn metric_label(&self) -> Option<&'static str> {
+        match &self {
+            HmsError::InvalidAppId(_) | HmsError::NoAppId => {
+                Some("notification.bridge.error.hms.badappid")
+            }
+            _ => None,
+        }
+    }
+
+    fn extras(&self) -> Vec<(&str, String)> {
+        match self {
+            HmsError::InvalidAppId(appid) => {
+                vec![("app_id", appid.to_string())]
+            }
+            HmsError::OAuthRejected(status) => {
+                vec![("status", status.to_string())]
+            }
+            HmsError::InvalidResponse(_, body, status) => {
+                vec![("status", status.to_string()), ("body", body.to_owned())]
+            }
+            _ => vec![],
+        }
+    }
+}
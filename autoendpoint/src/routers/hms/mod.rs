@@ -0,0 +1,7 @@
+//! A notification router for Huawei devices, using Huawei Mobile Services
+//! (HMS) Push Kit
+
+mod client;
+pub mod error;
+pub mod router;
+pub mod settings;
@@ -0,0 +1,380 @@
+use autopush_common::{db::client::DbClient, MAX_NOTIFICATION_TTL};
+
+use crate::error::ApiResult;
+use crate::extractors::notification::Notification;
+use crate::extractors::router_data_input::RouterDataInput;
+use crate::routers::common::{build_message_data, handle_error, incr_success_metrics};
+use crate::routers::hms::client::HmsClient;
+use crate::routers::hms::error::HmsError;
+use crate::routers::hms::settings::HmsSettings;
+use crate::routers::{Router, RouterError, RouterResponse};
+use async_trait::async_trait;
+use cadence::StatsdClient;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use url::Url;
+use uuid::Uuid;
+
+/// Huawei Mobile Services Push Kit router
+pub struct HmsRouter {
+    settings: HmsSettings,
+    endpoint_url: Url,
+    metrics: Arc<StatsdClient>,
+    db: Box<dyn DbClient>,
+    /// A map from application ID to an HMS client
+    clients: HashMap<String, HmsClient>,
+}
+
+impl HmsRouter {
+    /// Create a new `HmsRouter`
+    pub fn new(
+        settings: HmsSettings,
+        endpoint_url: Url,
+        http: reqwest::Client,
+        metrics: Arc<StatsdClient>,
+        db: Box<dyn DbClient>,
+    ) -> Result<Self, HmsError> {
+        let clients: HashMap<String, HmsClient> = settings
+            .credentials()?
+            .into_iter()
+            .map(|(profile, credential)| {
+                (profile, HmsClient::new(&settings, credential, http.clone()))
+            })
+            .collect();
+        trace!("Initialized {} HMS clients", clients.len());
+        Ok(Self {
+            settings,
+            endpoint_url,
+            metrics,
+            db,
+            clients,
+        })
+    }
+
+    /// if we have any clients defined, this connection is "active"
+    pub fn active(&self) -> bool {
+        !self.clients.is_empty()
+    }
+
+    /// Get the routing credentials: the app ID and the subscription specific
+    /// user routing token, stored as `app_id` & `token`.
+    fn routing_info(
+        &self,
+        router_data: &HashMap<String, Value>,
+        uaid: &Uuid,
+    ) -> ApiResult<(String, String)> {
+        let routing_token = match router_data.get("token").and_then(Value::as_str) {
+            Some(v) => v.to_owned(),
+            None => {
+                warn!("No Registration token found for user {}", uaid.to_string());
+                return Err(HmsError::NoRegistrationToken.into());
+            }
+        };
+        let app_id = match router_data.get("app_id").and_then(Value::as_str) {
+            Some(v) => v.to_owned(),
+            None => {
+                warn!("No App_id found for user {}", uaid.to_string());
+                return Err(HmsError::NoAppId.into());
+            }
+        };
+        Ok((routing_token, app_id))
+    }
+}
+
+#[async_trait(?Send)]
+impl Router for HmsRouter {
+    fn register(
+        &self,
+        router_data_input: &RouterDataInput,
+        app_id: &str,
+    ) -> Result<HashMap<String, Value>, RouterError> {
+        if !self.clients.contains_key(app_id) {
+            return Err(HmsError::InvalidAppId(app_id.to_owned()).into());
+        }
+
+        let mut router_data = HashMap::new();
+        router_data.insert(
+            "token".to_string(),
+            serde_json::to_value(&router_data_input.token).unwrap(),
+        );
+        router_data.insert("app_id".to_string(), serde_json::to_value(app_id).unwrap());
+
+        Ok(router_data)
+    }
+
+    async fn route_notification(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        debug!(
+            "Sending HMS notification to UAID {}",
+            notification.subscription.user.uaid
+        );
+        trace!("Notification = {:?}", notification);
+
+        let router_data = notification
+            .subscription
+            .user
+            .router_data
+            .as_ref()
+            .ok_or(HmsError::NoRegistrationToken)?;
+
+        let (routing_token, app_id) =
+            self.routing_info(router_data, &notification.subscription.user.uaid)?;
+        let ttl =
+            MAX_NOTIFICATION_TTL.min(self.settings.min_ttl.max(notification.headers.ttl as u64));
+
+        // Send the notification to HMS
+        let client = self
+            .clients
+            .get(&app_id)
+            .ok_or_else(|| HmsError::InvalidAppId(app_id.clone()))?;
+
+        let message_data = build_message_data(notification)?;
+        let platform = "hms";
+        trace!("Sending message to {platform}: [{:?}]", &app_id);
+        if let Err(e) = client.send(message_data, routing_token, ttl).await {
+            return Err(handle_error(
+                e,
+                &self.metrics,
+                self.db.as_ref(),
+                platform,
+                &app_id,
+                notification.subscription.user.uaid,
+                notification.subscription.vapid.clone(),
+            )
+            .await);
+        };
+        incr_success_metrics(&self.metrics, platform, &app_id, notification);
+        // Sent successfully, update metrics and make response
+        trace!("Send request was successful");
+
+        Ok(RouterResponse::success(
+            self.endpoint_url
+                .join(&format!("/m/{}", notification.message_id))
+                .expect("Message ID is not URL-safe")
+                .to_string(),
+            notification.headers.ttl as usize,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ApiErrorKind;
+    use crate::extractors::router_data_input::RouterDataInput;
+    use crate::extractors::routers::RouterType;
+    use crate::routers::common::tests::{make_notification, CHANNEL_ID};
+    use crate::routers::hms::client::tests::{
+        make_settings, mock_hms_endpoint_builder, mock_token_endpoint, APP_ID,
+    };
+    use crate::routers::hms::error::HmsError;
+    use crate::routers::hms::router::HmsRouter;
+    use crate::routers::hms::settings::HmsSettings;
+    use crate::routers::RouterError;
+    use crate::routers::{Router, RouterResponse};
+    use autopush_common::db::client::DbClient;
+    use autopush_common::db::mock::MockDbClient;
+    use std::sync::Arc;
+
+    use cadence::StatsdClient;
+    use mockall::predicate;
+    use std::collections::HashMap;
+    use url::Url;
+
+    const HMS_TOKEN: &str = "test-token";
+
+    /// Create a router for testing
+    fn make_router(server: &mockito::ServerGuard, db: Box<dyn DbClient>) -> HmsRouter {
+        HmsRouter::new(
+            HmsSettings {
+                credentials: serde_json::json!({
+                    "dev": {
+                        "app_id": APP_ID,
+                        "client_secret": "test-client-secret"
+                    }
+                })
+                .to_string(),
+                ..make_settings(server)
+            },
+            Url::parse("http://localhost:8080/").unwrap(),
+            reqwest::Client::new(),
+            Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+            db,
+        )
+        .unwrap()
+    }
+
+    /// Create default user router data
+    fn default_router_data() -> HashMap<String, serde_json::Value> {
+        let mut map = HashMap::new();
+        map.insert(
+            "token".to_string(),
+            serde_json::to_value(HMS_TOKEN).unwrap(),
+        );
+        map.insert("app_id".to_string(), serde_json::to_value("dev").unwrap());
+        map
+    }
+
+    /// A notification with no data is sent to HMS
+    #[tokio::test]
+    async fn successful_routing_no_data() {
+        let mut server = mockito::Server::new_async().await;
+
+        let router = make_router(&server, MockDbClient::new().into_boxed_arc());
+        assert!(router.active());
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let hms_mock = mock_hms_endpoint_builder(&mut server, APP_ID)
+            .match_body(
+                serde_json::json!({
+                    "validate_only": false,
+                    "message": {
+                        "data": serde_json::json!({"chid": CHANNEL_ID}).to_string(),
+                        "android": {
+                            "ttl": "60s"
+                        },
+                        "token": ["test-token"]
+                    }
+                })
+                .to_string()
+                .as_str(),
+            )
+            .with_body(r#"{"code":"80000000","msg":"Success"}"#)
+            .create_async()
+            .await;
+        let notification = make_notification(default_router_data(), None, RouterType::HMS);
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_ok(), "result = {result:?}");
+        assert_eq!(
+            result.unwrap(),
+            RouterResponse::success("http://localhost:8080/m/test-message-id".to_string(), 0)
+        );
+        hms_mock.assert();
+    }
+
+    /// A notification with data is sent to HMS
+    #[tokio::test]
+    async fn successful_routing_with_data() {
+        let mut server = mockito::Server::new_async().await;
+
+        let router = make_router(&server, MockDbClient::new().into_boxed_arc());
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let hms_mock = mock_hms_endpoint_builder(&mut server, APP_ID)
+            .match_body(
+                serde_json::json!({
+                    "validate_only": false,
+                    "message": {
+                        "data": serde_json::json!({
+                            "chid": CHANNEL_ID,
+                            "body": "test-data",
+                            "con": "test-encoding",
+                            "enc": "test-encryption",
+                            "cryptokey": "test-crypto-key",
+                            "enckey": "test-encryption-key"
+                        })
+                        .to_string(),
+                        "android": {
+                            "ttl": "60s"
+                        },
+                        "token": ["test-token"]
+                    }
+                })
+                .to_string()
+                .as_str(),
+            )
+            .with_body(r#"{"code":"80000000","msg":"Success"}"#)
+            .create_async()
+            .await;
+        let data = "test-data".to_string();
+        let notification = make_notification(default_router_data(), Some(data), RouterType::HMS);
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_ok(), "result = {result:?}");
+        hms_mock.assert();
+    }
+
+    /// If there is no client for the user's app ID, an error is returned and
+    /// the HMS request is not sent.
+    #[tokio::test]
+    async fn missing_client() {
+        let mut server = mockito::Server::new_async().await;
+
+        let router = make_router(&server, MockDbClient::new().into_boxed_arc());
+        let hms_mock = mock_hms_endpoint_builder(&mut server, APP_ID)
+            .expect(0)
+            .create_async()
+            .await;
+        let mut router_data = default_router_data();
+        router_data.insert(
+            "app_id".to_string(),
+            serde_json::to_value("unknown-app-id").unwrap(),
+        );
+        let notification = make_notification(router_data, None, RouterType::HMS);
+
+        let result = router.route_notification(&notification).await;
+        assert!(
+            matches!(
+                &result.as_ref().unwrap_err().kind,
+                ApiErrorKind::Router(RouterError::Hms(HmsError::InvalidAppId(app_id)))
+                    if app_id == "unknown-app-id"
+            ),
+            "result = {result:?}"
+        );
+        hms_mock.assert();
+    }
+
+    /// If HMS reports the token is invalid, we drop the user from our database
+    #[tokio::test]
+    async fn invalid_hms_token() {
+        let mut server = mockito::Server::new_async().await;
+
+        let notification = make_notification(default_router_data(), None, RouterType::HMS);
+        let mut db = MockDbClient::new();
+        db.expect_remove_user()
+            .with(predicate::eq(notification.subscription.user.uaid))
+            .times(1)
+            .return_once(|_| Ok(()));
+
+        let router = make_router(&server, db.into_boxed_arc());
+        let _token_mock = mock_token_endpoint(&mut server).await;
+        let _hms_mock = mock_hms_endpoint_builder(&mut server, APP_ID)
+            .with_body(r#"{"code":"80300007","msg":"All the tokens are invalid"}"#)
+            .create_async()
+            .await;
+
+        let result = router.route_notification(&notification).await;
+        assert!(
+            matches!(
+                result.as_ref().unwrap_err().kind,
+                ApiErrorKind::Router(RouterError::NotFound)
+            ),
+            "result = {result:?}"
+        );
+    }
+
+    /// Registration stores the token and app ID, for known app IDs only
+    #[tokio::test]
+    async fn register() {
+        let server = mockito::Server::new_async().await;
+
+        let router = make_router(&server, MockDbClient::new().into_boxed_arc());
+        let input = RouterDataInput {
+            token: HMS_TOKEN.to_owned(),
+            channel_id: None,
+            key: None,
+            aps: None,
+            secret: None,
+        };
+
+        let router_data = router.register(&input, "dev").unwrap();
+        assert_eq!(router_data, default_router_data());
+
+        let result = router.register(&input, "unknown-app-id");
+        assert!(
+            matches!(
+                result.as_ref().unwrap_err(),
+                RouterError::Hms(HmsError::InvalidAppId(_))
+            ),
+            "result = {result:?}"
+        );
+    }
+}
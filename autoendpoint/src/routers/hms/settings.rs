@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+use url::Url;
+
+/// Settings for `HmsRouter`
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct HmsSettings {
+    /// The minimum TTL to use for HMS notifications
+    pub min_ttl: u64,
+    /// A JSON dict of `HmsCredential`s. This must be a `String` because
+    /// environment variables cannot encode a `HashMap<String, HmsCredential>`
+    ///
+    /// ```json
+    /// {"_instance_id_":{"app_id": "_hms_app_id_", "client_secret": "_secret_"}, ...}
+    /// ```
+    ///
+    /// `client_id` may also be given when the OAuth client ID differs from
+    /// the HMS app ID.
+    pub credentials: String,
+    /// The max size of notification data in bytes
+    pub max_data: usize,
+    /// The base URL to use for HMS requests
+    pub base_url: Url,
+    /// The URL of the OAuth endpoint issuing HMS access tokens
+    pub token_url: Url,
+    /// The number of seconds to wait for HMS requests to complete
+    pub timeout: usize,
+}
+
+/// Credential information for each application
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct HmsCredential {
+    pub app_id: String,
+    /// The OAuth client ID, defaulting to `app_id`
+    pub client_id: Option<String>,
+    pub client_secret: String,
+}
+
+impl Default for HmsSettings {
+    fn default() -> Self {
+        Self {
+            min_ttl: 60,
+            credentials: "{}".to_string(),
+            max_data: 4096,
+            base_url: Url::parse("https://push-api.cloud.huawei.com").unwrap(),
+            token_url: Url::parse("https://oauth-login.cloud.huawei.com/oauth2/v3/token").unwrap(),
+            timeout: 3,
+        }
+    }
+}
+
+impl HmsSettings {
+    /// Read the credentials from the provided JSON
+    pub fn credentials(&self) -> serde_json::Result<HashMap<String, HmsCredential>> {
+        serde_json::from_str(&self.credentials)
+    }
+}
@@ -5,6 +5,9 @@ use crate::extractors::notification::Notification;
 use crate::extractors::router_data_input::RouterDataInput;
 use crate::routers::apns::error::ApnsError;
 use crate::routers::fcm::error::FcmError;
+use crate::routers::hms::error::HmsError;
+use crate::routers::unifiedpush::error::UnifiedPushError;
+use crate::routers::webhook::error::WebhookError;
 
 use autopush_common::db::error::DbError;
 
@@ -18,10 +21,15 @@ use thiserror::Error;
 #[cfg(feature = "stub")]
 use self::stub::error::StubError;
 pub mod apns;
+pub mod breaker;
 mod common;
 pub mod fcm;
+pub mod hms;
+pub mod retry;
 #[cfg(feature = "stub")]
 pub mod stub;
+pub mod unifiedpush;
+pub mod webhook;
 pub mod webpush;
 
 #[async_trait(?Send)]
@@ -82,6 +90,15 @@ pub enum RouterError {
     #[error(transparent)]
     Fcm(#[from] FcmError),
 
+    #[error(transparent)]
+    Hms(#[from] HmsError),
+
+    #[error(transparent)]
+    UnifiedPush(#[from] UnifiedPushError),
+
+    #[error(transparent)]
+    Webhook(#[from] WebhookError),
+
     #[cfg(feature = "stub")]
     #[error(transparent)]
     Stub(#[from] StubError),
@@ -115,14 +132,46 @@ pub enum RouterError {
 
     #[error("Bridge error, {status}: {message}")]
     Upstream { status: String, message: String },
+
+    #[error("Bridge is failing, circuit breaker is open")]
+    CircuitOpen,
 }
 
 impl RouterError {
+    /// Whether the error is likely temporary, so the notification may be
+    /// delivered if sent again
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RouterError::RequestTimeout | RouterError::Connect(_) | RouterError::CircuitOpen => {
+                true
+            }
+            // FCM reports gRPC style statuses, the other bridges HTTP ones
+            RouterError::Upstream { status, .. } => {
+                status.starts_with('5') || status == "UNAVAILABLE" || status == "INTERNAL"
+            }
+            RouterError::Apns(ApnsError::ApnsUpstream(e)) => match e {
+                a2::Error::ResponseError(response) => response.code >= 500,
+                a2::Error::ConnectionError(_)
+                | a2::Error::ClientError(_)
+                | a2::Error::RequestTimeout(_) => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
     /// Get the associated HTTP status code
     pub fn status(&self) -> StatusCode {
         match self {
             RouterError::Apns(e) => e.status(),
             RouterError::Fcm(e) => StatusCode::from_u16(e.status().as_u16()).unwrap_or_default(),
+            RouterError::Hms(e) => StatusCode::from_u16(e.status().as_u16()).unwrap_or_default(),
+            RouterError::UnifiedPush(e) => {
+                StatusCode::from_u16(e.status().as_u16()).unwrap_or_default()
+            }
+            RouterError::Webhook(e) => {
+                StatusCode::from_u16(e.status().as_u16()).unwrap_or_default()
+            }
 
             RouterError::SaveDb(e, _) => e.status(),
             #[cfg(feature = "stub")]
@@ -132,6 +181,8 @@ impl RouterError {
 
             RouterError::TooMuchData(_) => StatusCode::PAYLOAD_TOO_LARGE,
 
+            RouterError::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE,
+
             RouterError::Authentication
             | RouterError::GCMAuthentication
             | RouterError::RequestTimeout
@@ -145,6 +196,9 @@ impl RouterError {
         match self {
             RouterError::Apns(e) => e.errno(),
             RouterError::Fcm(e) => e.errno(),
+            RouterError::Hms(e) => e.errno(),
+            RouterError::UnifiedPush(e) => e.errno(),
+            RouterError::Webhook(e) => e.errno(),
 
             #[cfg(feature = "stub")]
             RouterError::Stub(e) => e.errno(),
@@ -165,6 +219,8 @@ impl RouterError {
 
             RouterError::GCMAuthentication => Some(904),
 
+            RouterError::CircuitOpen => Some(905),
+
             RouterError::Upstream { .. } => None,
         }
     }
@@ -175,6 +231,9 @@ impl ReportableError for RouterError {
         match &self {
             RouterError::Apns(e) => Some(e),
             RouterError::Fcm(e) => Some(e),
+            RouterError::Hms(e) => Some(e),
+            RouterError::UnifiedPush(e) => Some(e),
+            RouterError::Webhook(e) => Some(e),
             RouterError::SaveDb(e, _) => Some(e),
             _ => None,
         }
@@ -185,6 +244,9 @@ impl ReportableError for RouterError {
             // apns handle_error emits a metric for ApnsError::Unregistered
             RouterError::Apns(e) => e.is_sentry_event(),
             RouterError::Fcm(e) => e.is_sentry_event(),
+            RouterError::Hms(e) => e.is_sentry_event(),
+            RouterError::UnifiedPush(e) => e.is_sentry_event(),
+            RouterError::Webhook(e) => e.is_sentry_event(),
             // common handle_error emits metrics for these
             RouterError::Authentication
             | RouterError::GCMAuthentication
@@ -192,7 +254,8 @@ impl ReportableError for RouterError {
             | RouterError::NotFound
             | RouterError::RequestTimeout
             | RouterError::TooMuchData(_)
-            | RouterError::Upstream { .. } => false,
+            | RouterError::Upstream { .. }
+            | RouterError::CircuitOpen => false,
             RouterError::SaveDb(e, _) => e.is_sentry_event(),
             _ => true,
         }
@@ -205,6 +268,9 @@ impl ReportableError for RouterError {
         match self {
             RouterError::Apns(e) => e.metric_label(),
             RouterError::Fcm(e) => e.metric_label(),
+            RouterError::Hms(e) => e.metric_label(),
+            RouterError::UnifiedPush(e) => e.metric_label(),
+            RouterError::Webhook(e) => e.metric_label(),
             RouterError::TooMuchData(_) => Some("notification.bridge.error.too_much_data"),
             _ => None,
         }
@@ -214,6 +280,7 @@ impl ReportableError for RouterError {
         match &self {
             RouterError::Apns(e) => e.extras(),
             RouterError::Fcm(e) => e.extras(),
+            RouterError::Hms(e) => e.extras(),
             RouterError::SaveDb(e, sub) => {
                 let mut extras = e.extras();
                 if let Some(sub) = sub {
@@ -0,0 +1,816 @@
+//! A queue reattempting bridged notifications after transient failures
+//!
+//! Rather than returning a bridge timeout, connection error or upstream 5xx
+//! to the app server (losing the notification), it's accepted and queued.
+//! Queued notifications are kept in the message storage under the reserved
+//! [RETRY_QUEUE_UAID], along with their retry count and when they're next
+//! due, so they survive restarts. They're reattempted with exponential backoff
+//! until delivered, failing permanently or their TTL expiring.
+//!
+//! The queue is shared by every node: a node claims a due entry by taking
+//! (conditionally deleting) it from storage, so only one node attempts it. The
+//! claimed entry is stored again as a lease, due once the lease lapses, so it's
+//! reattempted should the node stop before the attempt completes.
+
+pub mod settings;
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use autopush_common::db::client::DbClient;
+use autopush_common::db::error::DbResult;
+use autopush_common::notification::Notification as StoredNotification;
+use autopush_common::util::{ms_since_epoch, sec_since_epoch};
+use cadence::{CountedExt, Gauged, StatsdClient};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
+use crate::extractors::notification::Notification;
+use crate::extractors::notification_headers::NotificationHeaders;
+use crate::extractors::routers::{RouterType, Routers};
+use crate::extractors::subscription::Subscription;
+use crate::routers::retry::settings::RetrySettings;
+use crate::routers::{Router, RouterResponse};
+use crate::server::AppState;
+
+/// The reserved user ID the queued notifications are stored under
+pub const RETRY_QUEUE_UAID: Uuid = Uuid::from_u128(0x0000_0000_0000_4000_8000_7265_7472_7921);
+
+/// The longest the worker sleeps between checks for due notifications
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A notification awaiting another delivery attempt
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RetryEntry {
+    uaid: Uuid,
+    channel_id: Uuid,
+    message_id: String,
+    timestamp: u64,
+    sort_key_timestamp: u64,
+    ttl: i64,
+    topic: Option<String>,
+    encoding: Option<String>,
+    encryption: Option<String>,
+    encryption_key: Option<String>,
+    crypto_key: Option<String>,
+    data: Option<String>,
+    /// The number of retries made so far
+    #[serde(default)]
+    attempts: u32,
+    /// When (in milliseconds since the epoch) the entry is next due
+    #[serde(default)]
+    due: u64,
+    /// The entry's key in the message storage
+    #[serde(skip)]
+    sort_key: String,
+}
+
+impl RetryEntry {
+    fn new(notification: &Notification) -> Self {
+        let headers = &notification.headers;
+        RetryEntry {
+            uaid: notification.subscription.user.uaid,
+            channel_id: notification.subscription.channel_id,
+            message_id: notification.message_id.clone(),
+            timestamp: notification.timestamp,
+            sort_key_timestamp: notification.sort_key_timestamp,
+            ttl: headers.ttl,
+            topic: headers.topic.clone(),
+            encoding: headers.encoding.clone(),
+            encryption: headers.encryption.clone(),
+            encryption_key: headers.encryption_key.clone(),
+            crypto_key: headers.crypto_key.clone(),
+            data: notification.data.clone(),
+            attempts: 0,
+            due: 0,
+            sort_key: String::new(),
+        }
+    }
+
+    /// When (in seconds since the epoch) the notification expires
+    fn expiry(&self) -> u64 {
+        self.timestamp + self.ttl.max(0) as u64
+    }
+
+    /// Convert into a record for the message storage, recording its key
+    fn as_stored(&mut self) -> StoredNotification {
+        let stored = StoredNotification {
+            channel_id: Uuid::new_v4(),
+            version: self.message_id.clone(),
+            ttl: self.expiry().saturating_sub(sec_since_epoch()),
+            timestamp: sec_since_epoch(),
+            sortkey_timestamp: Some(ms_since_epoch()),
+            data: Some(serde_json::to_string(self).unwrap()),
+            ..Default::default()
+        };
+        self.sort_key = stored.chidmessageid();
+        stored
+    }
+
+    /// Read a record from the message storage
+    fn from_stored(stored: &StoredNotification) -> serde_json::Result<Self> {
+        let mut entry: Self = serde_json::from_str(stored.data.as_deref().unwrap_or_default())?;
+        entry.sort_key = stored.chidmessageid();
+        Ok(entry)
+    }
+
+    /// Rebuild the notification for the (current) user record
+    fn notification(&self, user: autopush_common::db::User) -> Notification {
+        Notification {
+            message_id: self.message_id.clone(),
+            subscription: Subscription {
+                user,
+                channel_id: self.channel_id,
+                vapid: None,
+                tracking_id: None,
+            },
+            headers: NotificationHeaders {
+                ttl: self.ttl,
+                topic: self.topic.clone(),
+                encoding: self.encoding.clone(),
+                encryption: self.encryption.clone(),
+                encryption_key: self.encryption_key.clone(),
+                crypto_key: self.crypto_key.clone(),
+            },
+            timestamp: self.timestamp,
+            sort_key_timestamp: self.sort_key_timestamp,
+            data: self.data.clone(),
+        }
+    }
+}
+
+/// Queues bridged notifications which failed transiently, reattempting them
+/// with exponential backoff
+pub struct RetryQueue {
+    settings: RetrySettings,
+    db: Box<dyn DbClient>,
+    metrics: Arc<StatsdClient>,
+    /// The entries queued by, or polled from storage by, this node
+    pending: Mutex<Vec<RetryEntry>>,
+    /// Limits the retries in flight for each bridge platform
+    limits: Mutex<HashMap<RouterType, Arc<Semaphore>>>,
+}
+
+impl RetryQueue {
+    /// Create a new `RetryQueue`
+    pub fn new(settings: RetrySettings, db: Box<dyn DbClient>, metrics: Arc<StatsdClient>) -> Self {
+        Self {
+            settings,
+            db,
+            metrics,
+            pending: Mutex::new(Vec::new()),
+            limits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether failed notifications are queued at all
+    pub fn enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    /// Whether a notification which failed with `error` should be queued
+    pub fn should_retry(
+        &self,
+        error: &ApiError,
+        notification: &Notification,
+        router_type: RouterType,
+    ) -> bool {
+        self.settings.enabled
+            && router_type != RouterType::WebPush
+            && notification.headers.ttl > 0
+            && matches!(&error.kind, ApiErrorKind::Router(e) if e.is_transient())
+    }
+
+    /// Queue a notification, returning the response for the app server
+    ///
+    /// The notification isn't stored for its user (until it's delivered), so
+    /// there's no message resource (`Location`) for the app server to delete.
+    pub async fn enqueue(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        let mut entry = RetryEntry::new(notification);
+        entry.due = ms_since_epoch() + self.backoff(0);
+        self.db
+            .save_message(&RETRY_QUEUE_UAID, entry.as_stored())
+            .await?;
+        let platform = notification.subscription.user.router_type.clone();
+        debug!(
+            "Queued {} notification {} for retry",
+            platform, entry.message_id
+        );
+        self.schedule(entry);
+        self.incr_metric(&platform, "queued");
+
+        Ok(RouterResponse {
+            status: StatusCode::ACCEPTED,
+            headers: {
+                let mut map = HashMap::new();
+                map.insert("TTL", notification.headers.ttl.to_string());
+                map
+            },
+            body: None,
+        })
+    }
+
+    /// Queue the stored notifications not already queued, e.g. those queued
+    /// by other nodes. Entries another node has claimed since are skipped
+    /// when they fall due.
+    pub async fn poll(&self) -> DbResult<()> {
+        let response = self
+            .db
+            .fetch_timestamp_messages(&RETRY_QUEUE_UAID, None, 0)
+            .await?;
+        let mut loaded = 0;
+        for stored in response.messages {
+            let sort_key = stored.chidmessageid();
+            let queued = self
+                .pending
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|entry| entry.sort_key == sort_key);
+            if queued {
+                continue;
+            }
+            match RetryEntry::from_stored(&stored) {
+                Ok(entry) => {
+                    self.schedule(entry);
+                    loaded += 1;
+                }
+                Err(e) => {
+                    warn!("Dropping unreadable retry queue entry: {}", e);
+                    self.remove(&sort_key).await;
+                }
+            }
+        }
+        if loaded > 0 {
+            info!("Loaded {} queued bridge retries", loaded);
+        }
+        Ok(())
+    }
+
+    /// The number of queued notifications
+    fn len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// The delay (in milliseconds) before the retry following `attempts`
+    fn backoff(&self, attempts: u32) -> u64 {
+        self.settings
+            .initial_delay_millis
+            .saturating_mul(1u64.checked_shl(attempts).unwrap_or(u64::MAX))
+            .min(self.settings.max_delay_millis)
+    }
+
+    fn schedule(&self, entry: RetryEntry) {
+        self.pending.lock().unwrap().push(entry);
+    }
+
+    /// Claim an entry for this node to attempt, by taking it from storage and
+    /// storing it again as a lease. Returns `None` when another node claimed
+    /// it first.
+    async fn claim(&self, mut entry: RetryEntry) -> Option<RetryEntry> {
+        match self
+            .db
+            .take_message(&RETRY_QUEUE_UAID, &entry.sort_key)
+            .await
+        {
+            Ok(true) => (),
+            Ok(false) => {
+                debug!("Retry of {} claimed by another node", entry.message_id);
+                return None;
+            }
+            Err(e) => {
+                // Try again later
+                warn!("Error claiming retry queue entry: {}", e);
+                entry.due = ms_since_epoch() + self.backoff(entry.attempts + 1);
+                self.schedule(entry);
+                return None;
+            }
+        }
+        entry.due = ms_since_epoch() + self.settings.lease_secs * 1000;
+        if let Err(e) = self
+            .db
+            .save_message(&RETRY_QUEUE_UAID, entry.as_stored())
+            .await
+        {
+            // Attempt it regardless, this node holds the only copy
+            warn!("Error saving retry queue lease: {}", e);
+        }
+        Some(entry)
+    }
+
+    /// Store a claimed entry with its new retry count and due time, replacing
+    /// its lease, and queue it. When it can't be stored, the lease is kept to
+    /// be reattempted once it lapses.
+    async fn reschedule(&self, mut entry: RetryEntry, due: u64) {
+        let lease = entry.sort_key.clone();
+        entry.due = due;
+        if let Err(e) = self
+            .db
+            .save_message(&RETRY_QUEUE_UAID, entry.as_stored())
+            .await
+        {
+            warn!("Error saving retry queue entry: {}", e);
+            return;
+        }
+        self.schedule(entry);
+        self.remove(&lease).await;
+    }
+
+    /// Remove the entries due by `now`
+    fn take_due(&self, now: u64) -> Vec<RetryEntry> {
+        let mut pending = self.pending.lock().unwrap();
+        let (due, waiting) = pending.drain(..).partition(|entry| entry.due <= now);
+        *pending = waiting;
+        due
+    }
+
+    /// How long until the worker should next check for due entries. New
+    /// entries are never due sooner than the initial delay.
+    fn next_poll(&self, now: u64) -> Duration {
+        let next = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.due)
+            .min()
+            .unwrap_or(u64::MAX)
+            .min(now + self.settings.initial_delay_millis);
+        Duration::from_millis(next.saturating_sub(now)).min(MAX_POLL_INTERVAL)
+    }
+
+    fn limit(&self, router_type: RouterType) -> Arc<Semaphore> {
+        self.limits
+            .lock()
+            .unwrap()
+            .entry(router_type)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.settings.max_concurrency)))
+            .clone()
+    }
+
+    async fn remove(&self, sort_key: &str) {
+        if let Err(e) = self.db.remove_message(&RETRY_QUEUE_UAID, sort_key).await {
+            warn!("Error removing retry queue entry: {}", e);
+        }
+    }
+
+    fn incr_metric(&self, platform: &str, result: &str) {
+        self.metrics
+            .incr_with_tags("notification.bridge.retry")
+            .with_tag("platform", platform)
+            .with_tag("result", result)
+            .send();
+    }
+
+    /// Reattempt delivering an entry, using the router `router_for` returns
+    /// for the user's current router type
+    async fn attempt<'r>(
+        &self,
+        mut entry: RetryEntry,
+        router_for: impl Fn(RouterType) -> &'r dyn Router,
+    ) {
+        let user = match self.db.get_user(&entry.uaid).await {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                debug!("Dropping retry for removed user {}", entry.uaid);
+                self.remove(&entry.sort_key).await;
+                self.incr_metric("unknown", "dropped");
+                return;
+            }
+            Err(e) => {
+                // Try again later, leaving the stored entry for any node
+                warn!("Error fetching user for retry: {}", e);
+                entry.due = ms_since_epoch() + self.backoff(entry.attempts + 1);
+                self.schedule(entry);
+                return;
+            }
+        };
+        let platform = user.router_type.clone();
+        let Ok(router_type) = RouterType::from_str(&platform) else {
+            self.remove(&entry.sort_key).await;
+            self.incr_metric(&platform, "failed");
+            return;
+        };
+
+        let limit = self.limit(router_type);
+        let _permit = limit.acquire().await.expect("Retry limit closed");
+        if entry.expiry() <= sec_since_epoch() {
+            self.remove(&entry.sort_key).await;
+            self.incr_metric(&platform, "expired");
+            return;
+        }
+        let Some(mut entry) = self.claim(entry).await else {
+            return;
+        };
+
+        let notification = entry.notification(user);
+        match router_for(router_type)
+            .route_notification(&notification)
+            .await
+        {
+            Ok(_) => {
+                debug!("Retried notification {} delivered", entry.message_id);
+                self.remove(&entry.sort_key).await;
+                self.incr_metric(&platform, "success");
+            }
+            Err(e) if matches!(&e.kind, ApiErrorKind::Router(e) if e.is_transient()) => {
+                entry.attempts += 1;
+                let due = ms_since_epoch() + self.backoff(entry.attempts);
+                if due / 1000 >= entry.expiry() {
+                    self.remove(&entry.sort_key).await;
+                    self.incr_metric(&platform, "expired");
+                } else {
+                    self.reschedule(entry, due).await;
+                    self.incr_metric(&platform, "retried");
+                }
+            }
+            Err(e) => {
+                debug!("Retried notification {} failed: {}", entry.message_id, e);
+                self.remove(&entry.sort_key).await;
+                self.incr_metric(&platform, "failed");
+            }
+        }
+    }
+}
+
+/// Spawn the task periodically loading the stored retry queue and
+/// reattempting its notifications as they fall due
+pub fn spawn_retry_worker(app_state: AppState) {
+    actix_rt::spawn(async move {
+        let queue = app_state.retry_queue.clone();
+        let poll_interval = Duration::from_secs(queue.settings.poll_interval_secs);
+        let mut last_poll: Option<Instant> = None;
+        let routers = Rc::new(Routers::new(&app_state));
+        loop {
+            if last_poll.is_none_or(|at| at.elapsed() >= poll_interval) {
+                if let Err(e) = queue.poll().await {
+                    error!("Error loading the bridge retry queue: {}", e);
+                }
+                last_poll = Some(Instant::now());
+            }
+            let now = ms_since_epoch();
+            for entry in queue.take_due(now) {
+                let queue = queue.clone();
+                let routers = routers.clone();
+                actix_rt::spawn(async move {
+                    queue.attempt(entry, |rt| routers.get(rt)).await;
+                });
+            }
+            let _ = app_state
+                .metrics
+                .gauge("notification.bridge.retry.pending", queue.len() as u64);
+            actix_rt::time::sleep(queue.next_poll(now)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryEntry, RetryQueue, RETRY_QUEUE_UAID};
+    use crate::error::{ApiError, ApiResult};
+    use crate::extractors::notification::Notification;
+    use crate::extractors::router_data_input::RouterDataInput;
+    use crate::extractors::routers::RouterType;
+    use crate::routers::common::tests::make_notification;
+    use crate::routers::retry::settings::RetrySettings;
+    use crate::routers::{Router, RouterError, RouterResponse};
+    use async_trait::async_trait;
+    use autopush_common::db::client::{DbClient, FetchMessageResponse};
+    use autopush_common::db::error::DbError;
+    use autopush_common::db::mock::MockDbClient;
+    use autopush_common::notification::Notification as StoredNotification;
+    use autopush_common::util::sec_since_epoch;
+    use cadence::StatsdClient;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// A router failing with the returned error, if any
+    struct TestRouter(fn() -> Option<RouterError>);
+
+    #[async_trait(?Send)]
+    impl Router for TestRouter {
+        fn register(
+            &self,
+            _: &RouterDataInput,
+            _: &str,
+        ) -> Result<HashMap<String, serde_json::Value>, RouterError> {
+            Ok(HashMap::new())
+        }
+
+        async fn route_notification(&self, _: &Notification) -> ApiResult<RouterResponse> {
+            match (self.0)() {
+                Some(e) => Err(e.into()),
+                None => Ok(RouterResponse::success("".to_owned(), 60)),
+            }
+        }
+    }
+
+    fn make_queue(db: Box<dyn DbClient>) -> RetryQueue {
+        RetryQueue::new(
+            RetrySettings {
+                enabled: true,
+                ..Default::default()
+            },
+            db,
+            Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+        )
+    }
+
+    /// A live FCM notification
+    fn fcm_notification() -> Notification {
+        let mut notification = make_notification(HashMap::new(), None, RouterType::FCM);
+        notification.headers.ttl = 60;
+        notification.timestamp = sec_since_epoch();
+        notification
+    }
+
+    /// An entry as stored by `enqueue`
+    fn make_entry(notification: &Notification) -> RetryEntry {
+        let mut entry = RetryEntry::new(notification);
+        entry.as_stored();
+        entry
+    }
+
+    #[test]
+    fn backoff() {
+        let queue = make_queue(MockDbClient::new().into_boxed_arc());
+        assert_eq!(queue.backoff(0), 1000);
+        assert_eq!(queue.backoff(1), 2000);
+        assert_eq!(queue.backoff(4), 16000);
+        assert_eq!(queue.backoff(9), 300_000);
+        assert_eq!(queue.backoff(100), 300_000);
+    }
+
+    #[test]
+    fn transient_errors() {
+        assert!(RouterError::RequestTimeout.is_transient());
+        for status in ["503 Service Unavailable", "UNAVAILABLE", "INTERNAL"] {
+            let error = RouterError::Upstream {
+                status: status.to_owned(),
+                message: "test-message".to_owned(),
+            };
+            assert!(error.is_transient(), "{status}");
+        }
+        let error = RouterError::Upstream {
+            status: "INVALID_ARGUMENT".to_owned(),
+            message: "test-message".to_owned(),
+        };
+        assert!(!error.is_transient());
+        assert!(!RouterError::NotFound.is_transient());
+        assert!(!RouterError::Authentication.is_transient());
+    }
+
+    #[test]
+    fn should_retry() {
+        let queue = make_queue(MockDbClient::new().into_boxed_arc());
+        let notification = fcm_notification();
+        let timeout = ApiError::from(RouterError::RequestTimeout);
+
+        assert!(queue.should_retry(&timeout, &notification, RouterType::FCM));
+        assert!(!queue.should_retry(&timeout, &notification, RouterType::WebPush));
+        assert!(!queue.should_retry(
+            &ApiError::from(RouterError::NotFound),
+            &notification,
+            RouterType::FCM
+        ));
+
+        let mut expiring = notification.clone();
+        expiring.headers.ttl = 0;
+        assert!(!queue.should_retry(&timeout, &expiring, RouterType::FCM));
+
+        let disabled = RetryQueue::new(
+            RetrySettings::default(),
+            MockDbClient::new().into_boxed_arc(),
+            Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+        );
+        assert!(!disabled.should_retry(&timeout, &notification, RouterType::FCM));
+    }
+
+    /// Queued notifications are stored, and queued again from storage
+    #[tokio::test]
+    async fn enqueue_and_load() {
+        let stored = Arc::new(Mutex::new(None::<StoredNotification>));
+        let mut db = MockDbClient::new();
+        let saved = stored.clone();
+        db.expect_save_message()
+            .withf(|uaid, _| *uaid == RETRY_QUEUE_UAID)
+            .times(1)
+            .returning(move |_, message| {
+                *saved.lock().unwrap() = Some(message);
+                Ok(())
+            });
+        let queue = make_queue(db.into_boxed_arc());
+        let notification = fcm_notification();
+
+        let response = queue.enqueue(&notification).await.unwrap();
+        assert_eq!(response.status, actix_web::http::StatusCode::ACCEPTED);
+        // Queued notifications can't be deleted by the app server
+        assert!(!response.headers.contains_key("Location"));
+        assert_eq!(queue.len(), 1);
+        // Not due until after the initial delay
+        assert!(queue.take_due(super::ms_since_epoch()).is_empty());
+        let due = queue.take_due(u64::MAX)[0].due;
+
+        let stored = stored.lock().unwrap().take().unwrap();
+        let mut db = MockDbClient::new();
+        let messages = vec![stored.clone()];
+        db.expect_fetch_timestamp_messages()
+            .withf(|uaid, _, _| *uaid == RETRY_QUEUE_UAID)
+            .times(2)
+            .returning(move |_, _, _| {
+                Ok(FetchMessageResponse {
+                    timestamp: None,
+                    messages: messages.clone(),
+                })
+            });
+        let queue = make_queue(db.into_boxed_arc());
+        queue.poll().await.unwrap();
+        // Entries already queued aren't queued again
+        queue.poll().await.unwrap();
+        assert_eq!(queue.len(), 1);
+
+        // Still not due before the stored time
+        assert!(queue.take_due(due - 1).is_empty());
+        let loaded = queue.take_due(due);
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].uaid, notification.subscription.user.uaid);
+        assert_eq!(loaded[0].message_id, "test-message-id");
+        assert_eq!(loaded[0].ttl, 60);
+        assert_eq!(loaded[0].sort_key, stored.chidmessageid());
+    }
+
+    /// Expect an entry to be claimed, returning the records saved after
+    fn expect_claim(
+        db: &mut MockDbClient,
+        entry: &RetryEntry,
+    ) -> Arc<Mutex<Vec<StoredNotification>>> {
+        let sort_key = entry.sort_key.clone();
+        db.expect_take_message()
+            .withf(move |uaid, key| *uaid == RETRY_QUEUE_UAID && key == sort_key)
+            .times(1)
+            .return_once(|_, _| Ok(true));
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let saved = stored.clone();
+        db.expect_save_message()
+            .withf(|uaid, _| *uaid == RETRY_QUEUE_UAID)
+            .returning(move |_, message| {
+                saved.lock().unwrap().push(message);
+                Ok(())
+            });
+        stored
+    }
+
+    /// Expect the lease (the first record saved) to be removed
+    fn expect_lease_removed(db: &mut MockDbClient, stored: &Arc<Mutex<Vec<StoredNotification>>>) {
+        let stored = stored.clone();
+        db.expect_remove_message()
+            .withf(move |uaid, key| {
+                *uaid == RETRY_QUEUE_UAID && key == stored.lock().unwrap()[0].chidmessageid()
+            })
+            .times(1)
+            .return_once(|_, _| Ok(()));
+    }
+
+    /// Delivered notifications are claimed, then removed from storage
+    #[tokio::test]
+    async fn attempt_success() {
+        let notification = fcm_notification();
+        let entry = make_entry(&notification);
+        let mut db = MockDbClient::new();
+        let user = notification.subscription.user.clone();
+        db.expect_get_user()
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        let stored = expect_claim(&mut db, &entry);
+        expect_lease_removed(&mut db, &stored);
+        let queue = make_queue(db.into_boxed_arc());
+
+        let router = TestRouter(|| None);
+        queue.attempt(entry, |_| &router).await;
+        assert_eq!(queue.len(), 0);
+
+        // The lease lapses after the attempt would have completed
+        let stored = stored.lock().unwrap();
+        assert_eq!(stored.len(), 1);
+        let lease = RetryEntry::from_stored(&stored[0]).unwrap();
+        assert!(lease.due >= super::ms_since_epoch() + 59_000);
+    }
+
+    /// Entries claimed by another node aren't attempted
+    #[tokio::test]
+    async fn attempt_claimed_elsewhere() {
+        let notification = fcm_notification();
+        let entry = make_entry(&notification);
+        let mut db = MockDbClient::new();
+        let user = notification.subscription.user.clone();
+        db.expect_get_user()
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        db.expect_take_message()
+            .times(1)
+            .return_once(|_, _| Ok(false));
+        let queue = make_queue(db.into_boxed_arc());
+
+        let router = TestRouter(|| panic!("Claimed notifications aren't routed"));
+        queue.attempt(entry, |_| &router).await;
+        assert_eq!(queue.len(), 0);
+    }
+
+    /// Transient failures are stored with their retry count and rescheduled
+    /// with backoff, replacing the lease
+    #[tokio::test]
+    async fn attempt_transient_failure() {
+        let notification = fcm_notification();
+        let entry = make_entry(&notification);
+        let mut db = MockDbClient::new();
+        let user = notification.subscription.user.clone();
+        db.expect_get_user()
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        let stored = expect_claim(&mut db, &entry);
+        expect_lease_removed(&mut db, &stored);
+        let queue = make_queue(db.into_boxed_arc());
+
+        let router = TestRouter(|| Some(RouterError::RequestTimeout));
+        queue.attempt(entry, |_| &router).await;
+        assert_eq!(queue.len(), 1);
+        let entry = queue.pending.lock().unwrap()[0].clone();
+        assert_eq!(entry.attempts, 1);
+        assert!(entry.due >= super::ms_since_epoch() + 1000);
+
+        let stored = stored.lock().unwrap();
+        assert_eq!(stored.len(), 2);
+        let rescheduled = RetryEntry::from_stored(&stored[1]).unwrap();
+        assert_eq!(rescheduled.attempts, 1);
+        assert_eq!(rescheduled.due, entry.due);
+        assert_eq!(rescheduled.sort_key, entry.sort_key);
+    }
+
+    /// Failing to fetch the user leaves the stored entry for a later attempt
+    #[tokio::test]
+    async fn attempt_user_error() {
+        let notification = fcm_notification();
+        let entry = make_entry(&notification);
+        let sort_key = entry.sort_key.clone();
+        let mut db = MockDbClient::new();
+        db.expect_get_user()
+            .times(1)
+            .return_once(|_| Err(DbError::General("test-error".to_owned())));
+        let queue = make_queue(db.into_boxed_arc());
+
+        let router = TestRouter(|| panic!("Not routed without the user"));
+        queue.attempt(entry, |_| &router).await;
+        let entry = queue.pending.lock().unwrap()[0].clone();
+        assert_eq!(entry.sort_key, sort_key);
+        assert_eq!(entry.attempts, 0);
+        assert!(entry.due >= super::ms_since_epoch() + 1000);
+    }
+
+    /// Permanent failures are dropped after claiming them
+    #[tokio::test]
+    async fn attempt_failed() {
+        let notification = fcm_notification();
+        let entry = make_entry(&notification);
+        let mut db = MockDbClient::new();
+        let user = notification.subscription.user.clone();
+        db.expect_get_user()
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        let stored = expect_claim(&mut db, &entry);
+        expect_lease_removed(&mut db, &stored);
+        let queue = make_queue(db.into_boxed_arc());
+
+        let router = TestRouter(|| Some(RouterError::NotFound));
+        queue.attempt(entry, |_| &router).await;
+        assert_eq!(queue.len(), 0);
+    }
+
+    /// Expired notifications are dropped without claiming them
+    #[tokio::test]
+    async fn attempt_expired() {
+        let mut notification = fcm_notification();
+        notification.timestamp -= 60;
+        let entry = make_entry(&notification);
+        let mut db = MockDbClient::new();
+        let user = notification.subscription.user.clone();
+        db.expect_get_user()
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        let sort_key = entry.sort_key.clone();
+        db.expect_remove_message()
+            .withf(move |uaid, key| *uaid == RETRY_QUEUE_UAID && key == sort_key)
+            .times(1)
+            .return_once(|_, _| Ok(()));
+        let queue = make_queue(db.into_boxed_arc());
+
+        let router = TestRouter(|| panic!("Expired notifications aren't routed"));
+        queue.attempt(entry, |_| &router).await;
+        assert_eq!(queue.len(), 0);
+    }
+}
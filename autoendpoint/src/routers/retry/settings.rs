@@ -0,0 +1,37 @@
+/// Settings for the bridge `RetryQueue`
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct RetrySettings {
+    /// Whether bridged notifications failing with a transient error (a
+    /// timeout, connection error or upstream 5xx) are queued to be
+    /// reattempted, rather than returning the error to the app server
+    pub enabled: bool,
+    /// The delay (in milliseconds) before the first retry, doubling for each
+    /// retry after
+    pub initial_delay_millis: u64,
+    /// The maximum delay (in milliseconds) between retries
+    pub max_delay_millis: u64,
+    /// The maximum number of retries in flight for each bridge platform
+    pub max_concurrency: usize,
+    /// How often (in seconds) the stored queue is checked for notifications
+    /// this node hasn't queued, e.g. queued by other nodes
+    pub poll_interval_secs: u64,
+    /// How long (in seconds) a node's claim on a notification it's
+    /// reattempting lasts. Should the node stop before the attempt completes,
+    /// the notification is reattempted (by any node) once it lapses.
+    pub lease_secs: u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            initial_delay_millis: 1000,
+            max_delay_millis: 300_000,
+            max_concurrency: 16,
+            poll_interval_secs: 60,
+            lease_secs: 60,
+        }
+    }
+}
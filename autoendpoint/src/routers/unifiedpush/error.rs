@@ -0,0 +1,82 @@
+use crate::error::ApiErrorKind;
+use crate::routers::RouterError;
+
+use autopush_common::errors::ReportableError;
+use reqwest::StatusCode;
+
+/// Errors that may occur in the UnifiedPush router
+#[derive(thiserror::Error, Debug)]
+pub enum UnifiedPushError {
+    #[error("Failed to decode the UnifiedPush settings")]
+    SettingsDecode(#[from] serde_json::Error),
+
+    #[error("Error while building the HTTP client")]
+    HttpClientBuild(#[source] reqwest::Error),
+
+    #[error("Invalid distributor endpoint: {0}")]
+    InvalidEndpoint(String),
+
+    #[error("No distributor endpoint found for user")]
+    NoEndpoint,
+
+    #[error("Distributor endpoint is no longer allowed: {0}")]
+    EndpointNotAllowed(String),
+
+    #[error("Notification data is not valid base64")]
+    InvalidData(#[source] base64::DecodeError),
+}
+
+impl UnifiedPushError {
+    /// Get the associated HTTP status code
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UnifiedPushError::InvalidEndpoint(_) => StatusCode::BAD_REQUEST,
+
+            UnifiedPushError::NoEndpoint | UnifiedPushError::EndpointNotAllowed(_) => {
+                StatusCode::GONE
+            }
+
+            UnifiedPushError::SettingsDecode(_)
+            | UnifiedPushError::HttpClientBuild(_)
+            | UnifiedPushError::InvalidData(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Get the associated error number
+    pub fn errno(&self) -> Option<usize> {
+        match self {
+            UnifiedPushError::NoEndpoint | UnifiedPushError::EndpointNotAllowed(_) => Some(106),
+
+            UnifiedPushError::InvalidEndpoint(_)
+            | UnifiedPushError::SettingsDecode(_)
+            | UnifiedPushError::HttpClientBuild(_)
+            | UnifiedPushError::InvalidData(_) => None,
+        }
+    }
+}
+
+impl From<UnifiedPushError> for ApiErrorKind {
+    fn from(e: UnifiedPushError) -> Self {
+        ApiErrorKind::Router(RouterError::UnifiedPush(e))
+    }
+}
+
+impl ReportableError for UnifiedPushError {
+    fn is_sentry_event(&self) -> bool {
+        matches!(
+            &self,
+            UnifiedPushError::SettingsDecode(_)
+                | UnifiedPushError::HttpClientBuild(_)
+                | UnifiedPushError::InvalidData(_)
+        )
+    }
+
+    fn metric_label(&self) -> Option<&'static str> {
+        match &self {
+            UnifiedPushError::InvalidEndpoint(_) => {
+                Some("notification.bridge.error.unifiedpush.badendpoint")
+            }
+            _ => None,
+        }
+    }
+}
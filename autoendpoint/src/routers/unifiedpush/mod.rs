@@ -0,0 +1,6 @@
+//! A notification router for UnifiedPush distributors, used by Android
+//! devices without Google services
+
+pub mod error;
+pub mod router;
+pub mod settings;
@@ -0,0 +1,445 @@
+use autopush_common::db::client::DbClient;
+use autopush_common::util::b64_decode_url;
+
+use crate::error::ApiResult;
+use crate::extractors::notification::Notification;
+use crate::extractors::router_data_input::RouterDataInput;
+use crate::routers::common::{
+    endpoint_http_client, handle_error, incr_error_metric, incr_success_metrics, send_to_endpoint,
+    EndpointAllowlist,
+};
+use crate::routers::unifiedpush::error::UnifiedPushError;
+use crate::routers::unifiedpush::settings::UnifiedPushSettings;
+use crate::routers::{Router, RouterError, RouterResponse};
+use async_trait::async_trait;
+use cadence::StatsdClient;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use url::Url;
+
+/// UnifiedPush distributor router. Notifications are forwarded, still
+/// encrypted, to the distributor endpoint the user registered with.
+pub struct UnifiedPushRouter {
+    endpoint_url: Url,
+    metrics: Arc<StatsdClient>,
+    db: Box<dyn DbClient>,
+    http: reqwest::Client,
+    allowlist: EndpointAllowlist,
+}
+
+impl UnifiedPushRouter {
+    /// Create a new `UnifiedPushRouter`
+    pub fn new(
+        settings: UnifiedPushSettings,
+        endpoint_url: Url,
+        metrics: Arc<StatsdClient>,
+        db: Box<dyn DbClient>,
+    ) -> Result<Self, UnifiedPushError> {
+        let http =
+            endpoint_http_client(settings.timeout).map_err(UnifiedPushError::HttpClientBuild)?;
+        Ok(Self {
+            endpoint_url,
+            metrics,
+            db,
+            http,
+            allowlist: settings.allowlist()?,
+        })
+    }
+
+    /// if we allow any hosts, this connection is "active"
+    pub fn active(&self) -> bool {
+        self.allowlist.active()
+    }
+
+    /// Forward the notification to the distributor
+    async fn send(&self, endpoint: &str, notification: &Notification) -> Result<(), RouterError> {
+        let headers = &notification.headers;
+        let mut request = self
+            .http
+            .post(endpoint)
+            .header("TTL", headers.ttl.to_string());
+        for (name, value) in [
+            ("Topic", &headers.topic),
+            ("Content-Encoding", &headers.encoding),
+            ("Encryption", &headers.encryption),
+            ("Encryption-Key", &headers.encryption_key),
+            ("Crypto-Key", &headers.crypto_key),
+        ] {
+            if let Some(value) = value {
+                request = request.header(name, value);
+            }
+        }
+        if let Some(data) = &notification.data {
+            let body = b64_decode_url(data).map_err(UnifiedPushError::InvalidData)?;
+            request = request.body(body);
+        }
+
+        send_to_endpoint(
+            request,
+            notification.data.as_ref().map(String::len).unwrap_or(0),
+        )
+        .await
+    }
+}
+
+#[async_trait(?Send)]
+impl Router for UnifiedPushRouter {
+    fn register(
+        &self,
+        router_data_input: &RouterDataInput,
+        app_id: &str,
+    ) -> Result<HashMap<String, Value>, RouterError> {
+        // The registration token is the distributor's endpoint URL
+        let endpoint = self
+            .allowlist
+            .check(&router_data_input.token)
+            .map_err(UnifiedPushError::InvalidEndpoint)?;
+
+        let mut router_data = HashMap::new();
+        router_data.insert(
+            "endpoint".to_string(),
+            serde_json::to_value(endpoint.as_str()).unwrap(),
+        );
+        router_data.insert("app_id".to_string(), serde_json::to_value(app_id).unwrap());
+
+        Ok(router_data)
+    }
+
+    async fn route_notification(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        debug!(
+            "Sending UnifiedPush notification to UAID {}",
+            notification.subscription.user.uaid
+        );
+        trace!("Notification = {:?}", notification);
+
+        let router_data = notification
+            .subscription
+            .user
+            .router_data
+            .as_ref()
+            .ok_or(UnifiedPushError::NoEndpoint)?;
+        let endpoint = router_data
+            .get("endpoint")
+            .and_then(Value::as_str)
+            .ok_or(UnifiedPushError::NoEndpoint)?;
+        let app_id = router_data
+            .get("app_id")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+
+        let platform = "unifiedpush";
+        trace!("Sending message to {platform}: [{:?}]", &app_id);
+        // The user's kept if the endpoint's no longer allowed, in case the
+        // allowlist change was a mistake
+        if let Err(e) = self.allowlist.check(endpoint) {
+            warn!("UnifiedPush endpoint no longer allowed: {}", e);
+            let error = RouterError::UnifiedPush(UnifiedPushError::EndpointNotAllowed(e));
+            incr_error_metric(
+                &self.metrics,
+                platform,
+                app_id,
+                "endpoint_not_allowed",
+                error.status(),
+                error.errno(),
+            );
+            return Err(error.into());
+        }
+        if let Err(e) = self.send(endpoint, notification).await {
+            return Err(handle_error(
+                e,
+                &self.metrics,
+                self.db.as_ref(),
+                platform,
+                app_id,
+                notification.subscription.user.uaid,
+                notification.subscription.vapid.clone(),
+            )
+            .await);
+        };
+        incr_success_metrics(&self.metrics, platform, app_id, notification);
+        // Sent successfully, update metrics and make response
+        trace!("Send request was successful");
+
+        Ok(RouterResponse::success(
+            self.endpoint_url
+                .join(&format!("/m/{}", notification.message_id))
+                .expect("Message ID is not URL-safe")
+                .to_string(),
+            notification.headers.ttl as usize,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ApiErrorKind;
+    use crate::extractors::router_data_input::RouterDataInput;
+    use crate::extractors::routers::RouterType;
+    use crate::routers::common::tests::make_notification;
+    use crate::routers::unifiedpush::error::UnifiedPushError;
+    use crate::routers::unifiedpush::router::UnifiedPushRouter;
+    use crate::routers::unifiedpush::settings::UnifiedPushSettings;
+    use crate::routers::RouterError;
+    use crate::routers::{Router, RouterResponse};
+    use autopush_common::db::client::DbClient;
+    use autopush_common::db::mock::MockDbClient;
+    use autopush_common::util::b64_encode_url;
+    use std::sync::Arc;
+
+    use cadence::StatsdClient;
+    use mockall::predicate;
+    use std::collections::HashMap;
+    use url::Url;
+
+    /// Create a router for testing, allowing the given schemes and hosts
+    fn make_router(schemes: &str, hosts: &str, db: Box<dyn DbClient>) -> UnifiedPushRouter {
+        UnifiedPushRouter::new(
+            UnifiedPushSettings {
+                allowed_schemes: schemes.to_owned(),
+                allowed_hosts: hosts.to_owned(),
+                ..Default::default()
+            },
+            Url::parse("http://localhost:8080/").unwrap(),
+            Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+            db,
+        )
+        .unwrap()
+    }
+
+    /// Create a router allowing the mock distributor
+    fn make_mock_router(db: Box<dyn DbClient>) -> UnifiedPushRouter {
+        make_router(r#"["http"]"#, r#"["127.0.0.1"]"#, db)
+    }
+
+    /// Create user router data for the mock distributor
+    fn router_data(server: &mockito::ServerGuard) -> HashMap<String, serde_json::Value> {
+        let mut map = HashMap::new();
+        map.insert(
+            "endpoint".to_string(),
+            serde_json::to_value(format!("{}/up/test-token", server.url())).unwrap(),
+        );
+        map.insert("app_id".to_string(), serde_json::to_value("dev").unwrap());
+        map
+    }
+
+    /// Registration input with the given endpoint as its token
+    fn register_input(token: &str) -> RouterDataInput {
+        RouterDataInput {
+            token: token.to_owned(),
+            channel_id: None,
+            key: None,
+            aps: None,
+            secret: None,
+        }
+    }
+
+    /// Registration stores allowed endpoints
+    #[test]
+    fn register() {
+        let router = make_router(
+            r#"["https"]"#,
+            r#"["ntfy.sh", ".push.example.com"]"#,
+            MockDbClient::new().into_boxed_arc(),
+        );
+        assert!(router.active());
+
+        for endpoint in [
+            "https://ntfy.sh/upAbC123?up=1",
+            "https://eu.push.example.com/up/abc",
+        ] {
+            let data = router.register(&register_input(endpoint), "dev").unwrap();
+            assert_eq!(data["endpoint"], endpoint);
+            assert_eq!(data["app_id"], "dev");
+        }
+    }
+
+    /// Registration rejects endpoints outside the allowed schemes and hosts
+    #[test]
+    fn register_not_allowed() {
+        let router = make_router(
+            r#"["https"]"#,
+            r#"["ntfy.sh", ".push.example.com"]"#,
+            MockDbClient::new().into_boxed_arc(),
+        );
+
+        for endpoint in [
+            "http://ntfy.sh/upAbC123",
+            "https://evil.example.org/up",
+            "https://notntfy.sh/up",
+            "https://evilpush.example.com/up",
+            "not a url",
+        ] {
+            let result = router.register(&register_input(endpoint), "dev");
+            assert!(
+                matches!(
+                    result,
+                    Err(RouterError::UnifiedPush(UnifiedPushError::InvalidEndpoint(
+                        _
+                    )))
+                ),
+                "{endpoint}: result = {result:?}"
+            );
+        }
+    }
+
+    /// Nothing is allowed until hosts are configured, "*" allows any
+    #[test]
+    fn allowed_hosts() {
+        let db = MockDbClient::new().into_boxed_arc();
+        let router = make_router(r#"["https"]"#, "[]", db.clone());
+        assert!(!router.active());
+        assert!(router
+            .register(&register_input("https://ntfy.sh/up"), "dev")
+            .is_err());
+
+        let router = make_router(r#"["https"]"#, r#"["*"]"#, db);
+        assert!(router
+            .register(&register_input("https://ntfy.sh/up"), "dev")
+            .is_ok());
+    }
+
+    /// The encrypted body and its headers are forwarded to the distributor
+    #[tokio::test]
+    async fn successful_routing() {
+        let mut server = mockito::Server::new_async().await;
+
+        let router = make_mock_router(MockDbClient::new().into_boxed_arc());
+        let body = b"encrypted-test-data".to_vec();
+        let distributor_mock = server
+            .mock("POST", "/up/test-token")
+            .match_header("TTL", "0")
+            .match_header("Topic", "test-topic")
+            .match_header("Content-Encoding", "test-encoding")
+            .match_header("Encryption", "test-encryption")
+            .match_header("Crypto-Key", "test-crypto-key")
+            .match_body(body.clone())
+            .with_status(201)
+            .create_async()
+            .await;
+        let notification = make_notification(
+            router_data(&server),
+            Some(b64_encode_url(&body)),
+            RouterType::UnifiedPush,
+        );
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_ok(), "result = {result:?}");
+        assert_eq!(
+            result.unwrap(),
+            RouterResponse::success("http://localhost:8080/m/test-message-id".to_string(), 0)
+        );
+        distributor_mock.assert();
+    }
+
+    /// If the distributor no longer knows the endpoint, we drop the user from
+    /// our database
+    #[tokio::test]
+    async fn endpoint_gone() {
+        for status in [404, 410] {
+            let mut server = mockito::Server::new_async().await;
+
+            let notification =
+                make_notification(router_data(&server), None, RouterType::UnifiedPush);
+            let mut db = MockDbClient::new();
+            db.expect_remove_user()
+                .with(predicate::eq(notification.subscription.user.uaid))
+                .times(1)
+                .return_once(|_| Ok(()));
+
+            let router = make_mock_router(db.into_boxed_arc());
+            let _distributor_mock = server
+                .mock("POST", "/up/test-token")
+                .with_status(status)
+                .create_async()
+                .await;
+
+            let result = router.route_notification(&notification).await;
+            assert!(
+                matches!(
+                    result.as_ref().unwrap_err().kind,
+                    ApiErrorKind::Router(RouterError::NotFound)
+                ),
+                "result = {result:?}"
+            );
+        }
+    }
+
+    /// Endpoints no longer allowed aren't sent to, though the user's kept
+    #[tokio::test]
+    async fn endpoint_not_allowed() {
+        let server = mockito::Server::new_async().await;
+        let notification = make_notification(router_data(&server), None, RouterType::UnifiedPush);
+        let mut db = MockDbClient::new();
+        db.expect_remove_user().times(0);
+
+        let mut router = make_router(r#"["http"]"#, r#"["ntfy.sh"]"#, db.into_boxed_arc());
+        let (metrics, sink) = cadence::SpyMetricSink::new();
+        router.metrics = Arc::new(StatsdClient::from_sink("autopush", sink));
+        let result = router.route_notification(&notification).await;
+        let error = result.as_ref().unwrap_err();
+        assert!(
+            matches!(
+                error.kind,
+                ApiErrorKind::Router(RouterError::UnifiedPush(
+                    UnifiedPushError::EndpointNotAllowed(_)
+                ))
+            ),
+            "result = {result:?}"
+        );
+        assert_eq!(error.kind.status(), actix_web::http::StatusCode::GONE);
+        let metric = String::from_utf8(metrics.try_recv().unwrap()).unwrap();
+        assert!(
+            metric.starts_with("autopush.notification.bridge.error:1|c|#")
+                && metric.contains("reason:endpoint_not_allowed"),
+            "metric = {metric}"
+        );
+    }
+
+    /// Undecodable data is reported rather than sent
+    #[tokio::test]
+    async fn invalid_data() {
+        let server = mockito::Server::new_async().await;
+        let router = make_mock_router(MockDbClient::new().into_boxed_arc());
+        let notification = make_notification(
+            router_data(&server),
+            Some("not base64!".to_owned()),
+            RouterType::UnifiedPush,
+        );
+
+        let result = router.route_notification(&notification).await;
+        assert!(
+            matches!(
+                result.as_ref().unwrap_err().kind,
+                ApiErrorKind::Router(RouterError::UnifiedPush(UnifiedPushError::InvalidData(_)))
+            ),
+            "result = {result:?}"
+        );
+    }
+
+    /// Other errors, including redirects, are reported without dropping the
+    /// user
+    #[tokio::test]
+    async fn other_distributor_error() {
+        let mut server = mockito::Server::new_async().await;
+
+        let router = make_mock_router(MockDbClient::new().into_boxed_arc());
+        let _distributor_mock = server
+            .mock("POST", "/up/test-token")
+            .with_status(302)
+            .with_header("Location", "http://169.254.169.254/")
+            .create_async()
+            .await;
+        let notification = make_notification(router_data(&server), None, RouterType::UnifiedPush);
+
+        let result = router.route_notification(&notification).await;
+        assert!(
+            matches!(
+                &result.as_ref().unwrap_err().kind,
+                ApiErrorKind::Router(RouterError::Upstream { status, .. })
+                    if status == "302 Found"
+            ),
+            "result = {result:?}"
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use crate::routers::common::{EndpointAllowlist, DEFAULT_ALLOWED_HOSTS, DEFAULT_ALLOWED_SCHEMES};
+
+/// Settings for `UnifiedPushRouter`
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct UnifiedPushSettings {
+    /// A JSON list of the URL schemes distributor endpoints may use
+    pub allowed_schemes: String,
+    /// A JSON list of the hosts distributor endpoints may be on. An entry
+    /// starting with `.` (e.g. `".example.com"`) also allows any subdomain,
+    /// while `"*"` allows any host at all. Registrations are refused while
+    /// this is empty.
+    ///
+    /// ```json
+    /// ["ntfy.sh", ".push.example.com"]
+    /// ```
+    pub allowed_hosts: String,
+    /// The number of seconds to wait for distributor requests to complete
+    pub timeout: usize,
+}
+
+impl Default for UnifiedPushSettings {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: DEFAULT_ALLOWED_SCHEMES.to_string(),
+            allowed_hosts: DEFAULT_ALLOWED_HOSTS.to_string(),
+            timeout: 3,
+        }
+    }
+}
+
+impl UnifiedPushSettings {
+    /// Read the allowed schemes and hosts from the provided JSON
+    pub fn allowlist(&self) -> serde_json::Result<EndpointAllowlist> {
+        EndpointAllowlist::from_json(&self.allowed_schemes, &self.allowed_hosts)
+    }
+}
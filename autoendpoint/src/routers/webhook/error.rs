@@ -0,0 +1,76 @@
+use crate::error::ApiErrorKind;
+use crate::routers::RouterError;
+
+use autopush_common::errors::ReportableError;
+use reqwest::StatusCode;
+
+/// Errors that may occur in the webhook router
+#[derive(thiserror::Error, Debug)]
+pub enum WebhookError {
+    #[error("Failed to decode the webhook settings")]
+    SettingsDecode(#[from] serde_json::Error),
+
+    #[error("Error while building the HTTP client")]
+    HttpClientBuild(#[source] reqwest::Error),
+
+    #[error("Error while signing the webhook request")]
+    Signing(#[from] openssl::error::ErrorStack),
+
+    #[error("Invalid webhook URL: {0}")]
+    InvalidUrl(String),
+
+    #[error("No webhook URL found for user")]
+    NoUrl,
+
+    #[error("Webhook URL is no longer allowed: {0}")]
+    UrlNotAllowed(String),
+}
+
+impl WebhookError {
+    /// Get the associated HTTP status code
+    pub fn status(&self) -> StatusCode {
+        match self {
+            WebhookError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+
+            WebhookError::NoUrl | WebhookError::UrlNotAllowed(_) => StatusCode::GONE,
+
+            WebhookError::SettingsDecode(_)
+            | WebhookError::HttpClientBuild(_)
+            | WebhookError::Signing(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Get the associated error number
+    pub fn errno(&self) -> Option<usize> {
+        match self {
+            WebhookError::NoUrl | WebhookError::UrlNotAllowed(_) => Some(106),
+
+            WebhookError::InvalidUrl(_)
+            | WebhookError::SettingsDecode(_)
+            | WebhookError::HttpClientBuild(_)
+            | WebhookError::Signing(_) => None,
+        }
+    }
+}
+
+impl From<WebhookError> for ApiErrorKind {
+    fn from(e: WebhookError) -> Self {
+        ApiErrorKind::Router(RouterError::Webhook(e))
+    }
+}
+
+impl ReportableError for WebhookError {
+    fn is_sentry_event(&self) -> bool {
+        !matches!(
+            &self,
+            WebhookError::InvalidUrl(_) | WebhookError::NoUrl | WebhookError::UrlNotAllowed(_)
+        )
+    }
+
+    fn metric_label(&self) -> Option<&'static str> {
+        match &self {
+            WebhookError::InvalidUrl(_) => Some("notification.bridge.error.webhook.badurl"),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,6 @@
+//! A notification router POSTing notifications to webhooks, for
+//! server-to-server subscribers
+
+pub mod error;
+pub mod router;
+pub mod settings;
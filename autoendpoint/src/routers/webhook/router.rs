@@ -0,0 +1,509 @@
+use autopush_common::db::client::DbClient;
+use autopush_common::util::sec_since_epoch;
+
+use crate::auth::sign_with_key;
+use crate::error::ApiResult;
+use crate::extractors::notification::Notification;
+use crate::extractors::router_data_input::RouterDataInput;
+use crate::routers::common::{
+    build_message_data, endpoint_http_client, handle_error, incr_error_metric,
+    incr_success_metrics, send_to_endpoint, EndpointAllowlist,
+};
+use crate::routers::webhook::error::WebhookError;
+use crate::routers::webhook::settings::WebhookSettings;
+use crate::routers::{Router, RouterError, RouterResponse};
+use again::RetryPolicy;
+use async_trait::async_trait;
+use cadence::{CountedExt, StatsdClient};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+/// The header carrying the request signature, when the webhook has a secret.
+///
+/// The signature is the hex encoded HMAC-SHA256 of `"{timestamp}.{body}"`,
+/// sent as `t={timestamp},s={signature}`.
+pub const SIGNATURE_HEADER: &str = "X-Autopush-Signature";
+
+/// Webhook router, POSTing notifications as JSON to the URL the user
+/// registered with
+pub struct WebhookRouter {
+    endpoint_url: Url,
+    metrics: Arc<StatsdClient>,
+    db: Box<dyn DbClient>,
+    http: reqwest::Client,
+    allowlist: EndpointAllowlist,
+    retry_policy: RetryPolicy,
+}
+
+impl WebhookRouter {
+    /// Create a new `WebhookRouter`
+    pub fn new(
+        settings: WebhookSettings,
+        endpoint_url: Url,
+        metrics: Arc<StatsdClient>,
+        db: Box<dyn DbClient>,
+    ) -> Result<Self, WebhookError> {
+        let http = endpoint_http_client(settings.timeout).map_err(WebhookError::HttpClientBuild)?;
+        let retry_policy =
+            RetryPolicy::exponential(Duration::from_millis(settings.retry_delay_millis))
+                .with_max_retries(settings.max_retries)
+                .with_jitter(true);
+        Ok(Self {
+            endpoint_url,
+            metrics,
+            db,
+            http,
+            allowlist: settings.allowlist()?,
+            retry_policy,
+        })
+    }
+
+    /// if we allow any hosts, this connection is "active"
+    pub fn active(&self) -> bool {
+        self.allowlist.active()
+    }
+
+    /// POST the notification body to the webhook, once
+    async fn send(
+        &self,
+        url: &str,
+        secret: Option<&str>,
+        body: &[u8],
+        notification: &Notification,
+    ) -> Result<(), RouterError> {
+        let mut request = self
+            .http
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("TTL", notification.headers.ttl.to_string());
+        if let Some(topic) = &notification.headers.topic {
+            request = request.header("Topic", topic);
+        }
+        if let Some(secret) = secret {
+            // Signed per attempt, so retries carry a fresh timestamp
+            let timestamp = sec_since_epoch();
+            let signed = [format!("{timestamp}.").as_bytes(), body].concat();
+            let signature =
+                sign_with_key(secret.as_bytes(), &signed).map_err(WebhookError::from)?;
+            request = request.header(SIGNATURE_HEADER, format!("t={timestamp},s={signature}"));
+        }
+
+        send_to_endpoint(request.body(body.to_vec()), body.len()).await
+    }
+}
+
+/// Whether a failed webhook request may succeed if retried: connection
+/// problems, timeouts, rate limiting and server errors
+fn is_retryable(error: &RouterError) -> bool {
+    match error {
+        RouterError::RequestTimeout | RouterError::Connect(_) => true,
+        RouterError::Upstream { status, .. } => {
+            status.starts_with('5') || status.starts_with("429")
+        }
+        _ => false,
+    }
+}
+
+#[async_trait(?Send)]
+impl Router for WebhookRouter {
+    fn register(
+        &self,
+        router_data_input: &RouterDataInput,
+        app_id: &str,
+    ) -> Result<HashMap<String, Value>, RouterError> {
+        // The registration token is the webhook URL
+        let url = self
+            .allowlist
+            .check(&router_data_input.token)
+            .map_err(WebhookError::InvalidUrl)?;
+
+        let mut router_data = HashMap::new();
+        router_data.insert(
+            "url".to_string(),
+            serde_json::to_value(url.as_str()).unwrap(),
+        );
+        router_data.insert("app_id".to_string(), serde_json::to_value(app_id).unwrap());
+        if let Some(secret) = router_data_input.secret.as_ref().filter(|s| !s.is_empty()) {
+            router_data.insert("secret".to_string(), serde_json::to_value(secret).unwrap());
+        }
+
+        Ok(router_data)
+    }
+
+    async fn route_notification(&self, notification: &Notification) -> ApiResult<RouterResponse> {
+        debug!(
+            "Sending webhook notification to UAID {}",
+            notification.subscription.user.uaid
+        );
+        trace!("Notification = {:?}", notification);
+
+        let router_data = notification
+            .subscription
+            .user
+            .router_data
+            .as_ref()
+            .ok_or(WebhookError::NoUrl)?;
+        let url = router_data
+            .get("url")
+            .and_then(Value::as_str)
+            .ok_or(WebhookError::NoUrl)?;
+        let secret = router_data.get("secret").and_then(Value::as_str);
+        let app_id = router_data
+            .get("app_id")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+
+        let message_data = build_message_data(notification)?;
+        let body = serde_json::to_vec(&message_data).unwrap();
+        let platform = "webhook";
+        trace!("Sending message to {platform}: [{:?}]", &app_id);
+        // The user's kept if the URL's no longer allowed, in case the
+        // allowlist change was a mistake
+        if let Err(e) = self.allowlist.check(url) {
+            warn!("Webhook URL no longer allowed: {}", e);
+            let error = RouterError::Webhook(WebhookError::UrlNotAllowed(e));
+            incr_error_metric(
+                &self.metrics,
+                platform,
+                app_id,
+                "endpoint_not_allowed",
+                error.status(),
+                error.errno(),
+            );
+            return Err(error.into());
+        }
+        let result = self
+            .retry_policy
+            .retry_if(
+                || self.send(url, secret, &body, notification),
+                |e: &RouterError| {
+                    let retryable = is_retryable(e);
+                    if retryable {
+                        self.metrics
+                            .incr_with_tags("notification.bridge.retry")
+                            .with_tag("platform", platform)
+                            .with_tag("app_id", app_id)
+                            .send();
+                    }
+                    retryable
+                },
+            )
+            .await;
+        if let Err(e) = result {
+            return Err(handle_error(
+                e,
+                &self.metrics,
+                self.db.as_ref(),
+                platform,
+                app_id,
+                notification.subscription.user.uaid,
+                notification.subscription.vapid.clone(),
+            )
+            .await);
+        };
+        incr_success_metrics(&self.metrics, platform, app_id, notification);
+        // Sent successfully, update metrics and make response
+        trace!("Send request was successful");
+
+        Ok(RouterResponse::success(
+            self.endpoint_url
+                .join(&format!("/m/{}", notification.message_id))
+                .expect("Message ID is not URL-safe")
+                .to_string(),
+            notification.headers.ttl as usize,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::sign_with_key;
+    use crate::error::ApiErrorKind;
+    use crate::extractors::router_data_input::RouterDataInput;
+    use crate::extractors::routers::RouterType;
+    use crate::routers::common::tests::{make_notification, CHANNEL_ID};
+    use crate::routers::common::EndpointAllowlist;
+    use crate::routers::webhook::error::WebhookError;
+    use crate::routers::webhook::router::{WebhookRouter, SIGNATURE_HEADER};
+    use crate::routers::webhook::settings::WebhookSettings;
+    use crate::routers::RouterError;
+    use crate::routers::{Router, RouterResponse};
+    use autopush_common::db::client::DbClient;
+    use autopush_common::db::mock::MockDbClient;
+    use std::sync::{Arc, Mutex};
+
+    use cadence::StatsdClient;
+    use mockall::predicate;
+    use std::collections::HashMap;
+    use url::Url;
+
+    const SECRET: &str = "test-webhook-secret";
+
+    /// Create a router for testing, allowing the mock webhook server
+    fn make_router(db: Box<dyn DbClient>) -> WebhookRouter {
+        WebhookRouter::new(
+            WebhookSettings {
+                allowed_schemes: r#"["http"]"#.to_owned(),
+                allowed_hosts: r#"["127.0.0.1"]"#.to_owned(),
+                retry_delay_millis: 1,
+                ..Default::default()
+            },
+            Url::parse("http://localhost:8080/").unwrap(),
+            Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+            db,
+        )
+        .unwrap()
+    }
+
+    /// Create user router data for the mock webhook
+    fn router_data(
+        server: &mockito::ServerGuard,
+        secret: Option<&str>,
+    ) -> HashMap<String, serde_json::Value> {
+        let mut map = HashMap::new();
+        map.insert(
+            "url".to_string(),
+            serde_json::to_value(format!("{}/hook", server.url())).unwrap(),
+        );
+        map.insert("app_id".to_string(), serde_json::to_value("dev").unwrap());
+        if let Some(secret) = secret {
+            map.insert("secret".to_string(), serde_json::to_value(secret).unwrap());
+        }
+        map
+    }
+
+    /// Registration stores the webhook URL and secret, for allowed URLs only
+    #[test]
+    fn register() {
+        let router = make_router(MockDbClient::new().into_boxed_arc());
+        assert!(router.active());
+        let mut input = RouterDataInput {
+            token: "http://127.0.0.1:9000/hook".to_owned(),
+            channel_id: None,
+            key: None,
+            aps: None,
+            secret: Some(SECRET.to_owned()),
+        };
+
+        let data = router.register(&input, "dev").unwrap();
+        assert_eq!(data["url"], "http://127.0.0.1:9000/hook");
+        assert_eq!(data["app_id"], "dev");
+        assert_eq!(data["secret"], SECRET);
+
+        input.secret = None;
+        let data = router.register(&input, "dev").unwrap();
+        assert!(!data.contains_key("secret"));
+
+        input.token = "https://example.com/hook".to_owned();
+        let result = router.register(&input, "dev");
+        assert!(
+            matches!(
+                result,
+                Err(RouterError::Webhook(WebhookError::InvalidUrl(_)))
+            ),
+            "result = {result:?}"
+        );
+    }
+
+    /// The notification is POSTed as JSON, unsigned without a secret
+    #[tokio::test]
+    async fn successful_routing() {
+        let mut server = mockito::Server::new_async().await;
+
+        let router = make_router(MockDbClient::new().into_boxed_arc());
+        let hook_mock = server
+            .mock("POST", "/hook")
+            .match_header("Content-Type", "application/json")
+            .match_header("TTL", "0")
+            .match_header("Topic", "test-topic")
+            .match_header(SIGNATURE_HEADER, mockito::Matcher::Missing)
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "chid": CHANNEL_ID,
+                "body": "test-data",
+                "con": "test-encoding",
+                "enc": "test-encryption",
+                "cryptokey": "test-crypto-key",
+                "enckey": "test-encryption-key"
+            })))
+            .with_status(204)
+            .create_async()
+            .await;
+        let notification = make_notification(
+            router_data(&server, None),
+            Some("test-data".to_string()),
+            RouterType::Webhook,
+        );
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_ok(), "result = {result:?}");
+        assert_eq!(
+            result.unwrap(),
+            RouterResponse::success("http://localhost:8080/m/test-message-id".to_string(), 0)
+        );
+        hook_mock.assert();
+    }
+
+    /// Requests to webhooks with a secret are signed
+    #[tokio::test]
+    async fn signed_routing() {
+        let mut server = mockito::Server::new_async().await;
+
+        let router = make_router(MockDbClient::new().into_boxed_arc());
+        let received = Arc::new(Mutex::new(None));
+        let captured = received.clone();
+        let hook_mock = server
+            .mock("POST", "/hook")
+            .with_body_from_request(move |request| {
+                let header = request.header(SIGNATURE_HEADER)[0]
+                    .to_str()
+                    .unwrap()
+                    .to_owned();
+                *captured.lock().unwrap() = Some((header, request.body().unwrap().clone()));
+                vec![]
+            })
+            .create_async()
+            .await;
+        let notification = make_notification(
+            router_data(&server, Some(SECRET)),
+            None,
+            RouterType::Webhook,
+        );
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_ok(), "result = {result:?}");
+        hook_mock.assert();
+
+        let (header, body) = received.lock().unwrap().take().unwrap();
+        let (timestamp, signature) = header
+            .strip_prefix("t=")
+            .and_then(|v| v.split_once(",s="))
+            .unwrap();
+        let signed = [format!("{timestamp}.").as_bytes(), &body].concat();
+        assert_eq!(
+            signature,
+            sign_with_key(SECRET.as_bytes(), &signed).unwrap()
+        );
+    }
+
+    /// Server errors are retried
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let mut server = mockito::Server::new_async().await;
+
+        let router = make_router(MockDbClient::new().into_boxed_arc());
+        let failed_mock = server
+            .mock("POST", "/hook")
+            .with_status(503)
+            .expect(2)
+            .create_async()
+            .await;
+        let hook_mock = server
+            .mock("POST", "/hook")
+            .with_status(200)
+            .create_async()
+            .await;
+        let notification = make_notification(router_data(&server, None), None, RouterType::Webhook);
+
+        let result = router.route_notification(&notification).await;
+        assert!(result.is_ok(), "result = {result:?}");
+        failed_mock.assert();
+        hook_mock.assert();
+    }
+
+    /// Retries stop after `max_retries`, and client errors aren't retried
+    #[tokio::test]
+    async fn gives_up() {
+        for (status, attempts) in [(500, 3), (400, 1)] {
+            let mut server = mockito::Server::new_async().await;
+
+            let router = make_router(MockDbClient::new().into_boxed_arc());
+            let hook_mock = server
+                .mock("POST", "/hook")
+                .with_status(status)
+                .with_body("test-message")
+                .expect(attempts)
+                .create_async()
+                .await;
+            let notification =
+                make_notification(router_data(&server, None), None, RouterType::Webhook);
+
+            let result = router.route_notification(&notification).await;
+            assert!(
+                matches!(
+                    &result.as_ref().unwrap_err().kind,
+                    ApiErrorKind::Router(RouterError::Upstream { message, .. })
+                        if message == "test-message"
+                ),
+                "result = {result:?}"
+            );
+            hook_mock.assert();
+        }
+    }
+
+    /// If the webhook is gone, we drop the user from our database
+    #[tokio::test]
+    async fn webhook_gone() {
+        let mut server = mockito::Server::new_async().await;
+
+        let notification = make_notification(router_data(&server, None), None, RouterType::Webhook);
+        let mut db = MockDbClient::new();
+        db.expect_remove_user()
+            .with(predicate::eq(notification.subscription.user.uaid))
+            .times(1)
+            .return_once(|_| Ok(()));
+
+        let router = make_router(db.into_boxed_arc());
+        let hook_mock = server
+            .mock("POST", "/hook")
+            .with_status(410)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let result = router.route_notification(&notification).await;
+        assert!(
+            matches!(
+                result.as_ref().unwrap_err().kind,
+                ApiErrorKind::Router(RouterError::NotFound)
+            ),
+            "result = {result:?}"
+        );
+        hook_mock.assert();
+    }
+
+    /// URLs no longer allowed aren't sent to, though the user's kept
+    #[tokio::test]
+    async fn webhook_not_allowed() {
+        let server = mockito::Server::new_async().await;
+
+        let notification = make_notification(router_data(&server, None), None, RouterType::Webhook);
+        let mut db = MockDbClient::new();
+        db.expect_remove_user().times(0);
+
+        let mut router = make_router(db.into_boxed_arc());
+        router.allowlist =
+            EndpointAllowlist::from_json(r#"["http"]"#, r#"["hooks.example.com"]"#).unwrap();
+        let (metrics, sink) = cadence::SpyMetricSink::new();
+        router.metrics = Arc::new(StatsdClient::from_sink("autopush", sink));
+
+        let result = router.route_notification(&notification).await;
+        let error = result.as_ref().unwrap_err();
+        assert!(
+            matches!(
+                error.kind,
+                ApiErrorKind::Router(RouterError::Webhook(WebhookError::UrlNotAllowed(_)))
+            ),
+            "result = {result:?}"
+        );
+        assert_eq!(error.kind.status(), actix_web::http::StatusCode::GONE);
+        let metric = String::from_utf8(metrics.try_recv().unwrap()).unwrap();
+        assert!(
+            metric.starts_with("autopush.notification.bridge.error:1|c|#")
+                && metric.contains("reason:endpoint_not_allowed"),
+            "metric = {metric}"
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use crate::routers::common::{EndpointAllowlist, DEFAULT_ALLOWED_HOSTS, DEFAULT_ALLOWED_SCHEMES};
+
+/// Settings for `WebhookRouter`
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSettings {
+    /// A JSON list of the URL schemes webhooks may use
+    pub allowed_schemes: String,
+    /// A JSON list of the hosts webhooks may be on. An entry starting with
+    /// `.` (e.g. `".internal.example.com"`) also allows any subdomain, while
+    /// `"*"` allows any host at all. Registrations are refused while this is
+    /// empty.
+    pub allowed_hosts: String,
+    /// The number of seconds to wait for each webhook request to complete
+    pub timeout: usize,
+    /// The number of times a failed webhook request is retried
+    pub max_retries: usize,
+    /// The delay (in milliseconds) before the first retry, doubling for each
+    /// retry after
+    pub retry_delay_millis: u64,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: DEFAULT_ALLOWED_SCHEMES.to_string(),
+            allowed_hosts: DEFAULT_ALLOWED_HOSTS.to_string(),
+            timeout: 3,
+            max_retries: 2,
+            retry_delay_millis: 100,
+        }
+    }
+}
+
+impl WebhookSettings {
+    /// Read the allowed schemes and hosts from the provided JSON
+    pub fn allowlist(&self) -> serde_json::Result<EndpointAllowlist> {
+        EndpointAllowlist::from_json(&self.allowed_schemes, &self.allowed_hosts)
+    }
+}
@@ -10,9 +10,14 @@ use uuid::Uuid;
 use crate::error::{ApiError, ApiErrorKind, ApiResult};
 use crate::extractors::{notification::Notification, router_data_input::RouterDataInput};
 use crate::headers::vapid::VapidHeaderWithKey;
+use crate::routers::breaker::CircuitBreakers;
 use crate::routers::{Router, RouterError, RouterResponse};
 
-use autopush_common::db::{client::DbClient, User};
+use autopush_common::{
+    db::{client::DbClient, User},
+    router_auth,
+    util::sec_since_epoch,
+};
 
 /// The router for desktop user agents.
 ///
@@ -24,6 +29,9 @@ pub struct WebPushRouter {
     pub metrics: Arc<StatsdClient>,
     pub http: reqwest::Client,
     pub endpoint_url: Url,
+    /// Key used to sign requests to the autoconnect node (if any)
+    pub router_auth_key: Option<String>,
+    pub breakers: Arc<CircuitBreakers>,
 }
 
 #[async_trait(?Send)]
@@ -46,15 +54,27 @@ impl Router for WebPushRouter {
         );
         trace!("✉ Notification = {:?}", notification);
 
-        // Check if there is a node connected to the client
-        if let Some(node_id) = &user.node_id {
+        // Check if there is a node connected to the client (which isn't
+        // failing, otherwise store the notification straight away)
+        let failing_node = user
+            .node_id
+            .as_deref()
+            .filter(|node_id| !self.breakers.allow(&Self::breaker(node_id)));
+        if let Some(node_id) = user.node_id.as_ref().filter(|_| failing_node.is_none()) {
             trace!(
                 "✉ User has a node ID, sending notification to node: {}",
                 &node_id
             );
 
+            // Serialize up front so the signature covers the exact bytes sent
+            let body = serde_json::to_vec(&notification.serialize_for_delivery()).map_err(|e| {
+                ApiErrorKind::General(format!("Unable to serialize notification: {e}"))
+            })?;
+
             // Try to send the notification to the node
-            match self.send_notification(notification, node_id).await {
+            let result = self.send_notification(&user.uaid, node_id, body).await;
+            self.record_node_result(node_id, &result);
+            match result {
                 Ok(response) => {
                     // The node might be busy, make sure it accepted the notification
                     if response.status() == 200 {
@@ -127,9 +147,17 @@ impl Router for WebPushRouter {
             }
         };
 
+        // (Without asking the breaker again for a node it already rejected)
+        if failing_node == Some(node_id.as_str()) || !self.breakers.allow(&Self::breaker(node_id)) {
+            trace!("✉ User's node is failing, returning stored response");
+            return Ok(self.make_stored_response(notification));
+        }
+
         // Notify the node to check for messages
         trace!("✉ Notifying node to check for messages");
-        match self.trigger_notification_check(&user.uaid, node_id).await {
+        let result = self.trigger_notification_check(&user.uaid, node_id).await;
+        self.record_node_result(node_id, &result);
+        match result {
             Ok(response) => {
                 trace!("Response = {:?}", response);
                 if response.status() == 200 {
@@ -172,16 +200,35 @@ impl WebPushRouter {
         err
     }
 
-    /// Send the notification to the node
+    /// The key of the circuit breaker for a node
+    fn breaker(node_id: &str) -> String {
+        format!("webpush:{node_id}")
+    }
+
+    /// Record the outcome of a request to a node with its circuit breaker
+    fn record_node_result(&self, node_id: &str, result: &Result<Response, reqwest::Error>) {
+        let success = match result {
+            Ok(response) => !response.status().is_server_error(),
+            Err(_) => false,
+        };
+        self.breakers.record(&Self::breaker(node_id), success);
+    }
+
+    /// Send the serialized notification to the node
     async fn send_notification(
         &self,
-        notification: &Notification,
+        uaid: &Uuid,
         node_id: &str,
+        body: Vec<u8>,
     ) -> Result<Response, reqwest::Error> {
-        let url = format!("{}/push/{}", node_id, notification.subscription.user.uaid);
-        let notification = notification.serialize_for_delivery();
+        let path = format!("/push/{uaid}");
+        let url = format!("{node_id}{path}");
 
-        self.http.put(&url).json(&notification).send().await
+        self.signed_request(self.http.put(&url), &path, &body)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
     }
 
     /// Notify the node to check for notifications for the user
@@ -190,9 +237,35 @@ impl WebPushRouter {
         uaid: &Uuid,
         node_id: &str,
     ) -> Result<Response, reqwest::Error> {
-        let url = format!("{node_id}/notif/{uaid}");
+        let path = format!("/notif/{uaid}");
+        let url = format!("{node_id}{path}");
+
+        self.signed_request(self.http.put(&url), &path, &[])
+            .send()
+            .await
+    }
 
-        self.http.put(&url).send().await
+    /// Sign a request to an autoconnect node's router endpoint when a
+    /// `router_auth_key` is configured
+    fn signed_request(
+        &self,
+        request: reqwest::RequestBuilder,
+        path: &str,
+        body: &[u8],
+    ) -> reqwest::RequestBuilder {
+        let Some(key) = &self.router_auth_key else {
+            return request;
+        };
+        match router_auth::make_header(key, sec_since_epoch(), path, body) {
+            Ok(header) => request.header(router_auth::ROUTER_AUTH_HEADER, header),
+            Err(e) => {
+                // The node will reject the request, which is handled like any
+                // other failed delivery
+                warn!("✉ Unable to sign router request: {:?}", e);
+                self.metrics.incr("error.node.sign").ok();
+                request
+            }
+        }
     }
 
     /// Store a notification in the database
@@ -286,8 +359,11 @@ mod test {
 
     use reqwest;
 
+    use crate::extractors::routers::RouterType;
     use crate::extractors::subscription::tests::{make_vapid, PUB_KEY};
     use crate::headers::vapid::VapidClaims;
+    use crate::routers::breaker::settings::BreakerSettings;
+    use crate::routers::common::tests::make_notification;
     use autopush_common::errors::ReportableError;
 
     use super::*;
@@ -299,6 +375,11 @@ mod test {
             metrics: Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
             http: reqwest::Client::new(),
             endpoint_url: Url::parse("http://localhost:8080/").unwrap(),
+            router_auth_key: None,
+            breakers: Arc::new(CircuitBreakers::new(
+                BreakerSettings::default(),
+                Arc::new(StatsdClient::from_sink("autopush", cadence::NopMetricSink)),
+            )),
         }
     }
 
@@ -316,4 +397,73 @@ mod test {
         let err = router.handle_error(ApiErrorKind::LogCheck, Some(vapid));
         assert!(err.extras().contains(&("sub", sub.to_owned())));
     }
+
+    /// Notifications for a node whose circuit breaker is open are stored
+    /// without contacting it, rejected by the breaker once
+    #[tokio::test]
+    async fn stores_when_node_failing() {
+        let mut server = mockito::Server::new_async().await;
+        let node_mock = server
+            .mock("PUT", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+        let mut notification = make_notification(HashMap::new(), None, RouterType::WebPush);
+        notification.headers.ttl = 60;
+        notification.subscription.user.node_id = Some(server.url());
+        let user = notification.subscription.user.clone();
+
+        let mut db = MockDbClient::new();
+        db.expect_save_message().times(1).return_once(|_, _| Ok(()));
+        db.expect_get_user()
+            .times(1)
+            .return_once(move |_| Ok(Some(user)));
+        let mut router = make_router(db.into_boxed_arc());
+        let (metrics, sink) = cadence::SpyMetricSink::new();
+        router.breakers = Arc::new(CircuitBreakers::new(
+            BreakerSettings {
+                enabled: true,
+                min_requests: 1,
+                ..Default::default()
+            },
+            Arc::new(StatsdClient::from_sink("autopush", sink)),
+        ));
+        router
+            .breakers
+            .record(&WebPushRouter::breaker(&server.url()), false);
+
+        let response = router.route_notification(&notification).await.unwrap();
+        assert_eq!(response, router.make_stored_response(&notification));
+        node_mock.assert();
+        let rejected = metrics
+            .try_iter()
+            .filter(|metric| metric.starts_with(b"autopush.circuit_breaker.rejected:"))
+            .count();
+        assert_eq!(rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn signs_node_requests() {
+        let mut server = mockito::Server::new_async().await;
+        let uaid = Uuid::new_v4();
+        let path = format!("/notif/{uaid}");
+        let mut router = make_router(Box::new(MockDbClient::new()));
+        router.router_auth_key = Some("router_key".to_owned());
+
+        let node_mock = server
+            .mock("PUT", path.as_str())
+            .match_header(
+                router_auth::ROUTER_AUTH_HEADER,
+                mockito::Matcher::Regex("^t=[0-9]+,s=[0-9a-f]{64}$".to_owned()),
+            )
+            .with_status(200)
+            .create_async()
+            .await;
+        let response = router
+            .trigger_notification_check(&uaid, &server.url())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        node_mock.assert();
+    }
 }
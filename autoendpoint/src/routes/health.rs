@@ -21,13 +21,17 @@ pub async fn health_route(state: Data<AppState>) -> Json<serde_json::Value> {
     let mut routers: HashMap<&str, bool> = HashMap::new();
     routers.insert("apns", state.apns_router.active());
     routers.insert("fcm", state.fcm_router.active());
+    routers.insert("hms", state.hms_router.active());
+    routers.insert("unifiedpush", state.unifiedpush_router.active());
+    routers.insert("webhook", state.webhook_router.active());
 
     let health = json!({
     "status": "OK",
     "version": env!("CARGO_PKG_VERSION"),
     "router_table": router_health,
     "message_table": message_health,
-    "routers": routers});
+    "routers": routers,
+    "circuit_breakers": state.breakers.counts()});
 
     Json(health)
 }
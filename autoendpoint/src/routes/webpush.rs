@@ -4,7 +4,9 @@ use crate::error::{ApiErrorKind, ApiResult};
 use crate::extractors::message_id::MessageId;
 use crate::extractors::notification::Notification;
 use crate::extractors::routers::{RouterType, Routers};
+use crate::routers::RouterResponse;
 use crate::server::AppState;
+use crate::settings::{AesGcmPolicy, Settings};
 use actix_web::web::Data;
 use actix_web::HttpResponse;
 
@@ -12,7 +14,7 @@ use actix_web::HttpResponse;
 pub async fn webpush_route(
     notification: Notification,
     routers: Routers,
-    _app_state: Data<AppState>,
+    app_state: Data<AppState>,
 ) -> ApiResult<HttpResponse> {
     // TODO:
     sentry::configure_scope(|scope| {
@@ -21,11 +23,38 @@ pub async fn webpush_route(
             notification.subscription.user.uaid.to_string().into(),
         );
     });
-    let router = routers.get(
-        RouterType::from_str(&notification.subscription.user.router_type)
-            .map_err(|_| ApiErrorKind::InvalidRouterType)?,
+    let router_type = RouterType::from_str(&notification.subscription.user.router_type)
+        .map_err(|_| ApiErrorKind::InvalidRouterType)?;
+    let router = routers.get(router_type);
+    let mut response = match router.route_notification(&notification).await {
+        Ok(response) => response,
+        // Accept the notification, to retry delivering it later
+        Err(e)
+            if app_state
+                .retry_queue
+                .should_retry(&e, &notification, router_type) =>
+        {
+            app_state.retry_queue.enqueue(&notification).await?
+        }
+        Err(e) => return Err(e),
+    };
+    if notification.headers.encoding.as_deref() == Some("aesgcm")
+        && app_state.settings.aesgcm_policy == AesGcmPolicy::Warn
+    {
+        add_aesgcm_deprecation_headers(&mut response, &app_state.settings);
+    }
+    Ok(response.into())
+}
+
+/// Announce the deprecation of the `aesgcm` content encoding to the sender
+fn add_aesgcm_deprecation_headers(response: &mut RouterResponse, settings: &Settings) {
+    response.headers.insert(
+        "Warning",
+        r#"299 - "The aesgcm Content-Encoding is deprecated, use aes128gcm""#.to_owned(),
     );
-    Ok(router.route_notification(&notification).await?.into())
+    if let Some(sunset) = &settings.aesgcm_sunset {
+        response.headers.insert("Sunset", sunset.clone());
+    }
 }
 
 /// Handle the `DELETE /m/{message_id}` route
@@ -43,3 +72,26 @@ pub async fn delete_notification_route(
 
     Ok(HttpResponse::NoContent().finish())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aesgcm_deprecation_headers() {
+        let mut response = RouterResponse::success("http://localhost/m/foo".to_owned(), 60);
+        add_aesgcm_deprecation_headers(&mut response, &Settings::default());
+        assert!(response.headers.contains_key("Warning"));
+        assert!(!response.headers.contains_key("Sunset"));
+
+        let settings = Settings {
+            aesgcm_sunset: Some("Sat, 01 Mar 2025 00:00:00 GMT".to_owned()),
+            ..Default::default()
+        };
+        add_aesgcm_deprecation_headers(&mut response, &settings);
+        assert_eq!(
+            response.headers.get("Sunset").map(String::as_str),
+            Some("Sat, 01 Mar 2025 00:00:00 GMT")
+        );
+    }
+}
@@ -19,9 +19,14 @@ use autopush_common::{
 };
 
 use crate::metrics;
+use crate::routers::breaker::CircuitBreakers;
+use crate::routers::retry::{spawn_retry_worker, RetryQueue};
 #[cfg(feature = "stub")]
 use crate::routers::stub::router::StubRouter;
-use crate::routers::{apns::router::ApnsRouter, fcm::router::FcmRouter};
+use crate::routers::{
+    apns::router::ApnsRouter, fcm::router::FcmRouter, hms::router::HmsRouter,
+    unifiedpush::router::UnifiedPushRouter, webhook::router::WebhookRouter,
+};
 use crate::routes::{
     health::{health_route, lb_heartbeat_route, log_check, status_route, version_route},
     registration::{
@@ -42,10 +47,17 @@ pub struct AppState {
     pub metrics: Arc<StatsdClient>,
     pub settings: Settings,
     pub fernet: MultiFernet,
+    /// Key used to sign requests to autoconnect nodes (if any)
+    pub router_auth_key: Option<String>,
     pub db: Box<dyn DbClient>,
     pub http: reqwest::Client,
     pub fcm_router: Arc<FcmRouter>,
     pub apns_router: Arc<ApnsRouter>,
+    pub hms_router: Arc<HmsRouter>,
+    pub unifiedpush_router: Arc<UnifiedPushRouter>,
+    pub webhook_router: Arc<WebhookRouter>,
+    pub retry_queue: Arc<RetryQueue>,
+    pub breakers: Arc<CircuitBreakers>,
     #[cfg(feature = "stub")]
     pub stub_router: Arc<StubRouter>,
     pub reliability: Arc<VapidTracker>,
@@ -58,6 +70,7 @@ impl Server {
         let metrics = Arc::new(metrics::metrics_from_settings(&settings)?);
         let bind_address = format!("{}:{}", settings.host, settings.port);
         let fernet = settings.make_fernet();
+        let router_auth_key = settings.router_auth_keys().into_iter().next();
         let endpoint_url = settings.endpoint_url();
         let db_settings = DbSettings {
             dsn: settings.db_dsn.clone(),
@@ -90,13 +103,17 @@ impl Server {
             .timeout(Duration::from_millis(settings.request_timeout_millis))
             .build()
             .expect("Could not generate request client");
+        let breakers = Arc::new(CircuitBreakers::new(
+            settings.circuit_breaker.clone(),
+            metrics.clone(),
+        ));
         let fcm_router = Arc::new(
             FcmRouter::new(
                 settings.fcm.clone(),
                 endpoint_url.clone(),
-                http.clone(),
                 metrics.clone(),
                 db.clone(),
+                breakers.clone(),
             )
             .await?,
         );
@@ -106,9 +123,34 @@ impl Server {
                 endpoint_url.clone(),
                 metrics.clone(),
                 db.clone(),
+                breakers.clone(),
             )
             .await?,
         );
+        let hms_router = Arc::new(HmsRouter::new(
+            settings.hms.clone(),
+            endpoint_url.clone(),
+            http.clone(),
+            metrics.clone(),
+            db.clone(),
+        )?);
+        let unifiedpush_router = Arc::new(UnifiedPushRouter::new(
+            settings.unifiedpush.clone(),
+            endpoint_url.clone(),
+            metrics.clone(),
+            db.clone(),
+        )?);
+        let webhook_router = Arc::new(WebhookRouter::new(
+            settings.webhook.clone(),
+            endpoint_url.clone(),
+            metrics.clone(),
+            db.clone(),
+        )?);
+        let retry_queue = Arc::new(RetryQueue::new(
+            settings.bridge_retry.clone(),
+            db.clone(),
+            metrics.clone(),
+        ));
         let reliability = Arc::new(VapidTracker(settings.tracking_keys()));
         #[cfg(feature = "stub")]
         let stub_router = Arc::new(StubRouter::new(settings.stub.clone())?);
@@ -116,15 +158,33 @@ impl Server {
             metrics: metrics.clone(),
             settings,
             fernet,
+            router_auth_key,
             db,
             http,
             fcm_router,
             apns_router,
+            hms_router,
+            unifiedpush_router,
+            webhook_router,
+            retry_queue,
+            breakers,
             #[cfg(feature = "stub")]
             stub_router,
             reliability,
         };
 
+        if app_state.retry_queue.enabled() {
+            spawn_retry_worker(app_state.clone());
+        }
+
+        if app_state.settings.credential_reload_secs > 0 {
+            spawn_credential_reloader(
+                Duration::from_secs(app_state.settings.credential_reload_secs),
+                app_state.fcm_router.clone(),
+                app_state.apns_router.clone(),
+            );
+        }
+
         spawn_pool_periodic_reporter(
             Duration::from_secs(10),
             app_state.db.clone(),
@@ -204,3 +264,19 @@ impl Server {
         Ok(server)
     }
 }
+
+/// Periodically rebuild the FCM and APNs clients whose credential files
+/// changed
+fn spawn_credential_reloader(
+    interval: Duration,
+    fcm_router: Arc<FcmRouter>,
+    apns_router: Arc<ApnsRouter>,
+) {
+    actix_rt::spawn(async move {
+        loop {
+            actix_rt::time::sleep(interval).await;
+            fcm_router.reload_credentials().await;
+            apns_router.reload_credentials().await;
+        }
+    });
+}
@@ -8,9 +8,14 @@ use url::Url;
 
 use crate::headers::vapid::VapidHeaderWithKey;
 use crate::routers::apns::settings::ApnsSettings;
+use crate::routers::breaker::settings::BreakerSettings;
 use crate::routers::fcm::settings::FcmSettings;
+use crate::routers::hms::settings::HmsSettings;
+use crate::routers::retry::settings::RetrySettings;
 #[cfg(feature = "stub")]
 use crate::routers::stub::settings::StubSettings;
+use crate::routers::unifiedpush::settings::UnifiedPushSettings;
+use crate::routers::webhook::settings::WebhookSettings;
 
 pub const ENV_PREFIX: &str = "autoend";
 
@@ -42,12 +47,26 @@ pub struct Settings {
     pub tracking_keys: String,
 
     pub max_data_bytes: usize,
+    /// How to handle notifications using the legacy `aesgcm` content encoding
+    pub aesgcm_policy: AesGcmPolicy,
+    /// The date (in HTTP-date format, e.g. "Sat, 01 Mar 2025 00:00:00 GMT")
+    /// announced in the `Sunset` header of `aesgcm` notification responses
+    /// when `aesgcm_policy` is `warn`
+    pub aesgcm_sunset: Option<String>,
     pub crypto_keys: String,
     pub auth_keys: String,
+    /// A stringified list of keys used to sign requests to the autoconnect
+    /// router endpoints. The first key is used for signing; an empty list
+    /// sends the requests unsigned.
+    pub router_auth_keys: String,
     pub human_logs: bool,
 
     pub connection_timeout_millis: u64,
     pub request_timeout_millis: u64,
+    /// How often (in seconds) the FCM and APNs credential files are checked
+    /// for changes, rebuilding the clients of those which changed. 0 disables
+    /// reloading.
+    pub credential_reload_secs: u64,
 
     pub statsd_host: Option<String>,
     pub statsd_port: u16,
@@ -55,10 +74,30 @@ pub struct Settings {
 
     pub fcm: FcmSettings,
     pub apns: ApnsSettings,
+    pub hms: HmsSettings,
+    pub unifiedpush: UnifiedPushSettings,
+    pub webhook: WebhookSettings,
+    pub bridge_retry: RetrySettings,
+    pub circuit_breaker: BreakerSettings,
     #[cfg(feature = "stub")]
     pub stub: StubSettings,
 }
 
+/// The policy for the legacy `aesgcm` (draft-ietf-webpush-encryption-04)
+/// content encoding, to drive migration to `aes128gcm`
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AesGcmPolicy {
+    /// Accept `aesgcm` notifications
+    #[default]
+    Allow,
+    /// Accept `aesgcm` notifications, but include a deprecation `Warning`
+    /// (and `Sunset`, if configured) header in the response
+    Warn,
+    /// Reject `aesgcm` notifications
+    Reject,
+}
+
 impl Default for Settings {
     fn default() -> Settings {
         Settings {
@@ -79,17 +118,26 @@ impl Default for Settings {
             // 4216 byte data block. Since we're going to be receiving this, we have to
             // presume base64 encoding, so we can bump things up to 5630 bytes max.
             max_data_bytes: 5630,
+            aesgcm_policy: AesGcmPolicy::default(),
+            aesgcm_sunset: None,
             crypto_keys: format!("[{}]", Fernet::generate_key()),
             auth_keys: r#"["AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAB="]"#.to_string(),
+            router_auth_keys: r#"[]"#.to_string(),
             tracking_keys: r#"[]"#.to_string(),
             human_logs: false,
             connection_timeout_millis: 1000,
             request_timeout_millis: 3000,
+            credential_reload_secs: 0,
             statsd_host: None,
             statsd_port: 8125,
             statsd_label: "autoendpoint".to_string(),
             fcm: FcmSettings::default(),
             apns: ApnsSettings::default(),
+            hms: HmsSettings::default(),
+            unifiedpush: UnifiedPushSettings::default(),
+            webhook: WebhookSettings::default(),
+            bridge_retry: RetrySettings::default(),
+            circuit_breaker: BreakerSettings::default(),
             #[cfg(feature = "stub")]
             stub: StubSettings::default(),
         }
@@ -169,6 +217,15 @@ impl Settings {
             .collect()
     }
 
+    /// Get the list of router auth keys
+    pub fn router_auth_keys(&self) -> Vec<String> {
+        let keys = &self.router_auth_keys.replace(['"', ' '], "");
+        Self::read_list_from_str(keys, "Invalid AUTOEND_ROUTER_AUTH_KEYS")
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_owned())
+            .collect()
+    }
+
     /// Get the list of tracking public keys
     // TODO: this should return a Vec<[u8]> so that key formatting errors do not cause
     // false rejections. This is not a problem now since we have access to the source
@@ -222,7 +279,7 @@ impl VapidTracker {
 mod tests {
     use actix_http::header::{HeaderMap, HeaderName, HeaderValue};
 
-    use super::{Settings, VapidTracker};
+    use super::{AesGcmPolicy, Settings, VapidTracker};
     use crate::{
         error::ApiResult,
         headers::vapid::{VapidHeader, VapidHeaderWithKey},
@@ -252,6 +309,21 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_router_auth_keys() {
+        let settings = Settings::default();
+        assert!(settings.router_auth_keys().is_empty());
+
+        let settings = Settings {
+            router_auth_keys: r#"["new_key", "old_key"]"#.to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.router_auth_keys(),
+            vec!["new_key".to_owned(), "old_key".to_owned()]
+        );
+    }
+
     #[test]
     fn test_endpoint_url() -> ApiResult<()> {
         let example = "https://example.org/";
@@ -307,6 +379,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_aesgcm_policy() {
+        let policy = format!("{}__AESGCM_POLICY", super::ENV_PREFIX).to_uppercase();
+
+        use std::env;
+        assert_eq!(Settings::default().aesgcm_policy, AesGcmPolicy::Allow);
+        env::set_var(&policy, "reject");
+        let settings = Settings::with_env_and_config_file(&None).unwrap();
+        assert_eq!(settings.aesgcm_policy, AesGcmPolicy::Reject);
+        env::remove_var(&policy);
+    }
+
     #[test]
     fn test_tracking_keys() -> ApiResult<()> {
         let settings = Settings{
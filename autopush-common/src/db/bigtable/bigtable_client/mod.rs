@@ -739,6 +739,9 @@ impl BigTableClientImpl {
                     .map_err(|e| DbError::Serialization(e.to_string()))?,
             );
         }
+        if let Some(cell) = row.take_cell("vapid_sub") {
+            notif.vapid_sub = Some(to_string(cell.value, "vapid_sub")?);
+        }
 
         trace!("🚣  Deserialized message row: {:?}", &notif);
         Ok(notif)
@@ -1174,6 +1177,14 @@ impl DbClient for BigTableClientImpl {
                 });
             }
         }
+        if let Some(vapid_sub) = message.vapid_sub {
+            cells.push(cell::Cell {
+                qualifier: "vapid_sub".to_owned(),
+                value: vapid_sub.into_bytes(),
+                timestamp: expiry,
+                ..Default::default()
+            });
+        }
         if let Some(data) = message.data {
             cells.push(cell::Cell {
                 qualifier: "data".to_owned(),
@@ -1264,6 +1275,30 @@ impl DbClient for BigTableClientImpl {
         Ok(())
     }
 
+    /// Delete the notification from storage, if it's still there.
+    async fn take_message(&self, uaid: &Uuid, chidmessageid: &str) -> DbResult<bool> {
+        let row_key = format!("{}#{}", uaid.simple(), chidmessageid);
+        debug!("🉑🔥 Taking message {}", &row_key);
+        let mut req = self.check_and_mutate_row_request(&row_key);
+
+        // Only delete the message if it's (still) there
+        req.set_predicate_filter(filter_chain(message_gc_policy_filter()?));
+        let mut mutations = protobuf::RepeatedField::default();
+        let mut mutation = data::Mutation::default();
+        mutation.set_delete_from_row(data::Mutation_DeleteFromRow::default());
+        mutations.push(mutation);
+        req.set_true_mutations(mutations);
+
+        let taken = self.check_and_mutate(req).await?;
+        if taken {
+            self.metrics
+                .incr_with_tags("notification.message.deleted")
+                .with_tag("database", &self.name())
+                .send();
+        }
+        Ok(taken)
+    }
+
     /// Return `limit` pending messages from storage. `limit=0` for all messages.
     async fn fetch_topic_messages(
         &self,
@@ -1621,11 +1656,17 @@ mod tests {
             .await?;
         assert_eq!(fetched.messages.len(), 0);
 
-        // can we clean up our toys?
-        assert!(client
-            .remove_message(&uaid, &test_notification.chidmessageid())
-            .await
-            .is_ok());
+        // can we clean up our toys? (only once)
+        assert!(
+            client
+                .take_message(&uaid, &test_notification.chidmessageid())
+                .await?
+        );
+        assert!(
+            !client
+                .take_message(&uaid, &test_notification.chidmessageid())
+                .await?
+        );
 
         assert!(client.remove_channel(&uaid, &chid).await.is_ok());
 
@@ -88,6 +88,10 @@ pub trait DbClient: Send + Sync {
     /// Delete a notification
     async fn remove_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<()>;
 
+    /// Delete a notification, returning whether this call removed it. Of
+    /// concurrent calls for the same notification, at most one returns true.
+    async fn take_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<bool>;
+
     /// Check if the router table exists
     async fn router_table_exists(&self) -> DbResult<bool>;
 
@@ -95,6 +95,10 @@ impl DbClient for Arc<MockDbClient> {
         Arc::as_ref(self).remove_message(uaid, sort_key).await
     }
 
+    async fn take_message(&self, uaid: &Uuid, sort_key: &str) -> DbResult<bool> {
+        Arc::as_ref(self).take_message(uaid, sort_key).await
+    }
+
     async fn router_table_exists(&self) -> DbResult<bool> {
         Arc::as_ref(self).router_table_exists().await
     }
@@ -333,6 +333,7 @@ impl NotificationRecord {
             data: self.data,
             headers: self.headers.map(|m| m.into()),
             sortkey_timestamp: key.sortkey_timestamp,
+            vapid_sub: None,
         })
     }
 
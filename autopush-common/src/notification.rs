@@ -27,6 +27,11 @@ pub struct Notification {
     pub sortkey_timestamp: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub headers: Option<HashMap<String, String>>,
+    /// The `sub` claim of the sender's VAPID authorization (if any). Not
+    /// sent to the UserAgent: used to report Client errors handling the
+    /// Notification back to the app server
+    #[serde(skip_serializing)]
+    pub vapid_sub: Option<String>,
 }
 
 pub const TOPIC_NOTIFICATION_PREFIX: &str = "01";
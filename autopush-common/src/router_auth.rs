@@ -0,0 +1,162 @@
+//! Shared secret signing of the internal autoendpoint -> autoconnect routing requests
+//!
+//! The autoconnect router endpoints (`/push/{uaid}` and `/notif/{uaid}`) were
+//! traditionally protected only by network isolation. When a set of router
+//! auth keys is configured, autoendpoint signs each request with the first key
+//! and autoconnect accepts a signature made with any of its keys (allowing for
+//! key rotation, similar to `auth_keys`).
+//!
+//! The signature is the hex encoded HMAC-SHA256 of
+//! `"{timestamp}\n{path}\n{body}"`, carried in the [ROUTER_AUTH_HEADER] as
+//! `t={timestamp},s={signature}`. The timestamp (seconds since epoch) limits
+//! how long a captured request may be replayed.
+use openssl::{error::ErrorStack, hash::MessageDigest, pkey::PKey, sign::Signer};
+
+/// The header carrying the router request signature
+pub const ROUTER_AUTH_HEADER: &str = "X-Autopush-Router-Auth";
+
+/// Reasons a router request signature was rejected
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum RouterAuthError {
+    #[error("Missing router auth header")]
+    Missing,
+
+    #[error("Malformed router auth header")]
+    Malformed,
+
+    #[error("Router auth timestamp outside of the allowed window")]
+    Expired,
+
+    #[error("Invalid router auth signature")]
+    Invalid,
+}
+
+impl RouterAuthError {
+    /// The value used to tag the rejection metric
+    pub fn metric_label(&self) -> &'static str {
+        match self {
+            RouterAuthError::Missing => "missing",
+            RouterAuthError::Malformed => "malformed",
+            RouterAuthError::Expired => "expired",
+            RouterAuthError::Invalid => "invalid",
+        }
+    }
+}
+
+/// Calculate the signature of a router request
+fn sign(key: &str, timestamp: u64, path: &str, body: &[u8]) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(key.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(format!("{timestamp}\n{path}\n").as_bytes())?;
+    signer.update(body)?;
+    Ok(hex::encode(signer.sign_to_vec()?))
+}
+
+/// Build the [ROUTER_AUTH_HEADER] value for a request to `path` with `body`
+pub fn make_header(
+    key: &str,
+    timestamp: u64,
+    path: &str,
+    body: &[u8],
+) -> Result<String, ErrorStack> {
+    Ok(format!(
+        "t={},s={}",
+        timestamp,
+        sign(key, timestamp, path, body)?
+    ))
+}
+
+/// Verify a [ROUTER_AUTH_HEADER] value against any of the `keys`.
+///
+/// The header's timestamp must be within `max_skew` seconds of `now`.
+pub fn verify_header(
+    header: Option<&str>,
+    keys: &[String],
+    path: &str,
+    body: &[u8],
+    now: u64,
+    max_skew: u64,
+) -> Result<(), RouterAuthError> {
+    let header = header.ok_or(RouterAuthError::Missing)?;
+    let mut timestamp = None;
+    let mut signature = None;
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", val)) => {
+                timestamp = Some(val.parse::<u64>().map_err(|_| RouterAuthError::Malformed)?)
+            }
+            Some(("s", val)) => signature = Some(val),
+            _ => return Err(RouterAuthError::Malformed),
+        }
+    }
+    let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+        return Err(RouterAuthError::Malformed);
+    };
+    if now.abs_diff(timestamp) > max_skew {
+        return Err(RouterAuthError::Expired);
+    }
+    for key in keys {
+        let expected = sign(key, timestamp, path, body).map_err(|e| {
+            warn!("🔐 Unable to sign router request: {:?}", e);
+            RouterAuthError::Invalid
+        })?;
+        if expected.len() == signature.len()
+            && openssl::memcmp::eq(expected.as_bytes(), signature.as_bytes())
+        {
+            return Ok(());
+        }
+    }
+    Err(RouterAuthError::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "/push/deadbeef00000000decafbad00000000";
+    const BODY: &[u8] = br#"{"channelID":"decafbad-0000-0000-0000-0000deadbeef"}"#;
+
+    fn keys() -> Vec<String> {
+        vec!["old_key".to_owned(), "new_key".to_owned()]
+    }
+
+    #[test]
+    fn test_roundtrip_with_rotated_keys() {
+        for key in keys() {
+            let header = make_header(&key, 1000, PATH, BODY).unwrap();
+            assert_eq!(
+                verify_header(Some(&header), &keys(), PATH, BODY, 1010, 60),
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn test_rejections() {
+        let header = make_header("new_key", 1000, PATH, BODY).unwrap();
+        assert_eq!(
+            verify_header(None, &keys(), PATH, BODY, 1000, 60),
+            Err(RouterAuthError::Missing)
+        );
+        assert_eq!(
+            verify_header(Some("garbage"), &keys(), PATH, BODY, 1000, 60),
+            Err(RouterAuthError::Malformed)
+        );
+        assert_eq!(
+            verify_header(Some(&header), &keys(), PATH, BODY, 2000, 60),
+            Err(RouterAuthError::Expired)
+        );
+        assert_eq!(
+            verify_header(Some(&header), &keys(), "/notif/other", BODY, 1000, 60),
+            Err(RouterAuthError::Invalid)
+        );
+        assert_eq!(
+            verify_header(Some(&header), &keys(), PATH, b"{}", 1000, 60),
+            Err(RouterAuthError::Invalid)
+        );
+        assert_eq!(
+            verify_header(Some(&header), &["unknown".to_owned()], PATH, BODY, 1000, 60),
+            Err(RouterAuthError::Invalid)
+        );
+    }
+}
@@ -57,6 +57,13 @@ impl From<&str> for UserAgentInfo {
     }
 }
 
+impl UserAgentInfo {
+    /// Consume this, returning the User-Agent header it was derived from
+    pub fn into_user_agent_string(self) -> String {
+        self._user_agent_string
+    }
+}
+
 impl From<&actix_web::HttpRequest> for UserAgentInfo {
     fn from(req: &actix_web::HttpRequest) -> UserAgentInfo {
         if let Some(header) = req.headers().get(&actix_web::http::header::USER_AGENT) {
@@ -223,11 +223,14 @@ class AutopushUser(FastHttpUser):
             sending a notification.
         """
         message_type: str = "notification"
-        # Prefix random message with 'TestData' to more easily differentiate the payload
-        data: str = "TestData" + "".join(
+        # Prefix random message with 'TestData' to more easily differentiate the payload.
+        # The prefix doubles as an aes128gcm content-coding header (16 byte salt, a
+        # record size of 4096 and a 65 byte keyid) so the payload passes validation.
+        header: str = "TestDataTestData" + "\x00\x00\x10\x00" + "A" + "K" * 65
+        data: str = header + "".join(
             [
                 random.choice(string.ascii_letters + string.digits)  # nosec
-                for i in range(0, random.randrange(1024, 4096, 2) - 8)  # nosec
+                for i in range(0, random.randrange(1024, 4096, 2) - len(header))  # nosec
             ]
         )
 